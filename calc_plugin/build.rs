@@ -0,0 +1,3 @@
+fn main() {
+    plugin_interface::manifest::install(std::path::Path::new("pusz-plugin.toml"));
+}
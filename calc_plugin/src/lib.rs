@@ -25,21 +25,11 @@ impl plugin_interface::Plugin for CalcPlugin {
     }
 }
 
-#[no_mangle]
-pub extern "C" fn load(plugin_interface_version : &str) -> Result<Box<dyn plugin_interface::Plugin>, String> {
-    if plugin_interface_version == plugin_interface::COMMON_INTERFACE_VERSION {
-        Ok(Box::new(CalcPlugin{}))
-    } else {
-        Err(format!("compatible with: {} but your version is: {}", plugin_interface::COMMON_INTERFACE_VERSION, plugin_interface_version))
-    }
+fn load(_context : &plugin_interface::PluginContext) -> Result<Box<dyn plugin_interface::Plugin>, plugin_interface::LoadError> {
+    Ok(Box::new(CalcPlugin{}))
 }
 
-#[no_mangle]
-pub extern "C" fn introduce() -> Box<dyn plugin_interface::Plugin> {
-//    let x = Box::new(CalcPlugin{});
-//    Box::into_raw(x)
-    Box::new(CalcPlugin{})
-}
+plugin_interface::declare_plugin!(load);
 
 #[cfg(test)]
 mod tests {
@@ -1,18 +1,36 @@
 use std::time::SystemTime;
-
-use std::io::Write;
-use std::fs::{
-    metadata,
-    Metadata
-};
+use std::path::{Path, PathBuf};
 
 use serde::{Serialize, Deserialize};
 
 
 use plugin_interface;
-use plugin_interface::{PluginResult, PuszRow, PuszRowBuilder, PuszRowIdentifier, PluginEvent, PluginSettings};
+use plugin_interface::{PluginResult, PuszRow, PuszRowBuilder, PuszRowIdentifier, PuszAction, PuszEvent, KeyChord, PluginEvent, PluginSettings, PluginContext, LoadError};
+use plugin_interface::host::{HostContext, KvOp, LogLevel};
+
+const NAME : &'static str = "clip";
+// every clip is a key of its own in the plugin store, clips/<hash of the text>, the text is in the value.
+const CLIP_PREFIX : &'static str = "clips/";
+
+// [plugins.settings.clip] section of pusz config.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+struct ClipboardConfig {
+    // history kept by versions before the plugin store, moved into the store on first start.
+    // relative paths are resolved against the plugin data dir.
+    history_file : PathBuf,
+    // the least recently used clips above this many are forgotten.
+    max_clips : usize,
+}
 
-const FILENAME : &'static str = "pusz.toml";
+impl Default for ClipboardConfig {
+    fn default() -> Self {
+        Self {
+            history_file : PathBuf::from("clips.toml"),
+            max_clips : 1000,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct DataEntry {
@@ -26,61 +44,86 @@ struct DataModel {
 }
 
 impl DataModel {
-    fn add_entry(&mut self, text : &str, last_known_modification_time : SystemTime, ) -> SystemTime{
-        for e in &mut self.clips {
-            if e.text == text {
-                e.last_use_timestamp = SystemTime::now();
-                return last_known_modification_time;
+    // the entry to store, a new one or the one used again.
+    fn add_entry(&mut self, text : &str) -> &DataEntry {
+        let now = SystemTime::now();
+        let index = match self.clips.iter().position(|e| e.text == text) {
+            Some(index) => index,
+            None => {
+                self.clips.push(DataEntry {text : text.to_owned(), last_use_timestamp : now });
+                self.clips.len() - 1
             }
-        }
+        };
 
-        self.clips.push(DataEntry {text : text.to_owned(), last_use_timestamp : SystemTime::now() });
-
-        save_data_model(FILENAME, Some(text.to_owned()), last_known_modification_time, self)
+        self.clips[index].last_use_timestamp = now;
+        &self.clips[index]
     }
-}
 
-fn load_data_model(file : &str) -> (DataModel, SystemTime) {
-    use std::fs;
+    // drops the least recently used clips above `max_clips`, returns them.
+    fn prune(&mut self, max_clips : usize) -> Vec<DataEntry> {
+        if self.clips.len() <= max_clips {
+            return Vec::new();
+        }
 
-    if let Ok(contents) = fs::read_to_string(file) {
-        let modification_date = metadata(file).expect("metadata").modified().expect("metadata");
-        (toml::from_str(&contents).unwrap_or_default(), modification_date)
-    } else {
-        (DataModel::default(), SystemTime::now())
+        self.clips.sort_by_key(|e| std::cmp::Reverse(e.last_use_timestamp));
+        self.clips.split_off(max_clips)
     }
 }
 
-fn save_data_model(file : &str, last_stored_entry : Option<String>, last_known_modification_date : SystemTime, model : &mut DataModel) -> SystemTime {
-    use std::fs::File;
-
-    if let Ok(metadata) = metadata(file) {
-        if let Ok(modification_time) = metadata.modified() {
-            if modification_time > last_known_modification_date {
-                //then file was modified in the meantime.
+// fnv-1a, stable across builds unlike the std hasher.
+fn clip_key(text : &str) -> String {
+    let hash = text.bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    format!("{}{:016x}", CLIP_PREFIX, hash)
+}
 
-                let (loaded_model, modification_time) = load_data_model(file);
-                *model = loaded_model;
+fn put_clip(entry : &DataEntry) -> KvOp {
+    KvOp::Put { key : clip_key(&entry.text), value : toml::to_string(entry).expect("failed to serialize").into_bytes() }
+}
 
-                if let Some(last_stored_entry) = last_stored_entry {
-                    model.add_entry(&last_stored_entry, modification_time);
-                }
+fn delete_clip(entry : &DataEntry) -> KvOp {
+    KvOp::Delete(clip_key(&entry.text))
+}
 
-                return modification_time;
-            }
+// clips stored by older versions under clips/<text> are moved to their hashed keys on the way.
+fn load_data_model(host : &HostContext, max_clips : usize) -> Result<DataModel, String> {
+    let mut ops = Vec::new();
+    let mut clips = Vec::new();
+    for (key, value) in host.kv_scan(CLIP_PREFIX)? {
+        let entry : DataEntry = String::from_utf8(value).ok().and_then(|value| toml::from_str(&value).ok()).ok_or_else(|| format!("broken clip {}", key))?;
+        if key != clip_key(&entry.text) {
+            ops.push(KvOp::Delete(key));
+            ops.push(put_clip(&entry));
         }
+        clips.push(entry);
     }
 
-    let mut file = File::create(file).expect("couldnt create a file.");
-    file.write_all(toml::to_string_pretty(model).expect("failed to serialize").as_bytes()).expect("couldnt dump data model.");
+    let mut model = DataModel { clips };
+    ops.extend(model.prune(max_clips).iter().map(delete_clip));
+    if !ops.is_empty() {
+        host.kv_write(ops)?;
+    }
+    Ok(model)
+}
+
+// the whole file goes into the store in one write, then it is renamed so it isnt imported again.
+fn import_history(host : &HostContext, file : &Path) -> Result<(), String> {
+    let contents = match std::fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(()),
+    };
+
+    let model : DataModel = toml::from_str(&contents).map_err(|e| format!("{}: {}", file.display(), e))?;
+    host.kv_write(model.clips.iter().map(put_clip).collect())?;
 
-    SystemTime::now()
+    let imported = file.with_extension("toml.imported");
+    std::fs::rename(file, &imported).map_err(|e| format!("couldnt move {} to {}: {}", file.display(), imported.display(), e))
 }
 
 #[derive(Debug)]
 struct ClipboardPlugin {
+    host : HostContext,
     data_model : DataModel,
-    last_known_storage_modification_time : SystemTime,
+    max_clips : usize,
 }
 
 impl plugin_interface::Plugin for ClipboardPlugin {
@@ -94,7 +137,12 @@ impl plugin_interface::Plugin for ClipboardPlugin {
 
         ;
         let results : Vec<_> = matched.iter().filter(|(_, score)| *score >= score_requirement ).map(|(e, ..)| *e).map(| de : &DataEntry| {
-            PuszRowBuilder::new(de.text.clone(), PuszRowIdentifier::new(self.name(), de.text.clone())).build().unwrap()
+            // identifiers end up in the usage log on disk, the text itself must not.
+            let mut row = PuszRowBuilder::new(de.text.clone(), PuszRowIdentifier::new(self.name(), clip_key(&de.text))).build().unwrap();
+            // a click or Ctrl+C only copies, Return pastes where the user was.
+            row.main_entry.actions.insert(PuszEvent::KeyPress(KeyChord::Return), PuszAction::Paste);
+            row.main_entry.actions.insert(PuszEvent::KeyPress(KeyChord::CtrlC), PuszAction::SetClipboard);
+            row
         }).collect();
 
         PluginResult::Ok(results)
@@ -106,7 +154,7 @@ impl plugin_interface::Plugin for ClipboardPlugin {
     }
 
     fn name(&self) -> &'static str {
-        "clip"
+        NAME
     }
 
     fn settings(&self) -> PluginSettings {
@@ -119,32 +167,131 @@ impl plugin_interface::Plugin for ClipboardPlugin {
     fn on_subscribed_event(&mut self, event: &PluginEvent) {
         match event {
             PluginEvent::Clipboard(clipbard) => {
-                self.last_known_storage_modification_time = self.data_model.add_entry(clipbard, self.last_known_storage_modification_time);
+                let mut ops = vec![put_clip(self.data_model.add_entry(clipbard))];
+                ops.extend(self.data_model.prune(self.max_clips).iter().map(delete_clip));
+                if let Err(err) = self.host.kv_write(ops) {
+                    let _ = self.host.log(LogLevel::Error, &format!("couldnt store clip: {}", err));
+                }
             },
         }
     }
 }
 
-#[no_mangle]
-pub extern "C" fn load(plugin_interface_version : &str) -> Result<Box<dyn plugin_interface::Plugin>, String> {
-    if plugin_interface_version == plugin_interface::COMMON_INTERFACE_VERSION {
-        let (model, modification_time) = load_data_model(FILENAME);
-        Ok(Box::new(ClipboardPlugin{ data_model : model, last_known_storage_modification_time : modification_time}))
-    } else {
-        Err(format!("compatible with: {} but your version is: {}", plugin_interface::COMMON_INTERFACE_VERSION, plugin_interface_version))
-    }
-}
+fn load(context : &PluginContext) -> Result<Box<dyn plugin_interface::Plugin>, LoadError> {
+    let config : ClipboardConfig = context.config(NAME)?;
+    let history_file = context.data_dir(NAME)?.join(config.history_file);
+    let host = context.host().clone();
 
-#[no_mangle]
-pub extern "C" fn introduce() -> Box<dyn plugin_interface::Plugin> {
-//    let x = Box::new(ClipboardPlugin{});
-//    Box::into_raw(x)
-    let (model, modification_time) = load_data_model(FILENAME);
-    Box::new(ClipboardPlugin{ data_model : model, last_known_storage_modification_time : modification_time})
+    import_history(&host, &history_file).map_err(LoadError::Failed)?;
+    let model = load_data_model(&host, config.max_clips).map_err(LoadError::Failed)?;
+    Ok(Box::new(ClipboardPlugin{ host, data_model : model, max_clips : config.max_clips }))
 }
 
+plugin_interface::declare_plugin!(load);
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use plugin_interface::*;
+    use plugin_interface::host::{Host, HostCall, HostReply};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use std::fs;
+
+    // just the store part of the host.
+    #[derive(Default)]
+    struct MemoryStore(Mutex<BTreeMap<String, Vec<u8>>>);
+
+    impl Host for MemoryStore {
+        fn call(&self, call : HostCall) -> Result<HostReply, String> {
+            let mut values = self.0.lock().unwrap();
+            match call {
+                HostCall::KvScan(prefix) => Ok(HostReply::Entries(values.iter().filter(|(key, _)| key.starts_with(&prefix)).map(|(key, value)| (key.clone(), value.clone())).collect())),
+                HostCall::KvWrite(ops) => {
+                    for op in ops {
+                        match op {
+                            KvOp::Put { key, value } => values.insert(key, value),
+                            KvOp::Delete(key) => values.remove(&key),
+                        };
+                    }
+                    Ok(HostReply::Done)
+                },
+                other => Err(format!("unexpected {:?}", other)),
+            }
+        }
+    }
+
+    fn labels(plugin : &mut Box<dyn Plugin>) -> Vec<String> {
+        match plugin.query("") {
+            PluginResult::Ok(rows) => rows.into_iter().map(|row| row.main_entry.label).collect(),
+            other => panic!("expected rows, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn clips_are_imported_and_outlive_the_plugin() {
+        let data = std::env::temp_dir().join(format!("pusz_clipboard_plugin_tests_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data);
+        fs::create_dir_all(data.join(NAME)).unwrap();
+        let old = DataModel { clips : vec![DataEntry { text : "milk".to_owned(), last_use_timestamp : SystemTime::now() }] };
+        fs::write(data.join(NAME).join("clips.toml"), toml::to_string_pretty(&old).unwrap()).unwrap();
+
+        let context = PluginContext::new(BTreeMap::new(), data.clone()).with_host(HostContext::new(Arc::new(MemoryStore::default())));
+        let mut plugin = load(&context).unwrap();
+        assert!(!data.join(NAME).join("clips.toml").exists());
+
+        plugin.on_subscribed_event(&PluginEvent::Clipboard("bread".to_owned()));
+        plugin.on_subscribed_event(&PluginEvent::Clipboard("milk".to_owned()));
+
+        let mut reloaded = load(&context).unwrap();
+        assert_eq!(vec!["bread", "milk"], labels(&mut reloaded));
+
+        match reloaded.query("bread") {
+            PluginResult::Ok(rows) => assert_eq!(Some(&PuszAction::Paste), rows[0].main_entry.action(&PuszEvent::KeyPress(KeyChord::Return))),
+            other => panic!("expected rows, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn usage_log_never_sees_clip_text() {
+        let data = std::env::temp_dir().join(format!("pusz_clipboard_plugin_usage_tests_{}", std::process::id()));
+        let context = PluginContext::new(BTreeMap::new(), data.clone()).with_host(HostContext::new(Arc::new(MemoryStore::default())));
+        let mut plugin = load(&context).unwrap();
+        plugin.on_subscribed_event(&PluginEvent::Clipboard("hunter2".to_owned()));
+
+        let rows = match plugin.query("hunter") {
+            PluginResult::Ok(rows) => rows,
+            other => panic!("expected rows, got {:?}", other),
+        };
+        // what the usage log writes to usage.json for a picked row.
+        let logged = serde_json::to_string(&rows[0].identifier).unwrap();
+        assert_eq!("hunter2", rows[0].main_entry.content);
+        assert!(!logged.contains("hunter2"), "{}", logged);
+        let _ = fs::remove_dir_all(&data);
+    }
+
+    #[test]
+    fn old_keys_are_moved_and_history_is_capped() {
+        let data = std::env::temp_dir().join(format!("pusz_clipboard_plugin_cap_tests_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data);
+        let store = Arc::new(MemoryStore::default());
+        let old = DataEntry { text : "milk".to_owned(), last_use_timestamp : SystemTime::UNIX_EPOCH };
+        store.0.lock().unwrap().insert(format!("{}milk", CLIP_PREFIX), toml::to_string(&old).unwrap().into_bytes());
+
+        let mut sections = BTreeMap::new();
+        sections.insert(NAME.to_owned(), toml::from_str("max_clips = 2").unwrap());
+        let context = PluginContext::new(sections, data).with_host(HostContext::new(store.clone()));
+        let mut plugin = load(&context).unwrap();
+        assert_eq!(vec![clip_key("milk")], store.0.lock().unwrap().keys().cloned().collect::<Vec<_>>());
+
+        plugin.on_subscribed_event(&PluginEvent::Clipboard("bread".to_owned()));
+        plugin.on_subscribed_event(&PluginEvent::Clipboard("eggs".to_owned()));
+
+        let mut kept = store.0.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+        kept.sort();
+        let mut expected = vec![clip_key("bread"), clip_key("eggs")];
+        expected.sort();
+        assert_eq!(expected, kept);
+        assert_eq!(2, labels(&mut plugin).len());
+    }
 }
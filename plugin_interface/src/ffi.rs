@@ -0,0 +1,845 @@
+// the boundary between pusz and a plugin library.
+// only repr(C) types and json encoded data cross it, so a plugin built with another compiler
+// or another build of plugin_interface keeps working as long as ABI_VERSION is the same and the interface
+// version it was built against is supported, see COMMON_INTERFACE_VERSION.
+//
+// plugin side: `declare_plugin!(load)` with `fn load(&PluginContext) -> Result<Box<dyn Plugin>, LoadError>`.
+// host side: look up DECLARATION_SYMBOL and hand it to `PluginProxy::load`.
+// calls the other way, from the plugin to pusz, go through an FfiHost lent to the plugin at load.
+
+use std::any::Any;
+use std::ffi::{c_void, CStr};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::fmt;
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::host::{Host, HostCall, HostContext, HostReply, LogLevel};
+use crate::{CustomAction, Plugin, PluginContext, PluginEvent, PluginResult, PluginSettings, PuszRow, PuszRowIdentifier, ResultSink, LoadError, panic_message};
+use crate::manifest::supports;
+
+// bumped only when the layout of the repr(C) types below changes, what the json carries is versioned by
+// COMMON_INTERFACE_VERSION.
+pub const ABI_VERSION : u32 = 5;
+
+pub const DECLARATION_SYMBOL : &[u8] = b"PUSZ_PLUGIN_DECLARATION\0";
+
+// COMMON_INTERFACE_VERSION, nul terminated so it can sit in a static.
+#[doc(hidden)]
+pub const INTERFACE_VERSION_NUL : &[u8] = b"1.7.0\0";
+
+// borrowed utf8, only valid for the duration of the call.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FfiStr {
+    pub ptr : *const u8,
+    pub len : usize,
+}
+
+impl FfiStr {
+    pub fn new(text : &str) -> Self {
+        Self {
+            ptr : text.as_ptr(),
+            len : text.len(),
+        }
+    }
+
+    /// # Safety
+    /// `ptr` has to point at `len` bytes that stay valid and unchanged for `'a`.
+    pub unsafe fn as_bytes<'a>(&self) -> &'a [u8] {
+        std::slice::from_raw_parts(self.ptr, self.len)
+    }
+
+    /// # Safety
+    /// same as `as_bytes`.
+    pub unsafe fn as_str<'a>(&self) -> Result<&'a str, String> {
+        std::str::from_utf8(self.as_bytes()).map_err(|e| format!("not utf8: {}", e))
+    }
+}
+
+// bytes allocated by one side, has to be released by the free_buffer of that same side.
+#[repr(C)]
+pub struct FfiBuffer {
+    pub ptr : *mut u8,
+    pub len : usize,
+    pub capacity : usize,
+}
+
+impl FfiBuffer {
+    pub fn from_vec(mut bytes : Vec<u8>) -> Self {
+        let buffer = Self {
+            ptr : bytes.as_mut_ptr(),
+            len : bytes.len(),
+            capacity : bytes.capacity(),
+        };
+        std::mem::forget(bytes);
+        buffer
+    }
+
+    /// # Safety
+    /// the buffer has to come from `from_vec` and not be freed yet.
+    pub unsafe fn as_bytes(&self) -> &[u8] {
+        std::slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+/// # Safety
+/// `buffer` has to come from `FfiBuffer::from_vec` of this same build and is gone afterwards.
+pub unsafe extern "C" fn free_buffer(buffer : FfiBuffer) {
+    drop(Vec::from_raw_parts(buffer.ptr, buffer.len, buffer.capacity));
+}
+
+// host side ResultSink lent to the plugin for one query_streaming call.
+#[repr(C)]
+pub struct FfiSink {
+    pub sink : *mut c_void,
+    // json Vec<PuszRow> in.
+    pub push : unsafe extern "C" fn(*mut c_void, FfiStr),
+    pub cancelled : unsafe extern "C" fn(*mut c_void) -> bool,
+}
+
+// pusz as seen from the plugin, owned by the plugin until it calls release.
+#[repr(C)]
+pub struct FfiHost {
+    pub host : *const c_void,
+    // json HostCall in, json Result<HostReply, String> out, to be freed with free_buffer.
+    pub call : unsafe extern "C" fn(*const c_void, FfiStr) -> FfiBuffer,
+    pub free_buffer : unsafe extern "C" fn(FfiBuffer),
+    pub release : unsafe extern "C" fn(*const c_void),
+}
+
+#[repr(C)]
+pub struct PluginVTable {
+    pub drop : unsafe extern "C" fn(*mut c_void),
+    // json Result<String, String>, Err when the plugin panicked.
+    pub name : unsafe extern "C" fn(*mut c_void) -> FfiBuffer,
+    // json Result<PluginSettings, String>.
+    pub settings : unsafe extern "C" fn(*mut c_void) -> FfiBuffer,
+    // query in, json PluginResult out.
+    pub query : unsafe extern "C" fn(*mut c_void, FfiStr) -> FfiBuffer,
+    pub query_return : unsafe extern "C" fn(*mut c_void, FfiStr) -> FfiBuffer,
+    // json (PuszRowIdentifier, CustomAction) in, json PluginResult out.
+    pub action_request : unsafe extern "C" fn(*mut c_void, FfiStr) -> FfiBuffer,
+    pub query_streaming : unsafe extern "C" fn(*mut c_void, FfiStr, FfiSink) -> FfiBuffer,
+    // json PluginEvent in.
+    pub on_subscribed_event : unsafe extern "C" fn(*mut c_void, FfiStr),
+    // json Result<Option<String>, String>.
+    pub save_state : unsafe extern "C" fn(*mut c_void) -> FfiBuffer,
+    pub restore_state : unsafe extern "C" fn(*mut c_void, FfiStr),
+}
+
+#[repr(C)]
+pub struct FfiPlugin {
+    pub instance : *mut c_void,
+    pub vtable : *const PluginVTable,
+}
+
+#[repr(C)]
+pub struct PluginDeclaration {
+    // has to stay the first field, it is the only thing read before the rest can be trusted.
+    pub abi_version : u32,
+    // nul terminated COMMON_INTERFACE_VERSION the plugin was built against.
+    pub interface_version : *const c_char,
+    pub free_buffer : unsafe extern "C" fn(FfiBuffer),
+    // json PluginContext in, json Result<(), LoadError> out, the plugin is written to the out pointer only on success.
+    pub load : unsafe extern "C" fn(FfiStr, FfiHost, *mut FfiPlugin) -> FfiBuffer,
+}
+
+// only holds pointers to static data.
+unsafe impl Sync for PluginDeclaration {}
+
+#[macro_export]
+macro_rules! declare_plugin {
+    ($load:path) => {
+        #[no_mangle]
+        pub static PUSZ_PLUGIN_DECLARATION : $crate::ffi::PluginDeclaration = $crate::ffi::PluginDeclaration {
+            abi_version : $crate::ffi::ABI_VERSION,
+            interface_version : $crate::ffi::INTERFACE_VERSION_NUL.as_ptr() as *const ::std::os::raw::c_char,
+            free_buffer : $crate::ffi::free_buffer,
+            load : {
+                unsafe extern "C" fn pusz_load_shim(context : $crate::ffi::FfiStr, host : $crate::ffi::FfiHost, out : *mut $crate::ffi::FfiPlugin) -> $crate::ffi::FfiBuffer {
+                    $crate::ffi::load_with(context, host, out, $load)
+                }
+                pusz_load_shim
+            },
+        };
+    };
+}
+
+fn to_buffer<T : Serialize>(value : &T) -> FfiBuffer {
+    FfiBuffer::from_vec(serde_json::to_vec(value).expect("plugin data is always serializable"))
+}
+
+fn from_bytes<T : DeserializeOwned>(bytes : &[u8]) -> Result<T, String> {
+    serde_json::from_slice(bytes).map_err(|e| e.to_string())
+}
+
+// plugin side.
+
+// what the vtable's instance pointer points at.
+struct Instance {
+    plugin : Box<dyn Plugin>,
+    // where the shims report what they cant hand back in a result.
+    host : HostContext,
+}
+
+unsafe fn exported<'a>(instance : *mut c_void) -> &'a mut Instance {
+    &mut *(instance as *mut Instance)
+}
+
+unsafe fn instance<'a>(instance : *mut c_void) -> &'a mut Box<dyn Plugin> {
+    &mut exported(instance).plugin
+}
+
+// into the pusz log, nothing more to do when even that fails.
+unsafe fn report(instance : *mut c_void, message : String) {
+    let _ = exported(instance).host.log(LogLevel::Warn, &message);
+}
+
+// unwinding across extern "C" aborts the whole process, so every shim stops panics here.
+fn guard<T>(f : impl FnOnce() -> T) -> Result<T, String> {
+    catch_unwind(AssertUnwindSafe(f)).map_err(|payload| format!("plugin panicked: {}", panic_message(&*payload)))
+}
+
+unsafe extern "C" fn drop_shim(plugin : *mut c_void) {
+    let _ = guard(|| drop(Box::from_raw(plugin as *mut Instance)));
+}
+
+// json Result<String, String>.
+unsafe extern "C" fn name_shim(plugin : *mut c_void) -> FfiBuffer {
+    to_buffer(&guard(|| instance(plugin).name()))
+}
+
+// json Result<PluginSettings, String>.
+unsafe extern "C" fn settings_shim(plugin : *mut c_void) -> FfiBuffer {
+    to_buffer(&guard(|| instance(plugin).settings()))
+}
+
+fn query_guarded(f : impl FnOnce() -> PluginResult) -> FfiBuffer {
+    to_buffer(&guard(f).unwrap_or_else(PluginResult::Error))
+}
+
+// a broken query is an error rather than an empty query.
+unsafe fn with_query(query : FfiStr, f : impl FnOnce(&str) -> PluginResult) -> PluginResult {
+    match query.as_str() {
+        Ok(query) => f(query),
+        Err(err) => PluginResult::Error(format!("unreadable query: {}", err)),
+    }
+}
+
+unsafe extern "C" fn query_shim(plugin : *mut c_void, query : FfiStr) -> FfiBuffer {
+    query_guarded(|| with_query(query, |query| instance(plugin).query(query)))
+}
+
+unsafe extern "C" fn query_return_shim(plugin : *mut c_void, query : FfiStr) -> FfiBuffer {
+    query_guarded(|| with_query(query, |query| instance(plugin).query_return(query)))
+}
+
+unsafe extern "C" fn action_request_shim(plugin : *mut c_void, request : FfiStr) -> FfiBuffer {
+    query_guarded(|| match from_bytes::<(PuszRowIdentifier, CustomAction)>(request.as_bytes()) {
+        Ok((row, action)) => instance(plugin).action_request(&row, &action),
+        Err(err) => PluginResult::Error(format!("unreadable action request: {}", err)),
+    })
+}
+
+// the host sink as seen from the plugin.
+struct SinkShim(FfiSink);
+
+impl ResultSink for SinkShim {
+    fn push(&mut self, rows : Vec<PuszRow>) {
+        let rows = serde_json::to_string(&rows).expect("plugin data is always serializable");
+        unsafe { (self.0.push)(self.0.sink, FfiStr::new(&rows)) }
+    }
+
+    fn cancelled(&self) -> bool {
+        unsafe { (self.0.cancelled)(self.0.sink) }
+    }
+}
+
+unsafe extern "C" fn query_streaming_shim(plugin : *mut c_void, query : FfiStr, sink : FfiSink) -> FfiBuffer {
+    query_guarded(|| with_query(query, |query| instance(plugin).query_streaming(query, &mut SinkShim(sink))))
+}
+
+unsafe extern "C" fn on_subscribed_event_shim(plugin : *mut c_void, event : FfiStr) {
+    let handled = from_bytes::<PluginEvent>(event.as_bytes())
+        .map_err(|e| format!("unreadable event: {}", e))
+        .and_then(|event| guard(|| instance(plugin).on_subscribed_event(&event)));
+    if let Err(err) = handled {
+        report(plugin, err);
+    }
+}
+
+unsafe extern "C" fn save_state_shim(plugin : *mut c_void) -> FfiBuffer {
+    to_buffer(&guard(|| instance(plugin).save_state()))
+}
+
+// the plugin starts over when it doesnt get its state.
+unsafe extern "C" fn restore_state_shim(plugin : *mut c_void, state : FfiStr) {
+    let restored = state.as_str()
+        .map_err(|e| format!("unreadable state: {}", e))
+        .and_then(|state| guard(|| instance(plugin).restore_state(state)));
+    if let Err(err) = restored {
+        report(plugin, err);
+    }
+}
+
+static VTABLE : PluginVTable = PluginVTable {
+    drop : drop_shim,
+    name : name_shim,
+    settings : settings_shim,
+    query : query_shim,
+    query_return : query_return_shim,
+    action_request : action_request_shim,
+    query_streaming : query_streaming_shim,
+    on_subscribed_event : on_subscribed_event_shim,
+    save_state : save_state_shim,
+    restore_state : restore_state_shim,
+};
+
+pub fn export(plugin : Box<dyn Plugin>, host : HostContext) -> FfiPlugin {
+    FfiPlugin {
+        instance : Box::into_raw(Box::new(Instance { plugin, host })) as *mut c_void,
+        vtable : &VTABLE,
+    }
+}
+
+// the host lent to the plugin, released once the last HostContext clone is gone.
+struct HostShim(FfiHost);
+
+// the host side behind it is a HostContext, which is Send + Sync.
+unsafe impl Send for HostShim {}
+unsafe impl Sync for HostShim {}
+
+impl Host for HostShim {
+    fn call(&self, call : HostCall) -> Result<HostReply, String> {
+        let call = serde_json::to_string(&call).expect("host calls serialize");
+        unsafe { take::<Result<HostReply, String>>(self.0.free_buffer, (self.0.call)(self.0.host, FfiStr::new(&call))) }?
+    }
+}
+
+impl Drop for HostShim {
+    fn drop(&mut self) {
+        unsafe { (self.0.release)(self.0.host) }
+    }
+}
+
+/// # Safety
+/// the arguments are the ones `PluginDeclaration::load` was called with, `out` has to be writable.
+#[doc(hidden)]
+pub unsafe fn load_with(context : FfiStr, host : FfiHost, out : *mut FfiPlugin, load : fn(&PluginContext) -> Result<Box<dyn Plugin>, LoadError>) -> FfiBuffer {
+    let host = HostContext::new(Arc::new(HostShim(host)));
+    let result = from_bytes::<PluginContext>(context.as_bytes())
+        .map(|context| context.with_host(host.clone()))
+        .map_err(|e| LoadError::Failed(format!("couldnt read plugin context: {}", e)))
+        .and_then(|context| guard(|| load(&context)).unwrap_or_else(|e| Err(LoadError::Failed(e))))
+        .map(|plugin| *out = export(plugin, host));
+
+    to_buffer(&result)
+}
+
+// host side.
+
+unsafe extern "C" fn host_call_shim(host : *const c_void, call : FfiStr) -> FfiBuffer {
+    let host = &*(host as *const HostContext);
+    let reply = from_bytes::<HostCall>(call.as_bytes()).and_then(|call| {
+        // a panic must not unwind into the plugin either.
+        catch_unwind(AssertUnwindSafe(|| host.call(call))).unwrap_or_else(|payload| Err(format!("host panicked: {}", panic_message(&*payload))))
+    });
+    to_buffer(&reply)
+}
+
+unsafe extern "C" fn host_release_shim(host : *const c_void) {
+    drop(Box::from_raw(host as *mut HostContext));
+}
+
+pub fn export_host(host : HostContext) -> FfiHost {
+    FfiHost {
+        host : Box::into_raw(Box::new(host)) as *const c_void,
+        call : host_call_shim,
+        free_buffer,
+        release : host_release_shim,
+    }
+}
+
+pub struct PluginProxy {
+    plugin : FfiPlugin,
+    free_buffer : unsafe extern "C" fn(FfiBuffer),
+
+    // Plugin::name hands out &'static str, one leaked name per load is fine.
+    name : &'static str,
+    settings : PluginSettings,
+
+    // whatever keeps the vtable loaded, dropped after the plugin instance.
+    library : Option<Box<dyn Any + Send>>,
+}
+
+impl PluginProxy {
+    /// # Safety
+    /// `declaration` has to point into a library that stays loaded for as long as the proxy lives.
+    pub unsafe fn load(declaration : *const PluginDeclaration, context : &PluginContext) -> Result<Self, LoadError> {
+        let abi_version = std::ptr::read(declaration as *const u32);
+        if abi_version != ABI_VERSION {
+            return Err(LoadError::UnsupportedAbi(abi_version));
+        }
+
+        let declaration = &*declaration;
+        let built_against = CStr::from_ptr(declaration.interface_version).to_string_lossy();
+        if !is_compatible(&built_against) {
+            return Err(LoadError::UnsupportedInterface(built_against.into_owned()));
+        }
+
+        let context_host = context.host();
+        let context = serde_json::to_string(context).map_err(|e| LoadError::Failed(e.to_string()))?;
+        let mut plugin = FfiPlugin {
+            instance : std::ptr::null_mut(),
+            vtable : std::ptr::null(),
+        };
+
+        let host = export_host(context_host.clone());
+        let loaded : Result<(), LoadError> = take(declaration.free_buffer, (declaration.load)(FfiStr::new(&context), host, &mut plugin))
+            .map_err(|e| LoadError::Failed(format!("unreadable load result: {}", e)))?;
+        loaded?;
+
+        let mut proxy = Self {
+            plugin,
+            free_buffer : declaration.free_buffer,
+            name : "",
+            settings : PluginSettings {
+                requies_explicit_query : true,
+                interested_in_clipboard : false,
+            },
+            library : None,
+        };
+
+        let name : String = proxy.call(|plugin| ((*plugin.vtable).name)(plugin.instance)).and_then(|name| name).map_err(LoadError::Failed)?;
+        proxy.name = Box::leak(name.into_boxed_str());
+        proxy.settings = proxy.call(|plugin| ((*plugin.vtable).settings)(plugin.instance)).and_then(|settings| settings).map_err(LoadError::Failed)?;
+
+        Ok(proxy)
+    }
+
+    // unloads `library` once the proxy is gone.
+    pub fn owning<L : Any + Send>(mut self, library : L) -> Self {
+        self.library = Some(Box::new(library));
+        self
+    }
+
+    fn call<T : DeserializeOwned>(&self, f : impl FnOnce(&FfiPlugin) -> FfiBuffer) -> Result<T, String> {
+        unsafe { take(self.free_buffer, f(&self.plugin)) }
+    }
+
+    fn call_query(&mut self, query : &str, f : unsafe extern "C" fn(*mut c_void, FfiStr) -> FfiBuffer) -> PluginResult {
+        self.call(|plugin| unsafe { f(plugin.instance, FfiStr::new(query)) })
+            .unwrap_or_else(|e| PluginResult::Error(format!("unreadable result from {}: {}", self.name, e)))
+    }
+
+    fn vtable(&self) -> &PluginVTable {
+        unsafe { &*self.plugin.vtable }
+    }
+}
+
+// built against x.y.z asks for ^x.y.z.
+fn is_compatible(built_against : &str) -> bool {
+    semver::VersionReq::parse(&format!("^{}", built_against)).is_ok_and(|required| supports(&required).is_ok())
+}
+
+unsafe fn take<T : DeserializeOwned>(free_buffer : unsafe extern "C" fn(FfiBuffer), buffer : FfiBuffer) -> Result<T, String> {
+    let value = from_bytes(buffer.as_bytes());
+    free_buffer(buffer);
+    value
+}
+
+impl Plugin for PluginProxy {
+    fn query(&mut self, query : &str) -> PluginResult {
+        let f = self.vtable().query;
+        self.call_query(query, f)
+    }
+
+    fn query_return(&mut self, query : &str) -> PluginResult {
+        let f = self.vtable().query_return;
+        self.call_query(query, f)
+    }
+
+    fn action_request(&mut self, row : &PuszRowIdentifier, action : &CustomAction) -> PluginResult {
+        let f = self.vtable().action_request;
+        let request = serde_json::to_string(&(row, action)).expect("action requests serialize");
+        self.call_query(&request, f)
+    }
+
+    fn query_streaming(&mut self, query : &str, sink : &mut dyn ResultSink) -> PluginResult {
+        // called back from the plugin, panics must not unwind into it.
+        unsafe extern "C" fn push(sink : *mut c_void, rows : FfiStr) {
+            let sink = &mut *(sink as *mut &mut dyn ResultSink);
+            // a batch that doesnt parse is dropped, only a mismatched build would send one.
+            if let Ok(rows) = from_bytes::<Vec<PuszRow>>(rows.as_bytes()) {
+                let _ = catch_unwind(AssertUnwindSafe(|| sink.push(rows)));
+            }
+        }
+
+        unsafe extern "C" fn cancelled(sink : *mut c_void) -> bool {
+            let sink = &*(sink as *const &mut dyn ResultSink);
+            catch_unwind(AssertUnwindSafe(|| sink.cancelled())).unwrap_or(true)
+        }
+
+        let mut sink = sink;
+        let ffi_sink = FfiSink {
+            sink : &mut sink as *mut &mut dyn ResultSink as *mut c_void,
+            push,
+            cancelled,
+        };
+
+        let f = self.vtable().query_streaming;
+        let instance = self.plugin.instance;
+        let result = self.call(|_| unsafe { f(instance, FfiStr::new(query), ffi_sink) });
+        result.unwrap_or_else(|e| PluginResult::Error(format!("unreadable result from {}: {}", self.name, e)))
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn settings(&self) -> PluginSettings {
+        self.settings.clone()
+    }
+
+    fn on_subscribed_event(&mut self, event : &PluginEvent) {
+        let event = serde_json::to_string(event).expect("plugin data is always serializable");
+        unsafe { (self.vtable().on_subscribed_event)(self.plugin.instance, FfiStr::new(&event)) }
+    }
+
+    fn save_state(&mut self) -> Option<String> {
+        // a plugin that fails to save simply starts over.
+        let state : Result<Option<String>, String> = self.call(|plugin| unsafe { ((*plugin.vtable).save_state)(plugin.instance) }).and_then(|state| state);
+        state.unwrap_or_default()
+    }
+
+    fn restore_state(&mut self, state : &str) {
+        unsafe { (self.vtable().restore_state)(self.plugin.instance, FfiStr::new(state)) }
+    }
+}
+
+// the instance behind the vtable is a Box<dyn Plugin> on the plugin side, which is Send.
+unsafe impl Send for PluginProxy {}
+
+impl Drop for PluginProxy {
+    fn drop(&mut self) {
+        unsafe { (self.vtable().drop)(self.plugin.instance) }
+    }
+}
+
+impl fmt::Debug for PluginProxy {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PluginProxy({})", self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PuszRowBuilder, PuszRowIdentifier, PuszEvent, PuszAction, KeyChord};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug)]
+    struct EchoPlugin {
+        events : Vec<PluginEvent>,
+    }
+
+    impl Plugin for EchoPlugin {
+        fn query(&mut self, query : &str) -> PluginResult {
+            if query == "panic" {
+                panic!("echo gave up");
+            }
+
+            let mut row = PuszRowBuilder::new(query.to_owned(), PuszRowIdentifier::new(self.name(), format!("{}", self.events.len()))).build().unwrap();
+            row.main_entry.actions.insert(PuszEvent::KeyPress(KeyChord::ShiftReturn), PuszAction::OpenBrowserIfLink);
+            PluginResult::Ok(vec![row])
+        }
+
+        // every word is a batch of its own.
+        fn query_streaming(&mut self, query : &str, sink : &mut dyn ResultSink) -> PluginResult {
+            for word in query.split_whitespace() {
+                if sink.cancelled() {
+                    break;
+                }
+                if let PluginResult::Ok(rows) = self.query(word) {
+                    sink.push(rows);
+                }
+            }
+            PluginResult::None
+        }
+
+        fn action_request(&mut self, row : &PuszRowIdentifier, action : &CustomAction) -> PluginResult {
+            match action.id.as_str() {
+                "close" => PluginResult::Close,
+                _ => PluginResult::Error(format!("{} {} on {}", action.id, action.payload.as_deref().unwrap_or(""), row.identifier)),
+            }
+        }
+
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn on_subscribed_event(&mut self, event : &PluginEvent) {
+            self.events.push(event.clone());
+        }
+
+        fn save_state(&mut self) -> Option<String> {
+            Some(self.events.len().to_string())
+        }
+
+        fn restore_state(&mut self, state : &str) {
+            let count : usize = state.parse().unwrap();
+            self.events.resize(count, PluginEvent::Clipboard(String::new()));
+        }
+    }
+
+    fn load_echo(_context : &PluginContext) -> Result<Box<dyn Plugin>, LoadError> {
+        Ok(Box::new(EchoPlugin { events : vec![] }))
+    }
+
+    fn load_broken(_context : &PluginContext) -> Result<Box<dyn Plugin>, LoadError> {
+        Err(LoadError::Failed("never loads".to_owned()))
+    }
+
+    mod echo {
+        declare_plugin!(super::load_echo);
+    }
+
+    // single declaration per binary because of no_mangle, the others are copies with fields swapped.
+    fn declaration() -> PluginDeclaration {
+        PluginDeclaration { ..echo::PUSZ_PLUGIN_DECLARATION }
+    }
+
+    fn context() -> PluginContext {
+        PluginContext::new(BTreeMap::new(), std::env::temp_dir())
+    }
+
+    #[test]
+    fn interface_version_is_nul_terminated_copy() {
+        assert_eq!(crate::COMMON_INTERFACE_VERSION.as_bytes(), &INTERFACE_VERSION_NUL[..INTERFACE_VERSION_NUL.len() - 1]);
+        assert_eq!(Some(&0), INTERFACE_VERSION_NUL.last());
+    }
+
+    #[test]
+    fn calls_go_through_the_vtable() {
+        let mut plugin = unsafe { PluginProxy::load(&echo::PUSZ_PLUGIN_DECLARATION, &context()) }.unwrap();
+
+        assert_eq!("echo", plugin.name());
+        assert_eq!(PluginSettings { requies_explicit_query : true, interested_in_clipboard : false }, plugin.settings());
+
+        plugin.on_subscribed_event(&PluginEvent::Clipboard("copied".to_owned()));
+        match plugin.query("2+2") {
+            PluginResult::Ok(rows) => {
+                assert_eq!("2+2", rows[0].main_entry.content);
+                assert_eq!(PuszRowIdentifier::new("echo", "1".to_owned()), rows[0].identifier);
+                assert_eq!(Some(&PuszAction::OpenBrowserIfLink), rows[0].main_entry.actions.get(&PuszEvent::KeyPress(KeyChord::ShiftReturn)));
+            },
+            other => panic!("expected rows, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn custom_actions_reach_the_plugin() {
+        let mut plugin = unsafe { PluginProxy::load(&echo::PUSZ_PLUGIN_DECLARATION, &context()) }.unwrap();
+        let row = PuszRowIdentifier::new("echo", "7".to_owned());
+
+        assert_eq!(PluginResult::Close, plugin.action_request(&row, &CustomAction { id : "close".to_owned(), payload : None }));
+        let action = CustomAction { id : "open".to_owned(), payload : Some("tab".to_owned()) };
+        assert_eq!(PluginResult::Error("open tab on 7".to_owned()), plugin.action_request(&row, &action));
+    }
+
+    // copies whatever it is asked for into the host clipboard and answers with what the host has there.
+    #[derive(Debug)]
+    struct ClipboardUser {
+        host : HostContext,
+    }
+
+    impl Plugin for ClipboardUser {
+        fn query(&mut self, query : &str) -> PluginResult {
+            match self.host.set_clipboard(query).and_then(|_| self.host.clipboard()) {
+                Ok(clipboard) => PluginResult::Error(format!("{:?}", clipboard)),
+                Err(err) => PluginResult::Error(err),
+            }
+        }
+
+        fn name(&self) -> &'static str {
+            "clipboard user"
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeHost {
+        clipboard : std::sync::Mutex<Option<String>>,
+        logged : std::sync::Mutex<Vec<(LogLevel, String)>>,
+    }
+
+    impl Host for FakeHost {
+        fn call(&self, call : HostCall) -> Result<HostReply, String> {
+            match call {
+                HostCall::Log { level, message } => self.logged.lock().unwrap().push((level, message)),
+                HostCall::SetClipboard(text) if text == "panic" => panic!("clipboard is gone"),
+                HostCall::SetClipboard(text) => *self.clipboard.lock().unwrap() = Some(text),
+                HostCall::GetClipboard => return Ok(HostReply::Clipboard(self.clipboard.lock().unwrap().clone())),
+                other => return Err(format!("{:?} not faked", other)),
+            }
+            Ok(HostReply::Done)
+        }
+    }
+
+    #[test]
+    fn host_calls_cross_the_boundary() {
+        fn load_user(context : &PluginContext) -> Result<Box<dyn Plugin>, LoadError> {
+            Ok(Box::new(ClipboardUser { host : context.host().clone() }))
+        }
+
+        unsafe extern "C" fn load(context : FfiStr, host : FfiHost, out : *mut FfiPlugin) -> FfiBuffer {
+            load_with(context, host, out, load_user)
+        }
+
+        let host = Arc::new(FakeHost::default());
+        let declaration = PluginDeclaration { load, ..declaration() };
+        let context = context().with_host(HostContext::new(host.clone()));
+        let mut plugin = unsafe { PluginProxy::load(&declaration, &context) }.unwrap();
+        drop(context);
+
+        assert_eq!(PluginResult::Error("Some(\"copied\")".to_owned()), plugin.query("copied"));
+        assert_eq!(Some("copied".to_owned()), *host.clipboard.lock().unwrap());
+        assert_eq!(PluginResult::Error("host panicked: clipboard is gone".to_owned()), plugin.query("panic"));
+
+        // the plugin let go of the host it was lent.
+        drop(plugin);
+        assert_eq!(1, Arc::strong_count(&host));
+    }
+
+    #[test]
+    fn unreadable_input_is_reported_not_dropped() {
+        let host = Arc::new(FakeHost::default());
+        let context = context().with_host(HostContext::new(host.clone()));
+        let mut plugin = unsafe { PluginProxy::load(&echo::PUSZ_PLUGIN_DECLARATION, &context) }.unwrap();
+        let not_utf8 = [b'a', 0xff];
+        let not_utf8 = FfiStr { ptr : not_utf8.as_ptr(), len : not_utf8.len() };
+
+        unsafe { (plugin.vtable().on_subscribed_event)(plugin.plugin.instance, FfiStr::new("{\"Unknown\":1}")) };
+        unsafe { (plugin.vtable().restore_state)(plugin.plugin.instance, not_utf8) };
+        let logged = host.logged.lock().unwrap().clone();
+        assert_eq!(2, logged.len(), "{:?}", logged);
+        assert!(logged[0].1.starts_with("unreadable event: unknown variant `Unknown`"), "{:?}", logged);
+        assert_eq!((LogLevel::Warn, "unreadable state: not utf8: invalid utf-8 sequence of 1 bytes from index 1".to_owned()), logged[1]);
+
+        let f = plugin.vtable().query;
+        let result = plugin.call(|plugin| unsafe { f(plugin.instance, not_utf8) });
+        assert_eq!(Ok(PluginResult::Error("unreadable query: not utf8: invalid utf-8 sequence of 1 bytes from index 1".to_owned())), result);
+        assert!(matches!(plugin.query("still fine"), PluginResult::Ok(_)));
+    }
+
+    #[test]
+    fn load_errors_cross_the_boundary() {
+        unsafe extern "C" fn load(context : FfiStr, host : FfiHost, out : *mut FfiPlugin) -> FfiBuffer {
+            load_with(context, host, out, load_broken)
+        }
+
+        let declaration = PluginDeclaration {
+            load,
+            ..declaration()
+        };
+        let err = unsafe { PluginProxy::load(&declaration, &context()) }.unwrap_err();
+        assert_eq!(LoadError::Failed("never loads".to_owned()), err);
+    }
+
+    #[test]
+    fn reload_hands_over_state_and_unloads_library() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        struct Library(Arc<AtomicBool>);
+
+        impl Drop for Library {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let unloaded = Arc::new(AtomicBool::new(false));
+        let mut plugin = unsafe { PluginProxy::load(&echo::PUSZ_PLUGIN_DECLARATION, &context()) }.unwrap().owning(Library(unloaded.clone()));
+        plugin.on_subscribed_event(&PluginEvent::Clipboard("copied".to_owned()));
+        assert_eq!(Some("1".to_owned()), plugin.save_state());
+
+        let mut reloaded = unsafe { PluginProxy::load(&echo::PUSZ_PLUGIN_DECLARATION, &context()) }.unwrap();
+        reloaded.restore_state("1");
+        assert_eq!(Some("1".to_owned()), reloaded.save_state());
+
+        drop(plugin);
+        assert!(unloaded.load(Ordering::SeqCst));
+    }
+
+    struct CollectingSink {
+        batches : Vec<Vec<String>>,
+        cancel_after : usize,
+    }
+
+    impl ResultSink for CollectingSink {
+        fn push(&mut self, rows : Vec<PuszRow>) {
+            self.batches.push(rows.into_iter().map(|row| row.main_entry.label).collect());
+        }
+
+        fn cancelled(&self) -> bool {
+            self.batches.len() >= self.cancel_after
+        }
+    }
+
+    #[test]
+    fn streamed_batches_cross_the_boundary() {
+        let mut plugin = unsafe { PluginProxy::load(&echo::PUSZ_PLUGIN_DECLARATION, &context()) }.unwrap();
+        let mut sink = CollectingSink { batches : vec![], cancel_after : 2 };
+
+        assert_eq!(PluginResult::None, plugin.query_streaming("a b c", &mut sink));
+        assert_eq!(vec![vec!["a".to_owned()], vec!["b".to_owned()]], sink.batches);
+    }
+
+    #[test]
+    fn panics_stay_inside_the_plugin() {
+        let mut plugin = unsafe { PluginProxy::load(&echo::PUSZ_PLUGIN_DECLARATION, &context()) }.unwrap();
+
+        assert_eq!(PluginResult::Error("plugin panicked: echo gave up".to_owned()), plugin.query("panic"));
+        match plugin.query("still alive") {
+            PluginResult::Ok(rows) => assert_eq!(1, rows.len()),
+            other => panic!("expected rows, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mismatched_versions_are_rejected_before_load() {
+        unsafe extern "C" fn load_panics(_ : FfiStr, _ : FfiHost, _ : *mut FfiPlugin) -> FfiBuffer {
+            panic!("must not be called");
+        }
+
+        let mut declaration = PluginDeclaration {
+            abi_version : ABI_VERSION + 1,
+            load : load_panics,
+            ..declaration()
+        };
+        assert_eq!(LoadError::UnsupportedAbi(ABI_VERSION + 1), unsafe { PluginProxy::load(&declaration, &context()) }.unwrap_err());
+
+        declaration.abi_version = ABI_VERSION;
+        declaration.interface_version = b"0.9.0\0".as_ptr() as *const c_char;
+        assert_eq!(LoadError::UnsupportedInterface("0.9.0".to_owned()), unsafe { PluginProxy::load(&declaration, &context()) }.unwrap_err());
+
+        declaration.interface_version = b"1.8.0\0".as_ptr() as *const c_char;
+        assert_eq!(LoadError::UnsupportedInterface("1.8.0".to_owned()), unsafe { PluginProxy::load(&declaration, &context()) }.unwrap_err());
+    }
+
+    #[test]
+    fn plugins_built_against_older_interfaces_still_load() {
+        assert!(is_compatible("1.0.0"));
+        assert!(is_compatible(crate::COMMON_INTERFACE_VERSION));
+
+        // 1.5 logged with a number.
+        let call : HostCall = serde_json::from_str(r#"{"Log":{"level":2,"message":"careful"}}"#).unwrap();
+        assert_eq!(HostCall::Log { level : LogLevel::Warn, message : "careful".to_owned() }, call);
+        let call : HostCall = serde_json::from_str(&serde_json::to_string(&HostCall::Log { level : LogLevel::Trace, message : String::new() }).unwrap()).unwrap();
+        assert_eq!(HostCall::Log { level : LogLevel::Trace, message : String::new() }, call);
+    }
+}
@@ -0,0 +1,204 @@
+// what pusz does on behalf of a plugin. a plugin gets its HostContext from PluginContext::host at load time
+// and may keep it for as long as it lives, native plugins reach the host through the same json calls as
+// the host reaches them.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum HostCall {
+    SetClipboard(String),
+    GetClipboard,
+    OpenUrl(String),
+    // short message shown in the pusz window for a moment.
+    ShowToast(String),
+    Log { level : LogLevel, message : String },
+    // runs the current query again, for plugins whose results changed on their own.
+    RefreshResults,
+    // files directly in the plugin's data dir, None when it doesnt exist.
+    ReadData(String),
+    WriteData { file : String, contents : Vec<u8> },
+    // the plugin's own key value store, kept by pusz in its data dir.
+    KvGet(String),
+    // every key starting with the prefix, in key order.
+    KvScan(String),
+    // all of the changes are stored or none of them.
+    KvWrite(Vec<KvOp>),
+}
+
+// same levels as log::Level, without making plugins depend on log.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(try_from = "WireLogLevel")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+// plugins built against interface 1.5 and older send 1 error .. 5 trace instead of the name.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WireLogLevel {
+    Name(String),
+    Number(u8),
+}
+
+impl TryFrom<WireLogLevel> for LogLevel {
+    type Error = String;
+
+    fn try_from(level : WireLogLevel) -> Result<Self, String> {
+        match level {
+            WireLogLevel::Name(name) => match name.as_str() {
+                "Error" => Ok(LogLevel::Error),
+                "Warn" => Ok(LogLevel::Warn),
+                "Info" => Ok(LogLevel::Info),
+                "Debug" => Ok(LogLevel::Debug),
+                "Trace" => Ok(LogLevel::Trace),
+                _ => Err(format!("unknown log level {}", name)),
+            },
+            WireLogLevel::Number(1) => Ok(LogLevel::Error),
+            WireLogLevel::Number(2) => Ok(LogLevel::Warn),
+            WireLogLevel::Number(3) => Ok(LogLevel::Info),
+            WireLogLevel::Number(4) => Ok(LogLevel::Debug),
+            WireLogLevel::Number(5) => Ok(LogLevel::Trace),
+            WireLogLevel::Number(number) => Err(format!("unknown log level {}", number)),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum KvOp {
+    Put { key : String, value : Vec<u8> },
+    Delete(String),
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum HostReply {
+    Done,
+    Clipboard(Option<String>),
+    Data(Option<Vec<u8>>),
+    Entries(Vec<(String, Vec<u8>)>),
+}
+
+// implemented by pusz, every call is answered right away on the calling thread.
+pub trait Host : Send + Sync {
+    fn call(&self, call : HostCall) -> Result<HostReply, String>;
+}
+
+// stands in when there is no host, e.g. in plugin tests or inside a wasm module.
+struct Detached;
+
+impl Host for Detached {
+    fn call(&self, _call : HostCall) -> Result<HostReply, String> {
+        Err("no host to call".to_owned())
+    }
+}
+
+#[derive(Clone)]
+pub struct HostContext {
+    host : Arc<dyn Host>,
+}
+
+impl Default for HostContext {
+    fn default() -> Self {
+        Self::new(Arc::new(Detached))
+    }
+}
+
+impl fmt::Debug for HostContext {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HostContext")
+    }
+}
+
+fn unexpected(reply : HostReply) -> String {
+    format!("unexpected reply from host: {:?}", reply)
+}
+
+impl HostContext {
+    pub fn new(host : Arc<dyn Host>) -> Self {
+        Self {
+            host,
+        }
+    }
+
+    pub fn call(&self, call : HostCall) -> Result<HostReply, String> {
+        self.host.call(call)
+    }
+
+    fn done(&self, call : HostCall) -> Result<(), String> {
+        match self.call(call)? {
+            HostReply::Done => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn set_clipboard(&self, text : &str) -> Result<(), String> {
+        self.done(HostCall::SetClipboard(text.to_owned()))
+    }
+
+    pub fn clipboard(&self) -> Result<Option<String>, String> {
+        match self.call(HostCall::GetClipboard)? {
+            HostReply::Clipboard(text) => Ok(text),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn open_url(&self, url : &str) -> Result<(), String> {
+        self.done(HostCall::OpenUrl(url.to_owned()))
+    }
+
+    pub fn show_toast(&self, message : &str) -> Result<(), String> {
+        self.done(HostCall::ShowToast(message.to_owned()))
+    }
+
+    pub fn log(&self, level : LogLevel, message : &str) -> Result<(), String> {
+        self.done(HostCall::Log { level, message : message.to_owned() })
+    }
+
+    pub fn refresh_results(&self) -> Result<(), String> {
+        self.done(HostCall::RefreshResults)
+    }
+
+    pub fn read_data(&self, file : &str) -> Result<Option<Vec<u8>>, String> {
+        match self.call(HostCall::ReadData(file.to_owned()))? {
+            HostReply::Data(contents) => Ok(contents),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn write_data(&self, file : &str, contents : &[u8]) -> Result<(), String> {
+        self.done(HostCall::WriteData { file : file.to_owned(), contents : contents.to_vec() })
+    }
+
+    pub fn kv_get(&self, key : &str) -> Result<Option<Vec<u8>>, String> {
+        match self.call(HostCall::KvGet(key.to_owned()))? {
+            HostReply::Data(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn kv_scan(&self, prefix : &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+        match self.call(HostCall::KvScan(prefix.to_owned()))? {
+            HostReply::Entries(entries) => Ok(entries),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub fn kv_put(&self, key : &str, value : &[u8]) -> Result<(), String> {
+        self.kv_write(vec![KvOp::Put { key : key.to_owned(), value : value.to_vec() }])
+    }
+
+    pub fn kv_delete(&self, key : &str) -> Result<(), String> {
+        self.kv_write(vec![KvOp::Delete(key.to_owned())])
+    }
+
+    pub fn kv_write(&self, ops : Vec<KvOp>) -> Result<(), String> {
+        self.done(HostCall::KvWrite(ops))
+    }
+}
@@ -6,55 +6,113 @@ extern crate maplit;
 
 use std::any::Any;
 use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::fmt;
 
-#[derive(PartialEq, Clone, Debug)]
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+use serde::de::DeserializeOwned;
+
+pub mod ffi;
+pub mod host;
+pub mod manifest;
+pub mod wasm;
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct PuszRowIdentifier {
-    pub plugin_id : &'static str,
+    pub plugin_id : String,
 
     pub identifier :  String,
     pub data : Option<String>,
 }
 
 impl PuszRowIdentifier {
-    pub fn new(plugin_id : &'static str, identifier : String) -> Self {
+    pub fn new(plugin_id : &str, identifier : String) -> Self {
         Self {
-            plugin_id,
+            plugin_id : plugin_id.to_owned(),
             identifier,
             data : None,
         }
     }
 }
 
-#[derive(PartialEq, Clone, Debug)]
+// handed back to the plugin that made the row, `id` picks one of its actions and `payload` is whatever it needs to run it.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct CustomAction {
+    pub id : String,
+    #[serde(default)]
+    pub payload : Option<String>,
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub enum PuszAction {
     SetClipboard,
     OpenBrowserIfLink,
-    CustomAction,
+    CustomAction(CustomAction),
+    // sets the clipboard, hides pusz and pastes into the window that was focused before pusz showed up.
+    Paste,
 }
 
-#[derive(PartialEq, Eq, Clone, Debug, PartialOrd, Ord)]
+#[derive(PartialEq, Eq, Clone, Debug, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum SpecialKey {
     Return,
 }
 
-#[derive(PartialEq, Eq, Clone, Debug, PartialOrd, Ord)]
+// keys pressed while a row is selected, with whatever modifiers are held.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum KeyChord {
+    Return,
+    ShiftReturn,
+    CtrlReturn,
+    AltReturn,
+    Delete,
+    CtrlC,
+}
+
+impl KeyChord {
+    pub const ALL : [KeyChord; 6] = [KeyChord::Return, KeyChord::ShiftReturn, KeyChord::CtrlReturn, KeyChord::AltReturn, KeyChord::Delete, KeyChord::CtrlC];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            KeyChord::Return => "Return",
+            KeyChord::ShiftReturn => "Shift+Return",
+            KeyChord::CtrlReturn => "Ctrl+Return",
+            KeyChord::AltReturn => "Alt+Return",
+            KeyChord::Delete => "Delete",
+            KeyChord::CtrlC => "Ctrl+C",
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum PuszEvent {
     Click,
     DoubleClick,
+    // what plugins built before KeyPress bind plain Return to, same as KeyPress(KeyChord::Return).
     SpecialKeyPress(SpecialKey),
+    KeyPress(KeyChord),
     //CompountAction(Vec<PuszEvent>) ?
 }
 
-#[derive(PartialEq, Clone, Debug)]
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct PuszEntry {
     // TODO: consider having multiple actions?
+    #[serde(with = "action_pairs")]
     pub actions : BTreeMap<PuszEvent, PuszAction>,
     pub label : String,
     pub content : String,
 }
 
+impl PuszEntry {
+    pub fn action(&self, event : &PuszEvent) -> Option<&PuszAction> {
+        match event {
+            PuszEvent::KeyPress(KeyChord::Return) => self.actions.get(event).or_else(|| self.actions.get(&PuszEvent::SpecialKeyPress(SpecialKey::Return))),
+            _ => self.actions.get(event),
+        }
+    }
+}
+
 //already had panics unexpected due to builder, eh purge it out?
-#[derive(PartialEq, Clone, Debug, Builder)]
+#[derive(PartialEq, Clone, Debug, Builder, Serialize, Deserialize)]
 pub struct PuszRow {
     pub main_entry : PuszEntry,
 
@@ -63,6 +121,11 @@ pub struct PuszRow {
 
     pub identifier : PuszRowIdentifier,
     pub is_removable : bool,
+
+    // how well the row matches the query, on the scale of fuzzy_matcher scores. None lets pusz match the label itself.
+    #[builder(default)]
+    #[serde(default)]
+    pub score : Option<i64>,
 }
 
 impl PuszRowBuilder {
@@ -77,40 +140,70 @@ impl PuszRowBuilder {
             identifier : Some(identifier),
 
             is_removable : Some(false),
-
+            score : None,
         }
     }
 }
 
+// json keys have to be strings and PuszEvent::SpecialKeyPress is not one, so actions travel as a list of pairs.
+mod action_pairs {
+    use super::*;
 
-#[derive(PartialEq, Debug)]
+    pub fn serialize<S : Serializer>(actions : &BTreeMap<PuszEvent, PuszAction>, serializer : S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(actions.iter())
+    }
+
+    pub fn deserialize<'de, D : Deserializer<'de>>(deserializer : D) -> Result<BTreeMap<PuszEvent, PuszAction>, D::Error> {
+        Vec::<(PuszEvent, PuszAction)>::deserialize(deserializer).map(|pairs| pairs.into_iter().collect())
+    }
+}
+
+// answering an action: None keeps the window as it is, Ok replaces the results and Error is shown on top of them.
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
 pub enum PluginResult {
     None,
     Error(String),
     Ok(Vec<PuszRow>),
+    // only for actions, the job is done and pusz hides.
+    Close,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct PluginSettings {
     pub requies_explicit_query : bool,
     pub interested_in_clipboard : bool,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 // should this be renamed to voluntary/subscribed event?
 pub enum PluginEvent {
     Clipboard(String), // images'n stuff in the future
 }
 
-pub trait Plugin : ::std::fmt::Debug {
+// where a streaming query puts rows as the plugin finds them.
+pub trait ResultSink {
+    // shown right away, merged with everything found so far.
+    fn push(&mut self, rows : Vec<PuszRow>);
+    // the user typed something else in the meantime, whatever the plugin still finds wont be shown.
+    fn cancelled(&self) -> bool;
+}
+
+// plugins answer queries on their own thread.
+pub trait Plugin : ::std::fmt::Debug + Send {
     fn query(&mut self, query : &str) -> PluginResult;
+
+    // for searches that take a while: batches go to `sink` as they are found and returning marks the query
+    // complete, with the last rows or the error it ended with.
+    fn query_streaming(&mut self, query : &str, _sink : &mut dyn ResultSink) -> PluginResult {
+        self.query(query)
+    }
     fn query_return(&mut self, query: &str) -> PluginResult {
         self.query(query)
     }
 
-    //invoked on pressing return
-    fn action_request(&mut self, query : &str) -> PluginResult {
-        self.query(query)
+    // a PuszAction::CustomAction of one of this plugin's rows was triggered, `row` is that row's identifier.
+    fn action_request(&mut self, _row : &PuszRowIdentifier, _action : &CustomAction) -> PluginResult {
+        PluginResult::None
     }
     fn name(&self) -> &'static str;
 
@@ -126,26 +219,150 @@ pub trait Plugin : ::std::fmt::Debug {
     }
 
     fn on_subscribed_event(&mut self, _event : &PluginEvent) {
-        ()
+    }
+
+    // hot reload: whatever is returned here is handed to restore_state of the freshly built instance.
+    fn save_state(&mut self) -> Option<String> {
+        None
+    }
+
+    fn restore_state(&mut self, _state : &str) {
     }
 }
 
-pub const COMMON_INTERFACE_VERSION : &'static str = "1";
-pub type LoadFn = extern "C" fn(&str) -> Result<Box<dyn Plugin>, String>;
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+pub enum LoadError {
+    UnsupportedAbi(u32),
+    UnsupportedInterface(String),
+    // plugin section in the host config does not match what the plugin expects.
+    InvalidConfig(String),
+    Failed(String),
+}
 
-#[no_mangle]
-extern "C" fn load_example(plugin_interface_version : &str) -> Result<Box<dyn Plugin>, String> {
-    if plugin_interface_version != COMMON_INTERFACE_VERSION {
-        Err("unsupported common interface, update your plugin!".to_string())
+impl fmt::Display for LoadError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::UnsupportedAbi(version) => write!(f, "plugin built for abi version {}, pusz supports {}", version, ffi::ABI_VERSION),
+            LoadError::UnsupportedInterface(version) => write!(f, "plugin built for interface version {}, pusz supports {}", version, COMMON_INTERFACE_VERSION),
+            LoadError::InvalidConfig(reason) => write!(f, "invalid plugin config: {}", reason),
+            LoadError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// what the host hands over to the plugin at load time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginContext {
+    // [plugins.settings.<name>] tables from the host config.
+    sections : BTreeMap<String, toml::Value>,
+    data_root : PathBuf,
+    // handed over next to the json, see ffi::FfiHost.
+    #[serde(skip)]
+    host : host::HostContext,
+}
+
+impl PluginContext {
+    pub fn new(sections : BTreeMap<String, toml::Value>, data_root : PathBuf) -> Self {
+        Self {
+            sections,
+            data_root,
+            host : host::HostContext::default(),
+        }
+    }
+
+    pub fn with_host(mut self, host : host::HostContext) -> Self {
+        self.host = host;
+        self
+    }
+
+    // services pusz offers the plugin, fine to keep around.
+    pub fn host(&self) -> &host::HostContext {
+        &self.host
+    }
+
+    // plugin config from [plugins.settings.<plugin_name>], defaults when the section is missing.
+    pub fn config<T : DeserializeOwned + Default>(&self, plugin_name : &str) -> Result<T, LoadError> {
+        match self.sections.get(plugin_name) {
+            Some(section) => section.clone().try_into().map_err(|e| LoadError::InvalidConfig(format!("[plugins.settings.{}] {}", plugin_name, e))),
+            None => Ok(T::default()),
+        }
+    }
+
+    // directory owned by the plugin for whatever it needs to persist, created on first use.
+    pub fn data_dir(&self, plugin_name : &str) -> Result<PathBuf, LoadError> {
+        let dir = self.data_root.join(plugin_name);
+        std::fs::create_dir_all(&dir).map_err(|e| LoadError::Failed(format!("couldnt create data dir {}: {}", dir.display(), e)))?;
+        Ok(dir)
+    }
+}
+
+// the one version rule, the same for every kind of plugin: it loads when the range it asks for matches this,
+// see manifest::supports. the manifest `interface` states the range, a library built against 1.x also asks for ^1.x.
+// additions bump the minor, new host calls, enum variants and payload fields with #[serde(default)], which
+// older plugins never send or get. the major only goes up for changes that break them.
+// ffi::ABI_VERSION and wasm::ABI_VERSION are not part of it, they only say whether the declaration or the
+// exports can be read at all and change with their layout alone.
+pub const COMMON_INTERFACE_VERSION : &str = "1.7.0";
+
+// readable text out of a catch_unwind payload.
+pub fn panic_message(payload : &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
     } else {
-        panic!("this is example, cant load this.");
+        "unknown panic".to_owned()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Default, PartialEq, Debug)]
+    #[serde(default)]
+    struct ExampleConfig {
+        limit : u32,
+    }
+
+    fn context(section : &str) -> PluginContext {
+        let sections = btreemap!("example".to_owned() => toml::from_str(section).unwrap());
+        PluginContext::new(sections, std::env::temp_dir().join(format!("pusz_plugin_interface_tests_{}", std::process::id())))
+    }
+
+    #[test]
+    fn plugin_gets_its_section() {
+        assert_eq!(Ok(ExampleConfig { limit : 5 }), context("limit = 5").config("example"));
+        assert_eq!(Ok(ExampleConfig::default()), context("limit = 5").config::<ExampleConfig>("other"));
+    }
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn schema_errors_name_the_section() {
+        let err = context("limit = 'many'").config::<ExampleConfig>("example").unwrap_err();
+        match err {
+            LoadError::InvalidConfig(reason) => assert!(reason.starts_with("[plugins.settings.example]"), "{}", reason),
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn data_dir_is_created() {
+        let dir = context("").data_dir("example").unwrap();
+        assert!(dir.is_dir());
+        assert!(dir.ends_with("example"));
+    }
+
+    #[test]
+    fn old_return_bindings_still_answer_return() {
+        let mut entry = PuszRowBuilder::new("a".to_owned(), PuszRowIdentifier::new("example", "a".to_owned())).build().unwrap().main_entry;
+        entry.actions.insert(PuszEvent::SpecialKeyPress(SpecialKey::Return), PuszAction::OpenBrowserIfLink);
+        entry.actions.insert(PuszEvent::KeyPress(KeyChord::CtrlC), PuszAction::SetClipboard);
+
+        assert_eq!(Some(&PuszAction::OpenBrowserIfLink), entry.action(&PuszEvent::KeyPress(KeyChord::Return)));
+        assert_eq!(Some(&PuszAction::SetClipboard), entry.action(&PuszEvent::KeyPress(KeyChord::CtrlC)));
+        assert_eq!(None, entry.action(&PuszEvent::KeyPress(KeyChord::ShiftReturn)));
+
+        entry.actions.insert(PuszEvent::KeyPress(KeyChord::Return), PuszAction::SetClipboard);
+        assert_eq!(Some(&PuszAction::SetClipboard), entry.action(&PuszEvent::KeyPress(KeyChord::Return)));
     }
 }
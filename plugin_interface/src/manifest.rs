@@ -0,0 +1,197 @@
+// metadata a plugin ships next to its library as <library>.pusz.toml, pusz reads it before loading the library.
+//
+// name = "calc"
+// version = "0.1.0"
+// author = "fulara"
+// description = "evaluates math expressions"
+// interface = "^1.0"
+// prefixes = ["calc", "="]
+// capabilities = ["clipboard_events"]
+
+use std::path::{Path, PathBuf};
+use std::fs;
+
+use serde::Deserialize;
+use semver::{Version, VersionReq};
+
+use crate::COMMON_INTERFACE_VERSION;
+
+pub const MANIFEST_SUFFIX : &str = ".pusz.toml";
+
+#[derive(Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    ClipboardEvents,
+    // wasm plugins: key value storage kept by pusz in the plugin data dir.
+    Storage,
+    // wasm plugins: reading and setting the clipboard.
+    Clipboard,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PluginManifest {
+    pub name : String,
+    pub version : Version,
+    #[serde(default)]
+    pub author : String,
+    #[serde(default)]
+    pub description : String,
+    // COMMON_INTERFACE_VERSION range the plugin works with.
+    pub interface : VersionReq,
+    // /<prefix> routes the query to this plugin, its name always does.
+    #[serde(default)]
+    pub prefixes : Vec<String>,
+    #[serde(default)]
+    pub capabilities : Vec<Capability>,
+}
+
+pub fn interface_version() -> Version {
+    Version::parse(COMMON_INTERFACE_VERSION).expect("interface version is semver")
+}
+
+// whether pusz provides an interface in `required`, see COMMON_INTERFACE_VERSION.
+pub fn supports(required : &VersionReq) -> Result<(), String> {
+    let host = interface_version();
+    if required.matches(&host) {
+        Ok(())
+    } else {
+        Err(format!("needs interface {}, pusz provides {}", required, host))
+    }
+}
+
+impl PluginManifest {
+    pub fn parse(text : &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+
+    pub fn load(path : &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("couldnt read manifest {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("invalid manifest {}: {}", path.display(), e))
+    }
+
+    // libcalc_plugin.so, calc_plugin.dll -> calc_plugin.pusz.toml in the same directory.
+    pub fn path_for(library : &Path) -> PathBuf {
+        let stem = library.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+        let stem = stem.strip_prefix(std::env::consts::DLL_PREFIX).unwrap_or(stem);
+        library.with_file_name(format!("{}{}", stem, MANIFEST_SUFFIX))
+    }
+
+    // next to the library, or for libraries straight out of a cargo target dir where `install` left it:
+    // <profile>/build/<package>-<hash>/out, the newest one wins when stale builds are still around.
+    pub fn find_for(library : &Path) -> PathBuf {
+        let installed = Self::path_for(library);
+        if installed.exists() {
+            return installed;
+        }
+
+        let file_name = installed.file_name().unwrap_or_default().to_owned();
+        let package = file_name.to_string_lossy().trim_end_matches(MANIFEST_SUFFIX).to_owned();
+        let build_dir = library.with_file_name("build");
+        let entries = match fs::read_dir(&build_dir) {
+            Ok(entries) => entries,
+            Err(_) => return installed,
+        };
+
+        entries.filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().strip_prefix(&package).is_some_and(|hash| hash.starts_with('-')))
+            .map(|entry| entry.path().join("out").join(&file_name))
+            .filter_map(|path| fs::metadata(&path).and_then(|meta| meta.modified()).ok().map(|modified| (modified, path)))
+            .max()
+            .map(|(_, path)| path)
+            .unwrap_or(installed)
+    }
+
+    pub fn check_interface(&self) -> Result<(), String> {
+        supports(&self.interface)
+    }
+
+    pub fn has_capability(&self, capability : Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    pub fn handles_command(&self, command : &str) -> bool {
+        self.name == command || self.prefixes.iter().any(|prefix| prefix == command)
+    }
+}
+
+// for plugin build scripts: checks `manifest` and keeps a copy in OUT_DIR, where `find_for` looks
+// for plugins straight out of target/. installed plugins need it copied next to the library.
+pub fn install(manifest : &Path) {
+    println!("cargo:rerun-if-changed={}", manifest.display());
+
+    if let Err(err) = PluginManifest::load(manifest) {
+        panic!("{}", err);
+    }
+
+    let library = std::env::var("CARGO_PKG_NAME").expect("only callable from build scripts").replace('-', "_");
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").expect("only callable from build scripts"));
+    fs::copy(manifest, out_dir.join(format!("{}{}", library, MANIFEST_SUFFIX))).expect("couldnt install manifest");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(interface : &str) -> PluginManifest {
+        PluginManifest::parse(&format!(r#"
+            name = "calc"
+            version = "0.1.0"
+            interface = "{}"
+            prefixes = ["="]
+            capabilities = ["clipboard_events"]
+        "#, interface)).unwrap()
+    }
+
+    #[test]
+    fn parses_manifest() {
+        let manifest = manifest("^1.0");
+
+        assert_eq!("calc", manifest.name);
+        assert_eq!(Version::new(0, 1, 0), manifest.version);
+        assert_eq!("", manifest.description);
+        assert!(manifest.has_capability(Capability::ClipboardEvents));
+        assert!(manifest.handles_command("calc"));
+        assert!(manifest.handles_command("="));
+        assert!(!manifest.handles_command("clip"));
+    }
+
+    #[test]
+    fn interface_range_is_checked() {
+        assert_eq!(Ok(()), manifest("^1.0").check_interface());
+        assert_eq!(Err(format!("needs interface ^2.0, pusz provides {}", COMMON_INTERFACE_VERSION)), manifest("^2.0").check_interface());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let err = PluginManifest::parse("name = 'calc'\nversion = '0.1.0'\ninterface = '^1'\ncapabilites = []").unwrap_err();
+        assert!(err.contains("capabilites"), "{}", err);
+    }
+
+    #[test]
+    fn manifest_sits_next_to_library() {
+        let library = Path::new("plugins").join(format!("{}calc_plugin.{}", std::env::consts::DLL_PREFIX, std::env::consts::DLL_EXTENSION));
+        assert_eq!(Path::new("plugins").join("calc_plugin.pusz.toml"), PluginManifest::path_for(&library));
+    }
+
+    #[test]
+    fn manifest_of_cargo_build_is_found_in_out_dir() {
+        let profile = std::env::temp_dir().join(format!("pusz_manifest_tests_{}", std::process::id()));
+        let library = profile.join(format!("{}calc_plugin.{}", std::env::consts::DLL_PREFIX, std::env::consts::DLL_EXTENSION));
+        let out_dir = profile.join("build").join("calc_plugin-0123abcd").join("out");
+        fs::create_dir_all(&out_dir).unwrap();
+        fs::create_dir_all(profile.join("build").join("calc_plugin_extra-4567").join("out")).unwrap();
+        fs::write(profile.join("build").join("calc_plugin_extra-4567").join("out").join("calc_plugin.pusz.toml"), "").unwrap();
+
+        let other = profile.join("other.so");
+        assert_eq!(PluginManifest::path_for(&other), PluginManifest::find_for(&other));
+
+        fs::write(out_dir.join("calc_plugin.pusz.toml"), "").unwrap();
+        assert_eq!(out_dir.join("calc_plugin.pusz.toml"), PluginManifest::find_for(&library));
+
+        fs::write(PluginManifest::path_for(&library), "").unwrap();
+        assert_eq!(PluginManifest::path_for(&library), PluginManifest::find_for(&library));
+
+        fs::remove_dir_all(&profile).unwrap();
+    }
+}
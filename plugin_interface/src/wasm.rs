@@ -0,0 +1,257 @@
+// plugins compiled to wasm32-unknown-unknown, run sandboxed by pusz instead of loaded as native code.
+//
+// a module exports `memory` and
+//   pusz_abi_version() -> i32
+//   pusz_alloc(len) -> ptr                   buffer the host writes call input into
+//   pusz_free(ptr, len)                      releases a buffer returned by pusz_call
+//   pusz_call(method, ptr, len) -> packed    takes ownership of the input, returns a json buffer
+// and may import from "pusz" whatever its manifest grants, see `host`.
+//
+// plugin side: `declare_wasm_plugin!(load)` with the same load function a native plugin has.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{CustomAction, Plugin, PluginContext, PluginEvent, PuszRowIdentifier, LoadError};
+
+// bumped whenever existing exports or imports change, new imports and method payloads are versioned by COMMON_INTERFACE_VERSION.
+pub const ABI_VERSION : i32 = 2;
+
+pub const EXTENSION : &str = "wasm";
+
+// first argument of pusz_call, input and output are json:
+//   LOAD: PluginContext -> Result<(name, PluginSettings), LoadError>
+//   QUERY, QUERY_RETURN: query -> PluginResult
+//   ACTION_REQUEST: (PuszRowIdentifier, CustomAction) -> PluginResult
+//   ON_SUBSCRIBED_EVENT: PluginEvent -> nothing
+//   SAVE_STATE: nothing -> Option<String>
+//   RESTORE_STATE: state -> nothing
+pub mod method {
+    pub const LOAD : i32 = 0;
+    pub const QUERY : i32 = 1;
+    pub const QUERY_RETURN : i32 = 2;
+    pub const ACTION_REQUEST : i32 = 3;
+    pub const ON_SUBSCRIBED_EVENT : i32 = 4;
+    pub const SAVE_STATE : i32 = 5;
+    pub const RESTORE_STATE : i32 = 6;
+}
+
+// (ptr, len) of a buffer in module memory squeezed into the single i64 a wasm function returns.
+pub fn pack(ptr : u32, len : u32) -> i64 {
+    ((ptr as i64) << 32) | len as i64
+}
+
+pub fn unpack(packed : i64) -> (u32, u32) {
+    ((packed >> 32) as u32, packed as u32)
+}
+
+pub type LoadFn = fn(&PluginContext) -> Result<Box<dyn Plugin>, LoadError>;
+
+fn to_json<T : Serialize>(value : &T) -> Vec<u8> {
+    serde_json::to_vec(value).expect("plugin types serialize")
+}
+
+fn from_json<T : DeserializeOwned>(input : &[u8]) -> Result<T, String> {
+    serde_json::from_slice(input).map_err(|e| format!("unreadable call input: {}", e))
+}
+
+// the plugin instance inside the module and the method dispatch, kept apart from the pointer juggling
+// so it works, and is tested, on any target.
+#[derive(Default)]
+pub struct Guest {
+    plugin : Option<Box<dyn Plugin>>,
+}
+
+impl Guest {
+    pub fn call(&mut self, load : LoadFn, method : i32, input : &[u8]) -> Vec<u8> {
+        if method == method::LOAD {
+            let loaded = from_json::<PluginContext>(input).map_err(LoadError::Failed).and_then(|context| load(&context)).map(|plugin| {
+                let described = (plugin.name(), plugin.settings());
+                self.plugin = Some(plugin);
+                described
+            });
+            return to_json(&loaded);
+        }
+
+        // the host never calls anything else before a successful LOAD.
+        let plugin = self.plugin.as_mut().expect("plugin is loaded");
+        match method {
+            method::QUERY => from_json::<String>(input).map(|query| to_json(&plugin.query(&query))),
+            method::QUERY_RETURN => from_json::<String>(input).map(|query| to_json(&plugin.query_return(&query))),
+            method::ACTION_REQUEST => from_json::<(PuszRowIdentifier, CustomAction)>(input).map(|(row, action)| to_json(&plugin.action_request(&row, &action))),
+            method::ON_SUBSCRIBED_EVENT => from_json::<PluginEvent>(input).map(|event| {
+                plugin.on_subscribed_event(&event);
+                vec![]
+            }),
+            method::SAVE_STATE => Ok(to_json(&plugin.save_state())),
+            method::RESTORE_STATE => from_json::<String>(input).map(|state| {
+                plugin.restore_state(&state);
+                vec![]
+            }),
+            _ => Err(format!("unknown method {}", method)),
+        }.unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+// buffers handed across, boxed slices so length and capacity are the same thing.
+#[cfg(target_arch = "wasm32")]
+#[doc(hidden)]
+pub mod buffers {
+    pub fn alloc(len : i32) -> i32 {
+        Box::into_raw(vec![0u8; len as usize].into_boxed_slice()) as *mut u8 as i32
+    }
+
+    pub unsafe fn take(ptr : i32, len : i32) -> Vec<u8> {
+        Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr as *mut u8, len as usize)).into_vec()
+    }
+
+    pub unsafe fn free(ptr : i32, len : i32) {
+        drop(take(ptr, len))
+    }
+
+    pub fn leak(bytes : Vec<u8>) -> i64 {
+        let len = bytes.len() as u32;
+        super::pack(Box::into_raw(bytes.into_boxed_slice()) as *mut u8 as u32, len)
+    }
+}
+
+// services the host provides, each one is only linked when the plugin manifest lists its capability.
+// log is always there, storage_get and storage_set need `storage`, clipboard_get and clipboard_set `clipboard`.
+#[cfg(target_arch = "wasm32")]
+pub mod host {
+    #[link(wasm_import_module = "pusz")]
+    extern "C" {
+        #[link_name = "log"]
+        fn pusz_log(level : i32, ptr : i32, len : i32);
+        #[link_name = "storage_get"]
+        fn pusz_storage_get(key_ptr : i32, key_len : i32) -> i64;
+        #[link_name = "storage_set"]
+        fn pusz_storage_set(key_ptr : i32, key_len : i32, value_ptr : i32, value_len : i32);
+        #[link_name = "clipboard_get"]
+        fn pusz_clipboard_get() -> i64;
+        #[link_name = "clipboard_set"]
+        fn pusz_clipboard_set(ptr : i32, len : i32);
+    }
+
+    // the host wrote the value into a buffer from pusz_alloc, negative for none.
+    fn take_string(packed : i64) -> Option<String> {
+        if packed < 0 {
+            return None;
+        }
+
+        let (ptr, len) = super::unpack(packed);
+        String::from_utf8(unsafe { super::buffers::take(ptr as i32, len as i32) }).ok()
+    }
+
+    // 1 error .. 5 trace, like log::Level.
+    pub fn log(level : i32, message : &str) {
+        unsafe { pusz_log(level, message.as_ptr() as i32, message.len() as i32) }
+    }
+
+    // storage: the plugin's key value store, the same one native plugins reach with kv_get and kv_put.
+    pub fn storage_get(key : &str) -> Option<String> {
+        take_string(unsafe { pusz_storage_get(key.as_ptr() as i32, key.len() as i32) })
+    }
+
+    pub fn storage_set(key : &str, value : &str) {
+        unsafe { pusz_storage_set(key.as_ptr() as i32, key.len() as i32, value.as_ptr() as i32, value.len() as i32) }
+    }
+
+    // clipboard capability.
+    pub fn clipboard_get() -> Option<String> {
+        take_string(unsafe { pusz_clipboard_get() })
+    }
+
+    pub fn clipboard_set(text : &str) {
+        unsafe { pusz_clipboard_set(text.as_ptr() as i32, text.len() as i32) }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[macro_export]
+macro_rules! declare_wasm_plugin {
+    ($load:path) => {
+        thread_local! {
+            static PUSZ_GUEST : std::cell::RefCell<$crate::wasm::Guest> = std::cell::RefCell::new($crate::wasm::Guest::default());
+        }
+
+        #[no_mangle]
+        pub extern "C" fn pusz_abi_version() -> i32 {
+            $crate::wasm::ABI_VERSION
+        }
+
+        #[no_mangle]
+        pub extern "C" fn pusz_alloc(len : i32) -> i32 {
+            $crate::wasm::buffers::alloc(len)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn pusz_free(ptr : i32, len : i32) {
+            $crate::wasm::buffers::free(ptr, len)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn pusz_call(method : i32, ptr : i32, len : i32) -> i64 {
+            let input = $crate::wasm::buffers::take(ptr, len);
+            let output = PUSZ_GUEST.with(|guest| guest.borrow_mut().call($load, method, &input));
+            $crate::wasm::buffers::leak(output)
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PluginResult, PluginSettings};
+
+    #[derive(Debug, Default)]
+    struct Counter {
+        count : u32,
+    }
+
+    impl Plugin for Counter {
+        fn query(&mut self, query : &str) -> PluginResult {
+            self.count += 1;
+            PluginResult::Error(format!("{} {}", query, self.count))
+        }
+
+        fn name(&self) -> &'static str {
+            "counter"
+        }
+
+        fn save_state(&mut self) -> Option<String> {
+            Some(self.count.to_string())
+        }
+
+        fn restore_state(&mut self, state : &str) {
+            self.count = state.parse().unwrap();
+        }
+    }
+
+    fn load(_context : &PluginContext) -> Result<Box<dyn Plugin>, LoadError> {
+        Ok(Box::new(Counter::default()))
+    }
+
+    fn call<T : DeserializeOwned>(guest : &mut Guest, method : i32, input : &impl Serialize) -> T {
+        serde_json::from_slice(&guest.call(load, method, &to_json(input))).unwrap()
+    }
+
+    #[test]
+    fn methods_round_trip_as_json() {
+        let mut guest = Guest::default();
+
+        let context = PluginContext::new(Default::default(), "data".into());
+        let loaded : Result<(String, PluginSettings), LoadError> = call(&mut guest, method::LOAD, &context);
+        assert_eq!("counter", loaded.unwrap().0);
+
+        assert!(guest.call(load, method::RESTORE_STATE, &to_json(&"41")).is_empty());
+        assert_eq!(PluginResult::Error("x 42".to_owned()), call(&mut guest, method::QUERY, &"x"));
+        assert_eq!(Some("42".to_owned()), call::<Option<String>>(&mut guest, method::SAVE_STATE, &()));
+    }
+
+    #[test]
+    fn packing_keeps_both_halves() {
+        assert_eq!((0xffff_fff0, 7), unpack(pack(0xffff_fff0, 7)));
+        assert_eq!((0, 0), unpack(pack(0, 0)));
+    }
+}
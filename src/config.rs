@@ -0,0 +1,461 @@
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+use serde::de::Visitor;
+
+use crate::platform::{Hotkey, Key, Modifiers};
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct UiConfig {
+    pub width : i32,
+    pub height : i32,
+    pub max_results_height : i32,
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        Self {
+            width : 840,
+            height : 480,
+            max_results_height : 400,
+        }
+    }
+}
+
+// extra hotkey that opens pusz with the input already filled, e.g. "/clip " to go straight to a plugin.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct Binding {
+    pub hotkey : Hotkey,
+    pub query : String,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct HotkeysConfig {
+    pub main : Hotkey,
+    pub bindings : Vec<Binding>,
+}
+
+impl Default for HotkeysConfig {
+    fn default() -> Self {
+        Self {
+            main : Hotkey::new(Key::F1, Modifiers::default()),
+            bindings : vec![],
+        }
+    }
+}
+
+// plugin running as a child process, talking json-rpc over its stdin/stdout.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct ProcessPluginConfig {
+    pub name : String,
+    // program followed by its arguments.
+    pub command : Vec<String>,
+    // a call taking longer disables the plugin.
+    #[serde(default = "default_process_timeout_ms")]
+    pub timeout_ms : u64,
+}
+
+fn default_process_timeout_ms() -> u64 {
+    2000
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct PluginsConfig {
+    // earlier paths win when two libraries provide a plugin with the same name.
+    pub search_paths : Vec<PathBuf>,
+    // every plugin gets its own directory in here.
+    pub data_dir : PathBuf,
+    // plugin names, empty means all of them.
+    pub enabled : Vec<String>,
+    pub disabled : Vec<String>,
+    // reload plugins when their library in a search path is rebuilt.
+    pub hot_reload : bool,
+    pub processes : Vec<ProcessPluginConfig>,
+    // answers arriving later than this are dropped, per plugin in query_timeouts_ms.
+    pub query_timeout_ms : u64,
+    pub query_timeouts_ms : BTreeMap<String, u64>,
+    // added to the score of every row of that plugin, a label matching the query scores around 100.
+    pub priorities : BTreeMap<String, i64>,
+
+    // handed to the plugin of that name, e.g. [plugins.settings.clip].
+    pub settings : BTreeMap<String, toml::Value>,
+}
+
+impl Default for PluginsConfig {
+    fn default() -> Self {
+        Self {
+            search_paths : default_search_paths(),
+            data_dir : dirs::data_dir().map_or_else(|| PathBuf::from("data"), |dir| dir.join("pusz").join("data")),
+            enabled : vec![],
+            disabled : vec![],
+            hot_reload : cfg!(debug_assertions),
+            processes : vec![],
+            query_timeout_ms : 1000,
+            query_timeouts_ms : BTreeMap::new(),
+            priorities : BTreeMap::new(),
+            settings : BTreeMap::new(),
+        }
+    }
+}
+
+impl PluginsConfig {
+    pub fn is_enabled(&self, plugin : &str) -> bool {
+        (self.enabled.is_empty() || self.enabled.iter().any(|name| name == plugin)) && !self.disabled.iter().any(|name| name == plugin)
+    }
+
+    pub fn query_timeout(&self, plugin : &str) -> Duration {
+        Duration::from_millis(self.query_timeouts_ms.get(plugin).copied().unwrap_or(self.query_timeout_ms))
+    }
+}
+
+fn default_search_paths() -> Vec<PathBuf> {
+    let mut paths = vec![];
+
+    // plugins built next to pusz during development.
+    if cfg!(debug_assertions) {
+        paths.push(PathBuf::from("target/debug"));
+        paths.push(PathBuf::from("target/release"));
+    }
+
+    paths.push(PathBuf::from("plugins"));
+
+    if let Some(dir) = dirs::data_dir() {
+        paths.push(dir.join("pusz").join("plugins"));
+    }
+
+    if cfg!(unix) {
+        paths.push(PathBuf::from("/usr/lib/pusz/plugins"));
+    }
+
+    paths
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub file : PathBuf,
+    #[serde(deserialize_with = "deserialize_level")]
+    pub level : log::LevelFilter,
+    #[serde(deserialize_with = "deserialize_level")]
+    pub terminal_level : log::LevelFilter,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            file : PathBuf::from("pusz.log"),
+            level : log::LevelFilter::Info,
+            terminal_level : log::LevelFilter::Warn,
+        }
+    }
+}
+
+// what was picked and when, used to rank rows picked often and lately first.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct UsageConfig {
+    pub file : PathBuf,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            file : dirs::data_dir().map_or_else(|| PathBuf::from("usage.json"), |dir| dir.join("pusz").join("usage.json")),
+        }
+    }
+}
+
+// the string is parsed inside the visitor, that way toml points an error at the value rather than at its table.
+pub fn deserialize_parsed<'de, D, T>(deserializer : D, expecting : &'static str, parse : fn(&str) -> Result<T, String>) -> Result<T, D::Error>
+    where D : Deserializer<'de> {
+    struct Parsed<T> {
+        expecting : &'static str,
+        parse : fn(&str) -> Result<T, String>,
+    }
+
+    impl<'de, T> Visitor<'de> for Parsed<T> {
+        type Value = T;
+
+        fn expecting(&self, f : &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(self.expecting)
+        }
+
+        fn visit_str<E : serde::de::Error>(self, text : &str) -> Result<T, E> {
+            (self.parse)(text).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_str(Parsed { expecting, parse })
+}
+
+fn deserialize_level<'de, D : Deserializer<'de>>(deserializer : D) -> Result<log::LevelFilter, D::Error> {
+    deserialize_parsed(deserializer, "a log level", |text| {
+        text.parse().map_err(|_| format!("unknown log level '{}', expected one of: off, error, warn, info, debug, trace", text))
+    })
+}
+
+// text matching `pattern` gets an extra row linking to `url`, {} in url is replaced with the first capture.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct SpecialEntry {
+    pub label : String,
+    pub pattern : String,
+    pub url : String,
+}
+
+impl SpecialEntry {
+    fn snow(pattern : &str, table : &str) -> Self {
+        Self {
+            label : "snow link".to_owned(),
+            pattern : pattern.to_owned(),
+            url : format!("https://ig.service-now.com/{}.do?sysparm_query=number={{}}", table),
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub ui : UiConfig,
+    pub hotkeys : HotkeysConfig,
+    pub plugins : PluginsConfig,
+    pub logging : LoggingConfig,
+    pub usage : UsageConfig,
+    pub special_entries : Vec<SpecialEntry>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ui : UiConfig::default(),
+            hotkeys : HotkeysConfig::default(),
+            plugins : PluginsConfig::default(),
+            logging : LoggingConfig::default(),
+            usage : UsageConfig::default(),
+            special_entries : vec![
+                SpecialEntry::snow(r"(INC\d{4,})", "incident"),
+                SpecialEntry::snow(r"(RITM\d{4,})", "sc_req_item"),
+                SpecialEntry::snow(r"(CHG\d{4,})", "change_request"),
+                SpecialEntry::snow(r"(PRB\d{4,})", "problem"),
+                SpecialEntry::snow(r"(PRBTASK\d{4,})", "problem_task"),
+            ],
+        }
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct ConfigError {
+    pub path : Option<PathBuf>,
+    pub message : String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "invalid config {}: {}", path.display(), self.message),
+            None => write!(f, "invalid config: {}", self.message),
+        }
+    }
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("pusz").join("config.toml"))
+}
+
+// config plus the keys that were not recognized, reported once logging is up.
+pub struct LoadedConfig {
+    pub config : Config,
+    pub unknown_keys : Vec<String>,
+}
+
+impl Config {
+    pub fn parse(text : &str) -> Result<LoadedConfig, String> {
+        let mut unknown_keys = vec![];
+        let config : Config = serde_ignored::deserialize(&mut toml::Deserializer::new(text), |path| {
+            unknown_keys.push(path.to_string());
+        }).map_err(|e| e.to_string())?;
+
+        config.validate()?;
+
+        Ok(LoadedConfig {
+            config,
+            unknown_keys,
+        })
+    }
+
+    // missing file means defaults.
+    pub fn load() -> Result<LoadedConfig, ConfigError> {
+        match config_path() {
+            Some(path) => Self::load_from(&path),
+            None => Ok(LoadedConfig { config : Self::default(), unknown_keys : vec![] }),
+        }
+    }
+
+    pub fn load_from(path : &Path) -> Result<LoadedConfig, ConfigError> {
+        let error = |message : String| ConfigError { path : Some(path.to_owned()), message };
+
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).map_err(error),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(LoadedConfig { config : Self::default(), unknown_keys : vec![] }),
+            Err(err) => Err(error(err.to_string())),
+        }
+    }
+
+    pub fn plugin_section(&self, plugin : &str) -> Option<&toml::Value> {
+        self.plugins.settings.get(plugin)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (i, entry) in self.special_entries.iter().enumerate() {
+            let regex = regex::Regex::new(&entry.pattern).map_err(|e| format!("special_entries[{}].pattern: {}", i, e))?;
+            if regex.captures_len() < 2 {
+                return Err(format!("special_entries[{}].pattern: '{}' needs a capture group", i, entry.pattern));
+            }
+        }
+
+        for (i, process) in self.plugins.processes.iter().enumerate() {
+            if process.command.is_empty() {
+                return Err(format!("plugins.processes[{}]: command for {} is empty", i, process.name));
+            }
+        }
+
+        if self.ui.width <= 0 || self.ui.height <= 0 {
+            return Err(format!("ui: window size {}x{} is not positive", self.ui.width, self.ui.height));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text : &str) -> Config {
+        Config::parse(text).unwrap().config
+    }
+
+    #[test]
+    fn empty_file_is_default() {
+        assert_eq!(Config::default(), parse(""));
+    }
+
+    #[test]
+    fn full_config() {
+        let config = parse(r#"
+            [ui]
+            width = 1024
+            height = 600
+
+            [hotkeys]
+            main = "Ctrl+Alt+Space"
+
+            [[hotkeys.bindings]]
+            hotkey = "Ctrl+Shift+V"
+            query = "/clip "
+
+            [plugins]
+            search_paths = ["/usr/lib/pusz/plugins", "plugins"]
+            query_timeout_ms = 500
+
+            [plugins.query_timeouts_ms]
+            store = 3000
+
+            [plugins.priorities]
+            calc = 50
+
+            [plugins.settings.clip]
+            max_entries = 500
+
+            [[plugins.processes]]
+            name = "weather"
+            command = ["python3", "weather.py"]
+
+            [logging]
+            file = "/tmp/pusz.log"
+            level = "debug"
+
+            [usage]
+            file = "/tmp/usage.json"
+
+            [[special_entries]]
+            label = "jira"
+            pattern = '(PUSZ-\d+)'
+            url = "https://jira.example.com/browse/{}"
+        "#);
+
+        assert_eq!(UiConfig { width : 1024, height : 600, max_results_height : 400 }, config.ui);
+        assert_eq!("Ctrl+Alt+Space".parse::<Hotkey>().unwrap(), config.hotkeys.main);
+        assert_eq!(vec![Binding { hotkey : "Ctrl+Shift+V".parse().unwrap(), query : "/clip ".to_owned() }], config.hotkeys.bindings);
+        assert_eq!(vec![PathBuf::from("/usr/lib/pusz/plugins"), PathBuf::from("plugins")], config.plugins.search_paths);
+        assert_eq!(Some(&toml::Value::Integer(500)), config.plugin_section("clip").and_then(|clip| clip.get("max_entries")));
+        assert_eq!(Duration::from_millis(3000), config.plugins.query_timeout("store"));
+        assert_eq!(Duration::from_millis(500), config.plugins.query_timeout("clip"));
+        assert_eq!(btreemap!("calc".to_owned() => 50), config.plugins.priorities);
+        assert_eq!(vec![ProcessPluginConfig { name : "weather".to_owned(), command : vec!["python3".to_owned(), "weather.py".to_owned()], timeout_ms : 2000 }], config.plugins.processes);
+        assert_eq!(log::LevelFilter::Debug, config.logging.level);
+        assert_eq!(log::LevelFilter::Warn, config.logging.terminal_level);
+        assert_eq!(PathBuf::from("/tmp/usage.json"), config.usage.file);
+        assert_eq!(vec![SpecialEntry { label : "jira".to_owned(), pattern : r"(PUSZ-\d+)".to_owned(), url : "https://jira.example.com/browse/{}".to_owned() }], config.special_entries);
+    }
+
+    #[test]
+    fn unknown_keys_are_reported() {
+        let loaded = Config::parse(r#"
+            colour = "red"
+
+            [ui]
+            widht = 100
+
+            [plugins]
+            serach_paths = ["plugins"]
+
+            [plugins.settings.clip]
+            anything = "goes"
+        "#).unwrap();
+
+        assert_eq!(vec!["colour".to_owned(), "ui.widht".to_owned(), "plugins.serach_paths".to_owned()], loaded.unknown_keys);
+        assert_eq!(Config::default().plugins.search_paths, loaded.config.plugins.search_paths);
+    }
+
+    #[test]
+    fn errors_are_readable() {
+        assert_eq!(Err("unknown key 'Nope' in hotkey 'Ctrl+Nope' for key `hotkeys.main` at line 2 column 8".to_owned()),
+                   Config::parse("[hotkeys]\nmain = \"Ctrl+Nope\"").map(|loaded| loaded.config));
+        assert_eq!(Err("unknown log level 'loud', expected one of: off, error, warn, info, debug, trace for key `logging.level` at line 2 column 9".to_owned()),
+                   Config::parse("[logging]\nlevel = \"loud\"").map(|loaded| loaded.config));
+        assert_eq!(Err("special_entries[0].pattern: '\\d+' needs a capture group".to_owned()),
+                   Config::parse("[[special_entries]]\nlabel = 'x'\npattern = '\\d+'\nurl = 'x'").map(|loaded| loaded.config));
+
+        let err = Config::parse("[ui\nwidth = 1").map(|loaded| loaded.config).unwrap_err();
+        assert!(err.contains("line 1"), "{}", err);
+    }
+
+    #[test]
+    fn plugins_can_be_enabled_and_disabled() {
+        let all = parse("");
+        assert!(all.plugins.is_enabled("calc"));
+
+        let config = parse(r#"
+            [plugins]
+            enabled = ["calc", "clip"]
+            disabled = ["clip"]
+        "#);
+        assert!(config.plugins.is_enabled("calc"));
+        assert!(!config.plugins.is_enabled("clip"));
+        assert!(!config.plugins.is_enabled("store"));
+    }
+
+    #[test]
+    fn error_mentions_the_file() {
+        let err = ConfigError { path : Some(PathBuf::from("/home/user/.config/pusz/config.toml")), message : "broken".to_owned() };
+        assert_eq!("invalid config /home/user/.config/pusz/config.toml: broken", err.to_string());
+    }
+}
@@ -0,0 +1,96 @@
+// key value store of a single plugin, one redb file in its data dir. redb commits are atomic and survive
+// a crash mid write, so plugins get durable state without files of their own.
+
+use std::path::Path;
+
+use redb::{Database, ReadOnlyTable, TableDefinition};
+
+use plugin_interface::host::KvOp;
+
+const TABLE : TableDefinition<&str, &[u8]> = TableDefinition::new("plugin");
+
+pub struct KvStore {
+    db : Database,
+}
+
+impl KvStore {
+    pub fn open(path : &Path) -> Result<Self, String> {
+        let describe = |err : String| format!("store {}: {}", path.display(), err);
+        let db = Database::create(path).map_err(|e| describe(e.to_string()))?;
+        // so reads dont have to tell a missing table from a missing key.
+        let txn = db.begin_write().map_err(|e| describe(e.to_string()))?;
+        txn.open_table(TABLE).map_err(|e| describe(e.to_string()))?;
+        txn.commit().map_err(|e| describe(e.to_string()))?;
+
+        Ok(Self {
+            db,
+        })
+    }
+
+    fn read(&self) -> Result<ReadOnlyTable<&'static str, &'static [u8]>, String> {
+        let txn = self.db.begin_read().map_err(|e| e.to_string())?;
+        txn.open_table(TABLE).map_err(|e| e.to_string())
+    }
+
+    pub fn get(&self, key : &str) -> Result<Option<Vec<u8>>, String> {
+        let table = self.read()?;
+        let value = table.get(key).map_err(|e| e.to_string())?;
+        Ok(value.map(|value| value.value().to_vec()))
+    }
+
+    pub fn scan(&self, prefix : &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+        let table = self.read()?;
+        let mut entries = vec![];
+        for entry in table.range(prefix..).map_err(|e| e.to_string())? {
+            let (key, value) = entry.map_err(|e| e.to_string())?;
+            if !key.value().starts_with(prefix) {
+                break;
+            }
+            entries.push((key.value().to_owned(), value.value().to_vec()));
+        }
+        Ok(entries)
+    }
+
+    pub fn write(&self, ops : &[KvOp]) -> Result<(), String> {
+        let txn = self.db.begin_write().map_err(|e| e.to_string())?;
+        {
+            let mut table = txn.open_table(TABLE).map_err(|e| e.to_string())?;
+            for op in ops {
+                match op {
+                    KvOp::Put { key, value } => table.insert(key.as_str(), value.as_slice()).map(|_| ()),
+                    KvOp::Delete(key) => table.remove(key.as_str()).map(|_| ()),
+                }.map_err(|e| e.to_string())?;
+            }
+        }
+        // dropping an uncommitted transaction rolls all of it back.
+        txn.commit().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key : &str, value : &str) -> KvOp {
+        KvOp::Put { key : key.to_owned(), value : value.as_bytes().to_vec() }
+    }
+
+    #[test]
+    fn writes_survive_reopening() {
+        let path = std::env::temp_dir().join(format!("pusz_kv_store_tests_{}.redb", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        {
+            let store = KvStore::open(&path).unwrap();
+            assert_eq!(None, store.get("a").unwrap());
+            store.write(&[put("clips/b", "2"), put("clips/a", "1"), put("clipsx", "3"), put("other", "4")]).unwrap();
+            store.write(&[KvOp::Delete("other".to_owned()), KvOp::Delete("missing".to_owned())]).unwrap();
+        }
+
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(Some(b"1".to_vec()), store.get("clips/a").unwrap());
+        assert_eq!(None, store.get("other").unwrap());
+        assert_eq!(vec![("clips/a".to_owned(), b"1".to_vec()), ("clips/b".to_owned(), b"2".to_vec())], store.scan("clips/").unwrap());
+        assert_eq!(3, store.scan("").unwrap().len());
+    }
+}
@@ -22,11 +22,39 @@ use gtk::{Application};
 
 use serde::{Serialize, Deserialize};
 
+mod platform;
+use platform::Platform;
+
+mod config;
+use config::{Config, SpecialEntry};
+
+mod plugin_loader;
+mod process_plugin;
+mod wasm_plugin;
+mod script_plugin;
+mod plugin_worker;
+mod ranking;
+mod usage;
+mod plugin_host;
+mod kv_store;
+mod selection;
+use plugin_loader::{load_plugins, PluginFailure};
+use plugin_worker::{Answer, Generation, PluginWorker};
+use ranking::Ranker;
+use selection::{Selection, Step};
+use plugin_host::{HostEvent, PluginHosts};
+use plugin_interface::manifest::{Capability, PluginManifest};
+
+#[cfg(windows)]
 mod winapi_stuff;
-use winapi_stuff::*;
+
+#[cfg(target_os = "linux")]
+mod x11_stuff;
+#[cfg(target_os = "linux")]
+mod wayland_stuff;
+
 use std::collections::HashMap;
-use plugin_interface::{PuszRow, PuszRowBuilder, PuszRowIdentifier, PuszAction, PuszEvent, PuszEntry, PluginEvent, SpecialKey};
-use crate::winapi_stuff::ReceivedMessage::Hotkey;
+use plugin_interface::{PuszRow, PuszRowBuilder, PuszRowIdentifier, PuszAction, PuszEvent, PuszEntry, PluginEvent, PluginResult, KeyChord};
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 enum Model {
@@ -51,7 +79,7 @@ fn draw(_window: &gtk::ApplicationWindow, ctx: &cairo::Context) -> Inhibit {
 
 fn draw_entry_background(_window: &gtk::Box, ctx: &cairo::Context) -> Inhibit {
     // crucial for transparency
-    if _window.has_focus() {
+    if _window.get_state_flags().contains(gtk::StateFlags::SELECTED) {
         ctx.set_source_rgba(1.0, 0.0, 1.0, 1.0);
     } else {
         ctx.set_source_rgba(1.0, 0.0, 0.0, 1.0);
@@ -64,12 +92,12 @@ fn draw_entry_background(_window: &gtk::Box, ctx: &cairo::Context) -> Inhibit {
 fn special_entry(ctx : &Context, text : &str) -> Vec<PuszEntry> {
     let mut entries = vec![];
 
-    for (regex, base) in ctx.special_entries_builders.iter() {
+    for (regex, special) in ctx.special_entries_builders.iter() {
         for cap in regex.captures_iter(text) {
             entries.push(PuszEntry {
                 actions : btreemap!(PuszEvent::Click => PuszAction::SetClipboard),
-                label:  format!("snow link: {}", cap[1].to_owned()),
-                content: format!("https://ig.service-now.com/{}.do?sysparm_query=number={}", base, cap[1].to_owned()),
+                label:  format!("{}: {}", special.label, &cap[1]),
+                content: special.url.replace("{}", &cap[1]),
             })
         }
     }
@@ -77,85 +105,55 @@ fn special_entry(ctx : &Context, text : &str) -> Vec<PuszEntry> {
     entries
 }
 
-
-//okay gdk_event may not make sense - how to pass on keystrokes here? for now single return is sent as DAMAGE :)
-fn handle_action(gdk_event: gdk::EventType, entry : &PuszEntry, plugins : &mut HashMap<String, Box<dyn plugin_interface::Plugin>>) -> Inhibit {
-    let action = match gdk_event {
-        gdk::EventType::ButtonPress => {
-            if let Some(action) = entry.actions.get(&PuszEvent::Click) {
-                action
-            } else {
-                return Inhibit(false);
+// false when there was nobody to run it, the row's plugin is gone.
+fn run_action(action : &PuszAction, entry : &PuszEntry, row : &PuszRowIdentifier, ctx : &Context) -> bool {
+    match action {
+        PuszAction::SetClipboard => {
+            if let Err(err) = ctx.platform.set_clipboard(&entry.content) {
+                error!("couldnt set clipboard: {}", err);
             }
-        }
 
-        gdk::EventType::Damage => {
-            //fake out return lul.
-            if let Some(action) = entry.actions.get(&PuszEvent::SpecialKeyPress(SpecialKey::Return)) {
-                action
-            } else {
-                return Inhibit(false);
+            true
+        },
+        PuszAction::Paste => {
+            // the window has to be gone before the keys go out, so the ui loop does the rest.
+            match ctx.platform.set_clipboard(&entry.content) {
+                Ok(()) => (ctx.events)(PuszInternalEvent::Paste),
+                // pasting whatever was in the clipboard before would be worse.
+                Err(err) => error!("couldnt set clipboard, not pasting: {}", err),
             }
-        }
-        _ => {
-            return Inhibit(false);
-        }
-    };
-
-    match action {
-        PuszAction::SetClipboard => {
-            HotkeyData::set_clipboard(&entry.content);
 
-            Inhibit(true)
+            true
         },
         PuszAction::OpenBrowserIfLink => {
             if url::Url::parse(&entry.content).is_ok() {
                 webbrowser::open(&entry.content);
             }
 
-            Inhibit(true)
+            true
         },
-        PuszAction::CustomAction => {
-            panic!("custom action");
-
-            //this inhibit depends on the plugin result.
-            Inhibit(false)
+        PuszAction::CustomAction(action) => {
+            // what happens next is up to the plugin, see ActionAnswered.
+            match ctx.plugins.get(&row.plugin_id) {
+                Some(plugin) => {
+                    let (events, plugin_id) = (ctx.events.clone(), row.plugin_id.clone());
+                    plugin.action(row.clone(), action.clone(), move |result| events(PuszInternalEvent::ActionAnswered { plugin : plugin_id, result }));
+                    true
+                },
+                None => {
+                    warn!("action {} for a row of {} which is not loaded", action.id, row.plugin_id);
+                    false
+                }
+            }
         },
     }
 }
 
-fn spawn_entry(ctx : Rc<RefCell<Context>>, main_edit : gtk::Entry, row : PuszRow) -> gtk::Box {
+fn spawn_entry(ctx : Rc<RefCell<Context>>, row : PuszRow) -> gtk::Box {
     let container = gtk::Box::new(gtk::Orientation::Horizontal, 0);
 
-    let text = row.main_entry.content.clone();
-
-    let text_cloned = text.clone();
-    let ctx_clone = ctx.clone();
-    let main_entry_clone = row.main_entry.clone();
-    container.connect_key_press_event(move |_, event_key| {
-        use gdk::enums::key::*;
-        #[allow(non_upper_case_globals)]
-        match event_key.get_keyval() {
-            Return => {
-                let ctx : &mut Context = &mut ctx_clone.borrow_mut();
-                handle_action(gdk::EventType::ButtonPress, &main_entry_clone, &mut ctx.plugins);
-                //we are doing buttonpress and return at the same time.. temporarly(?)
-                handle_action(gdk::EventType::Damage, &main_entry_clone, &mut ctx.plugins);
-
-                Inhibit(false)
-            }
-            Down | Up  => {
-                Inhibit(false)
-            }
-            _ => {
-                main_edit.grab_focus_without_selecting();
-                let _ = main_edit.emit("key-press-event", &[&event_key.to_value()]);
-                Inhibit(true)
-            }
-        }
-        });
-
-    container.set_can_focus(true);
+    // keys stay with the input field, rows are only highlighted, see show_selection.
+    container.set_can_focus(false);
 //    container.set_has_window(true); crashes app.
     container.connect_draw(draw_entry_background);
 
@@ -165,10 +163,23 @@ fn spawn_entry(ctx : Rc<RefCell<Context>>, main_edit : gtk::Entry, row : PuszRow
 
     for entry in entries {
         let button = gtk::Button::new_with_label(&entry.label);
+        button.set_can_focus(false);
         let ctx = ctx.clone();
+        let identifier = row.identifier.clone();
         button.connect_button_press_event(move |_, event| {
+            let clicked = match event.get_event_type() {
+                gdk::EventType::ButtonPress => PuszEvent::Click,
+                gdk::EventType::DoubleButtonPress => PuszEvent::DoubleClick,
+                _ => return Inhibit(false),
+            };
+
             let ctx: &mut Context = &mut ctx.borrow_mut();
-            handle_action(event.get_event_type(), &entry, &mut ctx.plugins)
+            let handled = entry.action(&clicked).map_or(false, |action| run_action(action, &entry, &identifier, ctx));
+            // picked rows rank higher next time.
+            if handled {
+                ctx.ranker.picked(&identifier);
+            }
+            Inhibit(handled)
         });
 
         container.add(&button);
@@ -211,24 +222,63 @@ impl Query {
 }
 
 struct Context {
-    special_entries_builders : Vec<(regex::Regex, String)>,
-
-    plugins : HashMap<String, Box<dyn plugin_interface::Plugin>>,
+    special_entries_builders : Vec<(regex::Regex, SpecialEntry)>,
+
+    plugins : HashMap<String, PluginWorker>,
+    // query the results on screen belong to, answers to older ones are dropped.
+    generation : Generation,
+    query : String,
+    // everything answered for the current query so far, ranked.
+    results : Vec<PuszRow>,
+    selection : Selection,
+    ranker : Ranker,
+    // only for plugins loaded from libraries, keyed like plugins.
+    manifests : HashMap<String, PluginManifest>,
+    plugin_sources : HashMap<String, std::path::PathBuf>,
+    plugin_failures : Vec<PluginFailure>,
+
+    platform : Arc<dyn Platform>,
+    // feeds the ui event loop from plugin threads.
+    events : Arc<dyn Fn(PuszInternalEvent) + Send + Sync>,
+    // also what plugins loaded later on get.
+    hosts : PluginHosts,
 }
 
 impl Context {
-    fn new() -> Self {
-        let r = [(r"(INC\d{4,})", "incident"),
-            (r"(RITM\d{4,})", "sc_req_item"),
-            (r"(CHG\d{4,})", "change_request"),
-            (r"(PRB\d{4,})", "problem")
-            , (r"(PRBTASK\d{4,})", "problem_task")];
-
-
+    fn new(platform : Arc<dyn Platform>, config : &Config, events : Arc<dyn Fn(PuszInternalEvent) + Send + Sync>) -> Self {
+        let host_events = events.clone();
+        let hosts = PluginHosts::new(platform.clone(), config.plugins.data_dir.clone(), Arc::new(move |event| host_events(PuszInternalEvent::Host(event))));
+        let loaded = load_plugins(&config.plugins, &hosts);
         Self {
-            special_entries_builders: r.iter().map(|(pattern, base)| (regex::Regex::new(pattern).expect(&format!("failure to build regex from {}", pattern)), base.to_string())).collect(),
+            manifests : loaded.manifests,
+            plugin_sources : loaded.sources,
+            plugin_failures : loaded.failures,
+            events,
+            ..Self::with_plugins(platform, config, hosts, usage::UsageLog::load(&config.usage.file), loaded.plugins)
+        }
+    }
 
-            plugins: load_plugins(),
+    fn with_plugins(platform : Arc<dyn Platform>, config : &Config, hosts : PluginHosts, usage : usage::UsageLog, plugins : HashMap<String, Box<dyn plugin_interface::Plugin>>) -> Self {
+        Self {
+            // patterns were already validated when config was parsed.
+            special_entries_builders: config.special_entries.iter().map(|special| (regex::Regex::new(&special.pattern).expect(&format!("failure to build regex from {}", special.pattern)), special.clone())).collect(),
+
+            plugins : plugins.into_iter().map(|(name, plugin)| {
+                let timeout = config.plugins.query_timeout(&name);
+                (name, PluginWorker::spawn(plugin, timeout))
+            }).collect(),
+            generation : Generation::default(),
+            query : String::new(),
+            results : vec![],
+            selection : Selection::default(),
+            ranker : Ranker::new(config.plugins.priorities.clone(), usage),
+            manifests : HashMap::new(),
+            plugin_sources : HashMap::new(),
+            plugin_failures : vec![],
+
+            hosts,
+            platform,
+            events : Arc::new(|_| {}),
         }
     }
 
@@ -240,34 +290,356 @@ impl Context {
     }
 }
 
+#[derive(PartialEq, Debug)]
 enum PuszInternalEvent {
     ClipboardChanged(String),
-    BringToFront,
+    // optional text the input is pre-filled with, the query of a binding or for the main hotkey what is in the clipboard.
+    BringToFront(Option<String>),
+    // plugin library in a search path was rebuilt or a script edited.
+    PluginChanged(std::path::PathBuf),
+    // a plugin sent a batch for, or finished, the query of that generation.
+    QueryAnswered { generation : u64, plugin : String, answer : Answer },
+    // a plugin is done with a custom action of one of its rows.
+    ActionAnswered { plugin : String, result : PluginResult },
+    // a plugin asked for a toast or fresh results.
+    Host(HostEvent),
+    // the clipboard holds a picked row, hide and paste it where the user was.
+    Paste,
+}
+
+const MAIN_HOTKEY_ID : i32 = 13;
+const TOAST_SECONDS : u32 = 3;
+
+// routes everything the platform reports into the ui event loop.
+fn connect_platform<F>(platform : Arc<dyn Platform>, config : &Config, send : F)
+    where F : Fn(PuszInternalEvent) + Clone + Send + Sync + 'static {
+    {
+        let send = send.clone();
+        let listening = platform.add_clipboard_listener(Arc::new(move |clip| {
+            send(PuszInternalEvent::ClipboardChanged(clip));
+        }));
+
+        if let Err(err) = listening {
+            error!("clipboard history will not be recorded: {}", err);
+        }
+    }
+
+    let hotkeys = ::std::iter::once((config.hotkeys.main, None))
+        .chain(config.hotkeys.bindings.iter().map(|binding| (binding.hotkey, Some(binding.query.clone()))));
+
+    for (id, (hotkey, query)) in (MAIN_HOTKEY_ID..).zip(hotkeys) {
+        let send = send.clone();
+        // the platform keeps the handler, a strong reference back would keep both alive forever.
+        let clipboard_platform = Arc::downgrade(&platform);
+        let registered = platform.register_hotkey(id, hotkey, Arc::new(move |_| {
+            if query.is_some() {
+                send(PuszInternalEvent::BringToFront(query.clone()));
+                return;
+            }
+
+            // handlers run on the platform's event thread, which is the one answering get_clipboard.
+            let send = send.clone();
+            let platform = match clipboard_platform.upgrade() {
+                Some(platform) => platform,
+                None => return,
+            };
+            spawn(move || {
+                let clipboard = platform.get_clipboard().unwrap_or_else(|err| {
+                    warn!("couldnt read the clipboard: {}", err);
+                    None
+                });
+                send(PuszInternalEvent::BringToFront(clipboard));
+            });
+        }));
+
+        if let Err(err) = registered {
+            error!("couldnt register hotkey {}: {}", hotkey, err);
+        }
+    }
+}
+
+// plugins without a manifest are built into pusz or configured as processes by the user, both trusted with everything.
+fn has_capability(manifests : &HashMap<String, PluginManifest>, plugin : &str, capability : Capability) -> bool {
+    manifests.get(plugin).map_or(true, |manifest| manifest.has_capability(capability))
+}
+
+fn handles_command(manifests : &HashMap<String, PluginManifest>, plugin : &str, command : &str) -> bool {
+    manifests.get(plugin).map_or(plugin == command, |manifest| manifest.handles_command(command))
+}
+
+fn notify_clipboard_changed(ctx : &mut Context, clipboard : String) {
+    let manifests = &ctx.manifests;
+    for (name, plugin) in ctx.plugins.iter() {
+        if plugin.settings().interested_in_clipboard && !has_capability(manifests, name, Capability::ClipboardEvents) {
+            debug!("plugin {} wants clipboard events but its manifest doesnt ask for them", name);
+        } else if plugin.settings().interested_in_clipboard {
+            plugin.notify(PluginEvent::Clipboard(clipboard.clone()));
+        }
+    }
+}
+
+// puts a freshly loaded plugin in place of the instance built from the same library, handing over its state.
+fn swap_plugin(ctx : &mut Context, config : &config::PluginsConfig, manifest : PluginManifest, plugin : Box<dyn plugin_interface::Plugin>, source : std::path::PathBuf) {
+    let name = manifest.name.clone();
+    let worker = PluginWorker::spawn(plugin, config.query_timeout(&name));
+    if let Some(old) = ctx.plugins.remove(&name) {
+        // handed over on the new plugin's thread, the ui doesnt wait for the old one.
+        worker.take_over(old);
+    }
+
+    info!("loaded plugin {} {} from {}", name, manifest.version, source.display());
+    ctx.plugins.insert(name.clone(), worker);
+    ctx.manifests.insert(name.clone(), manifest);
+    ctx.plugin_sources.insert(name, source);
+}
+
+fn reload_plugin(ctx : &mut Context, config : &config::PluginsConfig, path : std::path::PathBuf) {
+    let source = path.display().to_string();
+    ctx.plugin_failures.retain(|failure| failure.source != source);
+
+    match plugin_loader::load_library(config, &ctx.hosts, &path) {
+        Ok(Some((manifest, _))) if ctx.plugin_sources.get(&manifest.name).map_or(false, |loaded_from| *loaded_from != path) => {
+            info!("ignoring rebuilt {}, plugin {} is loaded from {}", source, manifest.name, ctx.plugin_sources[&manifest.name].display());
+        },
+        Ok(Some((manifest, plugin))) => swap_plugin(ctx, config, manifest, plugin, path),
+        Ok(None) => {},
+        Err(reason) => {
+            // the previous build, if any, stays in use.
+            error!("plugin {} failed to reload: {}", source, reason);
+            ctx.plugin_failures.push(PluginFailure { source, reason });
+        }
+    }
+}
+
+const PLUGINS_COMMAND : &str = "plugins";
+
+// rows for /plugins, loaded plugins first then the ones that failed with the reason.
+fn plugin_status_rows(ctx : &Context) -> Vec<PuszRow> {
+    let mut names : Vec<_> = ctx.plugins.keys().collect();
+    names.sort();
+
+    let loaded = names.into_iter().map(|name| {
+        let label = match ctx.manifests.get(name) {
+            Some(manifest) if manifest.author.is_empty() => format!("{} {}: {}", name, manifest.version, manifest.description),
+            Some(manifest) => format!("{} {} by {}: {}", name, manifest.version, manifest.author, manifest.description),
+            None => format!("{}: loaded", name),
+        };
+        PuszRowBuilder::new(label, PuszRowIdentifier::new(PLUGINS_COMMAND, name.clone())).build().unwrap()
+    });
+
+    let failed = ctx.plugin_failures.iter().map(|failure| {
+        PuszRowBuilder::new(format!("{}: failed: {}", failure.source, failure.reason), PuszRowIdentifier::new(PLUGINS_COMMAND, failure.source.clone())).build().unwrap()
+    });
+
+    loaded.chain(failed).collect()
+}
+
+// folds a batch or final answer of one plugin into the results, false when there is nothing new to show.
+fn merge_answer(ctx : &mut Context, plugin : &str, answer : Answer) -> bool {
+    let rows = match answer {
+        Answer::Rows(rows) | Answer::Done(PluginResult::Ok(rows)) => rows,
+        Answer::Done(PluginResult::Error(err)) => {
+            warn!("plugin {} failed to answer: {}", plugin, err);
+            return false;
+        },
+        Answer::Done(PluginResult::None) | Answer::Done(PluginResult::Close) => return false,
+    };
+
+    if rows.is_empty() {
+        return false;
+    }
+    ctx.ranker.merge(&mut ctx.results, &ctx.query, rows);
+    true
+}
+
+#[derive(PartialEq, Debug)]
+enum AfterAction {
+    Keep,
+    Redraw,
+    Hide,
+}
+
+fn after_action(ctx : &mut Context, plugin : &str, result : PluginResult) -> AfterAction {
+    match result {
+        PluginResult::None => AfterAction::Keep,
+        PluginResult::Close => AfterAction::Hide,
+        PluginResult::Ok(rows) => {
+            // whatever the query still brings in would mix into the plugin's list.
+            ctx.generation.next();
+            ctx.results = rows;
+            AfterAction::Redraw
+        },
+        PluginResult::Error(err) => {
+            warn!("plugin {} failed to run an action: {}", plugin, err);
+            let row = PuszRowBuilder::new(format!("{}: {}", plugin, err), PuszRowIdentifier::new(plugin, "error".to_owned())).build().unwrap();
+            ctx.results.insert(0, row);
+            AfterAction::Redraw
+        },
+    }
+}
+
+// redraws the results, each batch can move rows already shown.
+fn show_results(ctx : &Rc<RefCell<Context>>, scroll_insides : &gtk::Box, hint : &gtk::Label) {
+    for c in &scroll_insides.get_children() {
+        scroll_insides.remove(c);
+    }
+
+    let rows = ctx.borrow().results.clone();
+    for row in rows {
+        scroll_insides.add(&spawn_entry(ctx.clone(), row));
+    }
+    scroll_insides.show_all();
+    show_selection(&ctx.borrow(), scroll_insides, hint);
+}
+
+#[allow(non_upper_case_globals)]
+fn navigation_step(key : gdk::enums::key::Key) -> Option<Step> {
+    use gdk::enums::key::*;
+    match key {
+        Up => Some(Step::Up),
+        Down => Some(Step::Down),
+        Page_Up => Some(Step::PageUp),
+        Page_Down => Some(Step::PageDown),
+        Home => Some(Step::Home),
+        End => Some(Step::End),
+        _ => None,
+    }
 }
 
-fn build_ui(application: &gtk::Application) {
-    let ctx = Rc::new(RefCell::new(Context::new()));
+#[allow(non_upper_case_globals)]
+fn key_chord(key : gdk::enums::key::Key, state : gdk::ModifierType) -> Option<KeyChord> {
+    use gdk::enums::key;
+    use gdk::ModifierType;
+
+    let (shift, ctrl, alt) = (state.contains(ModifierType::SHIFT_MASK), state.contains(ModifierType::CONTROL_MASK), state.contains(ModifierType::MOD1_MASK));
+    match key {
+        key::Return | key::KP_Enter if ctrl => Some(KeyChord::CtrlReturn),
+        key::Return | key::KP_Enter if shift => Some(KeyChord::ShiftReturn),
+        key::Return | key::KP_Enter if alt => Some(KeyChord::AltReturn),
+        key::Return | key::KP_Enter => Some(KeyChord::Return),
+        key::Delete | key::KP_Delete => Some(KeyChord::Delete),
+        key::c | key::C if ctrl => Some(KeyChord::CtrlC),
+        _ => None,
+    }
+}
+
+fn set_selected(widget : &gtk::Widget, selected : bool) {
+    if selected {
+        widget.set_state_flags(gtk::StateFlags::SELECTED, false);
+    } else {
+        widget.unset_state_flags(gtk::StateFlags::SELECTED);
+    }
+}
+
+// highlights the selected row and entry, scrolls the row into view and tells what its chords do.
+fn show_selection(ctx : &Context, scroll_insides : &gtk::Box, hint : &gtk::Label) {
+    let (selected_row, selected_entry) = (ctx.selection.index(&ctx.results), ctx.selection.entry_index(&ctx.results));
+    let hints = ctx.selection.entry(&ctx.results).map_or_else(String::new, |(_, entry)| selection::hints(entry));
+    hint.set_text(&hints);
+    hint.set_visible(!hints.is_empty());
+
+    for (index, row) in scroll_insides.get_children().iter().enumerate() {
+        let selected = selected_row == Some(index);
+        set_selected(row, selected);
+        if let Some(row) = row.downcast_ref::<gtk::Box>() {
+            for (entry, button) in row.get_children().iter().enumerate() {
+                set_selected(button, selected && entry == selected_entry);
+            }
+        }
+
+        if let (true, Some(adjustment)) = (selected, scroll_insides.get_focus_vadjustment()) {
+            let allocation = row.get_allocation();
+            adjustment.clamp_page(allocation.y as f64, (allocation.y + allocation.height) as f64);
+        }
+    }
+}
+
+// a chord on the selected entry, false when the entry has nothing bound to it.
+fn activate_selection(ctx : &mut Context, chord : KeyChord) -> bool {
+    let (identifier, entry) = match ctx.selection.entry(&ctx.results) {
+        Some((row, entry)) => (row.identifier.clone(), entry.clone()),
+        None => return false,
+    };
+
+    let handled = selection::chord_action(&entry, chord).map_or(false, |action| run_action(action, &entry, &identifier, ctx));
+    // picked rows rank higher next time.
+    if handled {
+        ctx.ranker.picked(&identifier);
+    }
+    handled
+}
+
+// asks the plugins about whatever is in the input, on every edit and when a plugin wants fresh results.
+fn run_query(ctx : &Rc<RefCell<Context>>, input_field : &gtk::Entry, scroll_insides : &gtk::Box, hint : &gtk::Label) {
+    for c in &scroll_insides.get_children() {
+        scroll_insides.remove(c);
+    }
+
+    if let Some(text) = input_field.get_text() {
+        let mut words = text.split_whitespace();
+        let (query, command) = if text.starts_with("/") {
+            let command = &words.next().unwrap()[1..];
+            let query: String = words.collect();
+
+            (query, Some(command))
+        } else {
+            (text.to_string(), None)
+        };
+
+        // answers to whatever was typed before are dropped from here on.
+        let generation = ctx.borrow().generation.next();
+        {
+            let mut ctx = ctx.borrow_mut();
+            ctx.query = query.clone();
+            ctx.results.clear();
+            ctx.selection = Selection::default();
+        }
+
+        if command == Some(PLUGINS_COMMAND) {
+            let rows = plugin_status_rows(&ctx.borrow());
+            ctx.borrow_mut().results = rows;
+            show_results(ctx, scroll_insides, hint);
+        } else {
+            let ctx = ctx.borrow();
+            let manifests = &ctx.manifests;
+            let queried = ctx.plugins
+                .iter()
+                .filter(|(name, plugin)| command.map_or(false, |command| handles_command(manifests, name, command)) || !plugin.settings().requies_explicit_query);
+
+            // rows show up batch by batch as plugins find them, see QueryAnswered.
+            for (name, plugin) in queried {
+                let (send, name) = (ctx.events.clone(), name.clone());
+                plugin.query(query.clone(), generation, &ctx.generation, move |answer| send(PuszInternalEvent::QueryAnswered { generation, plugin : name.clone(), answer }));
+            }
+        }
+    }
+    scroll_insides.show_all();
+}
+
+fn build_ui(application: &gtk::Application, config : &Config) {
+    let platform = platform::native();
+    let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+    let send = move |event| {
+        tx.send(event).expect("send failure");
+    };
+    let ctx = Rc::new(RefCell::new(Context::new(platform.clone(), config, Arc::new(send.clone()))));
 
     let window = gtk::ApplicationWindow::new(application);
     window.connect_screen_changed(set_visual);
     window.connect_draw(draw);
     window.set_app_paintable(true); // crucial for transparency
-    let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+    connect_platform(platform.clone(), config, send.clone());
 
     {
-        let tx = tx.clone();
-        HotkeyData::do_it(WindowsApiEvent::AddClipboardListener {
-            handler: Arc::new(move |clip| {
-                tx.send(PuszInternalEvent::ClipboardChanged(clip)).expect("send failure");
-            }
-            )
-        });
+        let send = send.clone();
+        plugin_loader::watch(&config.plugins.search_paths, config.plugins.hot_reload, move |path| send(PuszInternalEvent::PluginChanged(path)));
     }
+    let plugins_config = config.plugins.clone();
 
     window.set_title("pusz");
     window.set_border_width(0);
     window.set_position(gtk::WindowPosition::Center);
-    window.set_default_size(840, 480);
+    window.set_default_size(config.ui.width, config.ui.height);
     window.set_decorated(false);
 
 //    window.connect_focus_in_event(|_, event| {
@@ -285,167 +657,166 @@ fn build_ui(application: &gtk::Application) {
     let row = gtk::Box::new(gtk::Orientation::Vertical, 1);
 
     let scroll_container = gtk::ScrolledWindow::new( gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
-    scroll_container.set_max_content_height(400);
+    scroll_container.set_max_content_height(config.ui.max_results_height);
 
 
     let scroll_insides = gtk::Box::new(gtk::Orientation::Vertical, 1);
     scroll_container.add(&scroll_insides);
+    // what show_selection scrolls.
+    if let Some(adjustment) = scroll_container.get_vadjustment() {
+        scroll_insides.set_focus_vadjustment(&adjustment);
+    }
 
     row.add(&input_field);
 //    row.pack_start(&input_field, false, false, 10);
+    // messages plugins show through their host, hidden until there is one.
+    let toast = gtk::Label::new(None);
+    row.add(&toast);
     row.add(&scroll_container);
     row.set_child_expand(&scroll_container, true);
+    // chords of the selected entry.
+    let hint = gtk::Label::new(None);
+    row.add(&hint);
 
 //    let mut visible = true;
 
     window.add(&row);
 
     window.show_all();
+    toast.hide();
+    hint.hide();
     {
         let ctx = Rc::clone(&ctx);
         let input_field = input_field.clone();
-        input_field.clone().connect_key_press_event(move |_, event_key| {
-            use gdk::enums::key::*;
-            #[allow(non_upper_case_globals)]
+        let scroll_insides = scroll_insides.clone();
+        let hint = hint.clone();
+        {
+            let (ctx, scroll_insides, hint, window) = (ctx.clone(), scroll_insides.clone(), hint.clone(), window.clone());
+            input_field.clone().connect_key_press_event(move |_, event_key| {
+                use gdk::enums::key::*;
+                #[allow(non_upper_case_globals)]
                 match event_key.get_keyval() {
-                Return => {
-                    //TODO: so what we need to do here is we need to have an ability to know  which entry is the first one upon pressing enter
-                    //alternatively we just pass this to plugin - but which plugin? without /command to no plugin? with command to specific plugin.
-                    println!("key pressed on main focus");
-                    Inhibit(false)
-                }
-                _ => {
-                    Inhibit(false)
-                }
-            }
-        });
-        input_field.clone().connect_changed(move |entry| {
-            for c in &scroll_insides.get_children() {
-                scroll_insides.remove(c);
-            }
-
-            if let Some(text) = entry.get_text() {
-                let mut words = text.split_whitespace();
-                let (query, command) = if text.starts_with("/") {
-                    let command = &words.next().unwrap()[1..];
-                    let query: String = words.collect();
-
-                    (query, Some(command))
-                } else {
-                    (text.to_string(), None)
-                };
-
-                //would current borrowck allow me to store plugins into vec rather than doing the below abom?
-
-                let results: Vec<PluginResult> =
-                    ctx.borrow_mut()
-                        .plugins
-                        .iter_mut()
-                        .filter(|(name, plugin)| Some(plugin.name()) == command || !plugin.settings().requies_explicit_query)
-                        .map(|(_name, plugin)| {
-                    plugin.query(&query)
-                    // hint about what plugins are available
-                }).collect();
-
-                use plugin_interface::*;
-                // better handle a case where nothing matches.
-//                    if results.is_empty() {
-//                        let err_message = format!("{:?}", result);
-//                        let err_row = PuszRowBuilder::new(err_message, main_id()).build().unwrap();
-//
-//                        scroll_insides.add(&spawn_entry(ctx.clone(), input_field.clone(), err_row));
-//                    } else {
-                for result in results {
-                    if let PluginResult::Ok(results) = result {
-                        for r in results {
-                            scroll_insides.add(&spawn_entry(ctx.clone(), input_field.clone(), r));
-                        }
-                    }
+                    Escape => window.hide(),
+                    Tab => {
+                        let mut ctx = ctx.borrow_mut();
+                        let ctx = &mut *ctx;
+                        ctx.selection.next_entry(&ctx.results);
+                    },
+                    key => match (navigation_step(key), key_chord(key, event_key.get_state())) {
+                        (Some(step), _) => {
+                            let mut ctx = ctx.borrow_mut();
+                            let ctx = &mut *ctx;
+                            ctx.selection.step(&ctx.results, step);
+                        },
+                        // Delete and Ctrl+C edit the query unless the selected entry binds them.
+                        (None, Some(chord)) => if !activate_selection(&mut ctx.borrow_mut(), chord) {
+                            return Inhibit(false);
+                        },
+                        (None, None) => return Inhibit(false),
+                    },
                 }
-            }
-            scroll_insides.show_all();
-        });
+                show_selection(&ctx.borrow(), &scroll_insides, &hint);
+                Inhibit(true)
+            });
+        }
+        input_field.clone().connect_changed(move |_| run_query(&ctx, &input_field, &scroll_insides, &hint));
     }
 
     rx.attach(None, move |event| {
         match event {
             PuszInternalEvent::ClipboardChanged(clipboard) => {
-                for (_, plugin) in ctx.borrow_mut().plugins.iter_mut() {
-                    if plugin.settings().interested_in_clipboard {
-                        plugin.on_subscribed_event(&PluginEvent::Clipboard(clipboard.clone()));
+                notify_clipboard_changed(&mut ctx.borrow_mut(), clipboard);
+            },
+            PuszInternalEvent::PluginChanged(path) => {
+                reload_plugin(&mut ctx.borrow_mut(), &plugins_config, path);
+            },
+            PuszInternalEvent::QueryAnswered { generation, plugin, answer } => {
+                // the input changed since this query went out.
+                let current = ctx.borrow().generation.current();
+                let changed = generation == current && merge_answer(&mut ctx.borrow_mut(), &plugin, answer);
+                if changed {
+                    show_results(&ctx, &scroll_insides, &hint);
+                }
+            },
+            PuszInternalEvent::ActionAnswered { plugin, result } => {
+                let after = after_action(&mut ctx.borrow_mut(), &plugin, result);
+                match after {
+                    AfterAction::Keep => {},
+                    AfterAction::Redraw => show_results(&ctx, &scroll_insides, &hint),
+                    AfterAction::Hide => window.hide(),
+                }
+            },
+            PuszInternalEvent::Host(HostEvent::Toast { plugin, message }) => {
+                let text = format!("{}: {}", plugin, message);
+                toast.set_text(&text);
+                toast.show();
+                let toast = toast.clone();
+                // a newer toast stays up for its own 3 seconds.
+                glib::timeout_add_seconds_local(TOAST_SECONDS, move || {
+                    if toast.get_text().map_or(false, |shown| shown.as_str() == text) {
+                        toast.hide();
                     }
+                    glib::Continue(false)
+                });
+            },
+            PuszInternalEvent::Host(HostEvent::Refresh { plugin }) => {
+                debug!("{} asked for fresh results", plugin);
+                run_query(&ctx, &input_field, &scroll_insides, &hint);
+            },
+            PuszInternalEvent::Paste => {
+                window.hide();
+                if let Err(err) = platform.paste() {
+                    error!("couldnt paste: {}", err);
                 }
             },
-            PuszInternalEvent::BringToFront => {
+            PuszInternalEvent::BringToFront(query) => {
+                platform.bring_to_front();
                 window.present();
-                if let Some(clip) = HotkeyData::get_clipboard() {
-                    input_field.grab_focus();
-                    input_field.emit_paste_clipboard();
+                if let Some(query) = query {
+                    input_field.set_text(&query);
+                    input_field.grab_focus_without_selecting();
+                    input_field.set_position(-1);
                 }
             },
         }
         glib::Continue(true)
     });
-
-    HotkeyData::register_hotkey(13, winapi_stuff::Key::F1, Modifier::None, Arc::new(move |_| {
-        tx.send(PuszInternalEvent::BringToFront).unwrap();
-    }));
-}
-
-fn load_plugins() -> HashMap<String, Box<dyn plugin_interface::Plugin>> {
-    use std::fs;
-
-    let mut dll_paths =
-    if let Ok(entries) = fs::read_dir("plugins") {
-        entries.filter_map(|e| e.ok()).filter_map(|e| e.path().into_os_string().into_string().ok()).filter(|file_name| file_name.ends_with(".dll")).collect::<Vec<_>>()
-    } else {
-        println!("couldnt read plugins dir?");
-        return HashMap::new();
-    };
-
-    //hacky solution for development purposes.
-    if std::path::Path::new("target/debug/calc_plugin.dll").exists() {
-        dll_paths.push("target/debug/calc_plugin.dll".to_string());
-    }
-
-    if std::path::Path::new("target/debug/clipboard_plugin.dll").exists() {
-        dll_paths.push("target/debug/clipboard_plugin.dll".to_string());
-    }
-
-   let mut plugins : Vec<Box<dyn plugin_interface::Plugin>> =
-        unsafe {
-            dll_paths.into_iter().map(|dll_path| {
-                let lib = libloading::Library::new(dll_path).expect("failed to load");
-                let load: libloading::Symbol<plugin_interface::LoadFn> = lib.get(b"load").expect("failed to load introduce");
-                let plugin = load(plugin_interface::COMMON_INTERFACE_VERSION);
-
-                //well - we dont want to unload plugins ever.
-                ::std::mem::forget(lib);
-
-                plugin.expect("couldnt load plugin!")
-            }).collect()
-        };
-
-    plugins.into_iter().map(|p| (p.name().to_string(), p)).collect()
 }
 
 fn main() {
     use simplelog::*;
     use std::fs::File;
+
+    // logging itself is configured there, so errors go straight to stderr.
+    let loaded = config::Config::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        ::std::process::exit(1);
+    });
+    let config = loaded.config;
+
+    let log_file = File::create(&config.logging.file).unwrap_or_else(|err| {
+        eprintln!("couldnt create log file {}: {}", config.logging.file.display(), err);
+        ::std::process::exit(1);
+    });
+
     CombinedLogger::init(
         vec![
-            TermLogger::new(LevelFilter::Warn, Config::default(), TerminalMode::Mixed).unwrap(),
-            WriteLogger::new(LevelFilter::Info, Config::default(), File::create("pusz.log").unwrap()),
+            TermLogger::new(config.logging.terminal_level, Config::default(), TerminalMode::Mixed).unwrap(),
+            WriteLogger::new(config.logging.level, Config::default(), log_file),
         ]
     ).unwrap();
 
     info!("Pusz application initializing.");
+    for key in loaded.unknown_keys {
+        warn!("unknown config key: {}", key);
+    }
 
+    platform::init();
     let application = Application::new(Some("com.github.gtk-rs.examples.basic"), Default::default())
         .expect("failed to initialize GTK application");
 
-    application.connect_activate(|app| {
-        build_ui(app);
+    application.connect_activate(move |app| {
+        build_ui(app, &config);
     });
 
     application.run(&[]);
@@ -454,10 +825,54 @@ fn main() {
 #[cfg(test)]
 mod model_tests {
     use super::*;
+    use crate::platform::fake::FakePlatform;
+    use std::sync::mpsc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // plugins whose host events go nowhere, usage kept in memory and data in a dir of its own.
+    fn context_with(platform : Arc<dyn Platform>, plugins : HashMap<String, Box<dyn plugin_interface::Plugin>>) -> Context {
+        static NEXT_DATA_DIR : AtomicUsize = AtomicUsize::new(0);
+        let data_dir = std::env::temp_dir().join(format!("pusz_model_tests_{}_{}", std::process::id(), NEXT_DATA_DIR.fetch_add(1, Ordering::SeqCst)));
+        let hosts = PluginHosts::new(platform.clone(), data_dir, Arc::new(|_| {}));
+        Context::with_plugins(platform, &Config::default(), hosts, usage::UsageLog::default(), plugins)
+    }
+
+    fn test_context(platform : Arc<dyn Platform>) -> Context {
+        context_with(platform, HashMap::new())
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingPlugin {
+        events : Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl plugin_interface::Plugin for RecordingPlugin {
+        fn query(&mut self, _query : &str) -> plugin_interface::PluginResult {
+            plugin_interface::PluginResult::None
+        }
+
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        fn settings(&self) -> plugin_interface::PluginSettings {
+            plugin_interface::PluginSettings {
+                requies_explicit_query : false,
+                interested_in_clipboard : true,
+            }
+        }
+
+        fn on_subscribed_event(&mut self, event : &PluginEvent) {
+            match event {
+                PluginEvent::Clipboard(text) => self.events.lock().unwrap().push(text.clone()),
+            }
+        }
+    }
+
     #[test]
     fn special_entry_snow() {
 
-        let ctx = Context::new();
+        let ctx = test_context(Arc::new(FakePlatform::new()));
 
         assert_eq!(special_entry(&ctx,"invalid"), vec![]);
         assert_eq!(special_entry(&ctx,"INC0123"),
@@ -481,4 +896,248 @@ mod model_tests {
         assert_eq!(Some(110), fuzzy_match("c-hoice", "choice"));
         assert_eq!(Some(46), fuzzy_match("cxhxoxixcxex", "choice"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn hotkey_brings_window_to_front() {
+        let platform = Arc::new(FakePlatform::new());
+        let (tx, rx) = mpsc::channel();
+        let tx = Arc::new(std::sync::Mutex::new(tx));
+        connect_platform(platform.clone(), &Config::default(), move |event| tx.lock().unwrap().send(event).unwrap());
+
+        assert_eq!(Some(Config::default().hotkeys.main), platform.registered_hotkey(MAIN_HOTKEY_ID));
+
+        platform.press_hotkey(MAIN_HOTKEY_ID);
+        assert_eq!(PuszInternalEvent::BringToFront(None), rx.recv_timeout(Duration::from_secs(1)).unwrap());
+    }
+
+    #[test]
+    fn main_hotkey_brings_clipboard_along() {
+        let platform = Arc::new(FakePlatform::new());
+        let (tx, rx) = mpsc::channel();
+        let tx = Arc::new(std::sync::Mutex::new(tx));
+        connect_platform(platform.clone(), &Config::default(), move |event| tx.lock().unwrap().send(event).unwrap());
+        platform.copy("copied elsewhere");
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        platform.press_hotkey(MAIN_HOTKEY_ID);
+        assert_eq!(PuszInternalEvent::BringToFront(Some("copied elsewhere".to_owned())), rx.recv_timeout(Duration::from_secs(1)).unwrap());
+    }
+
+    #[test]
+    fn bindings_bring_window_with_query() {
+        let platform = Arc::new(FakePlatform::new());
+        let (tx, rx) = mpsc::channel();
+        let tx = Arc::new(std::sync::Mutex::new(tx));
+        let config = Config::parse(r#"
+            [[hotkeys.bindings]]
+            hotkey = "Ctrl+Shift+V"
+            query = "/clip "
+
+            [[hotkeys.bindings]]
+            hotkey = "Ctrl+Shift+C"
+            query = "/calc "
+        "#).unwrap().config;
+        connect_platform(platform.clone(), &config, move |event| tx.lock().unwrap().send(event).unwrap());
+
+        let calc_id = MAIN_HOTKEY_ID + 2;
+        assert_eq!(Some("Ctrl+Shift+C".parse().unwrap()), platform.registered_hotkey(calc_id));
+
+        platform.press_hotkey(calc_id);
+        assert_eq!(PuszInternalEvent::BringToFront(Some("/calc ".to_owned())), rx.try_recv().unwrap());
+    }
+
+    #[test]
+    fn taken_binding_does_not_stop_the_others() {
+        let platform = Arc::new(FakePlatform::new());
+        platform.occupy("Ctrl+Shift+V".parse().unwrap());
+        let config = Config::parse(r#"
+            [[hotkeys.bindings]]
+            hotkey = "Ctrl+Shift+V"
+            query = "/clip "
+
+            [[hotkeys.bindings]]
+            hotkey = "Ctrl+Shift+C"
+            query = "/calc "
+        "#).unwrap().config;
+        connect_platform(platform.clone(), &config, |_| {});
+
+        assert_eq!(None, platform.registered_hotkey(MAIN_HOTKEY_ID + 1));
+        assert!(platform.registered_hotkey(MAIN_HOTKEY_ID + 2).is_some());
+    }
+
+    #[test]
+    fn clipboard_change_reaches_interested_plugins() {
+        let platform = Arc::new(FakePlatform::new());
+        let (tx, rx) = mpsc::channel();
+        let tx = Arc::new(std::sync::Mutex::new(tx));
+        connect_platform(platform.clone(), &Config::default(), move |event| tx.lock().unwrap().send(event).unwrap());
+
+        platform.copy("copied elsewhere");
+        let event = rx.try_recv().unwrap();
+        assert_eq!(PuszInternalEvent::ClipboardChanged("copied elsewhere".to_owned()), event);
+
+        let plugin = RecordingPlugin::default();
+        let events = plugin.events.clone();
+        let mut ctx = context_with(platform.clone(), hashmap!("recording".to_owned() => Box::new(plugin) as Box<dyn plugin_interface::Plugin>));
+        if let PuszInternalEvent::ClipboardChanged(text) = event {
+            notify_clipboard_changed(&mut ctx, text);
+        }
+        ctx.plugins["recording"].sync();
+
+        assert_eq!(vec!["copied elsewhere".to_owned()], *events.lock().unwrap());
+    }
+
+    #[test]
+    fn plugins_view_lists_failures_with_reason() {
+        let mut ctx = context_with(Arc::new(FakePlatform::new()), hashmap!("recording".to_owned() => Box::new(RecordingPlugin::default()) as Box<dyn plugin_interface::Plugin>));
+        ctx.plugin_failures.push(PluginFailure { source : "plugins/broken.dll".to_owned(), reason : "plugin built for abi version 7, pusz supports 1".to_owned() });
+
+        let labels : Vec<_> = plugin_status_rows(&ctx).into_iter().map(|row| row.main_entry.label).collect();
+        assert_eq!(vec!["recording: loaded".to_owned(), "plugins/broken.dll: failed: plugin built for abi version 7, pusz supports 1".to_owned()], labels);
+
+        ctx.manifests.insert("recording".to_owned(), recording_manifest(""));
+        assert_eq!("recording 0.2.0 by fulara: records things", plugin_status_rows(&ctx)[0].main_entry.label);
+    }
+
+    #[test]
+    fn batches_are_merged_and_reranked() {
+        let mut ctx = test_context(Arc::new(FakePlatform::new()));
+        ctx.query = "choice".to_owned();
+        let rows = |labels : &[&str]| labels.iter().map(|label| PuszRowBuilder::new(label.to_string(), PuszRowIdentifier::new("recording", label.to_string())).build().unwrap()).collect::<Vec<_>>();
+        let labels = |ctx : &Context| ctx.results.iter().map(|row| row.main_entry.label.clone()).collect::<Vec<_>>();
+
+        assert!(merge_answer(&mut ctx, "recording", Answer::Rows(rows(&["unrelated", "cxhxoxixcxex"]))));
+        assert!(merge_answer(&mut ctx, "recording", Answer::Rows(rows(&["choice"]))));
+        assert_eq!(vec!["choice", "cxhxoxixcxex", "unrelated"], labels(&ctx));
+
+        assert!(!merge_answer(&mut ctx, "recording", Answer::Done(PluginResult::Error("gave up".to_owned()))));
+        assert!(merge_answer(&mut ctx, "recording", Answer::Done(PluginResult::Ok(rows(&["c-hoice"])))));
+        assert_eq!(vec!["c-hoice", "choice", "cxhxoxixcxex", "unrelated"], labels(&ctx));
+    }
+
+    #[test]
+    fn action_results_decide_what_the_window_does() {
+        let mut ctx = test_context(Arc::new(FakePlatform::new()));
+        let row = PuszRowBuilder::new("old".to_owned(), PuszRowIdentifier::new("recording", "old".to_owned())).build().unwrap();
+        ctx.results = vec![row.clone()];
+        let generation = ctx.generation.next();
+
+        assert_eq!(AfterAction::Keep, after_action(&mut ctx, "recording", PluginResult::None));
+        assert_eq!(AfterAction::Hide, after_action(&mut ctx, "recording", PluginResult::Close));
+        assert_eq!(vec![row.clone()], ctx.results);
+
+        assert_eq!(AfterAction::Redraw, after_action(&mut ctx, "recording", PluginResult::Error("no disk".to_owned())));
+        assert_eq!("recording: no disk", ctx.results[0].main_entry.label);
+
+        let mut replaced = row.clone();
+        replaced.main_entry.label = "new".to_owned();
+        assert_eq!(AfterAction::Redraw, after_action(&mut ctx, "recording", PluginResult::Ok(vec![replaced.clone()])));
+        assert_eq!(vec![replaced], ctx.results);
+        // answers to the query the list came from are dropped from now on.
+        assert_ne!(generation, ctx.generation.current());
+    }
+
+    fn recording_manifest(capabilities : &str) -> PluginManifest {
+        PluginManifest::parse(&format!(r#"
+            name = "recording"
+            version = "0.2.0"
+            author = "fulara"
+            description = "records things"
+            interface = "^1.0"
+            prefixes = ["rec"]
+            capabilities = [{}]
+        "#, capabilities)).unwrap()
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingPlugin {
+        queries : usize,
+    }
+
+    impl plugin_interface::Plugin for CountingPlugin {
+        fn query(&mut self, _query : &str) -> plugin_interface::PluginResult {
+            self.queries += 1;
+            plugin_interface::PluginResult::None
+        }
+
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        fn save_state(&mut self) -> Option<String> {
+            Some(self.queries.to_string())
+        }
+
+        fn restore_state(&mut self, state : &str) {
+            self.queries = state.parse().unwrap();
+        }
+    }
+
+    #[test]
+    fn swapped_plugin_keeps_state() {
+        let mut old = CountingPlugin::default();
+        old.query("a");
+        old.query("b");
+        let mut ctx = context_with(Arc::new(FakePlatform::new()), hashmap!("recording".to_owned() => Box::new(old) as Box<dyn plugin_interface::Plugin>));
+
+        swap_plugin(&mut ctx, &config::PluginsConfig::default(), recording_manifest(""), Box::new(CountingPlugin::default()), std::path::PathBuf::from("plugins/recording.so"));
+
+        assert_eq!(Some("2".to_owned()), ctx.plugins["recording"].save_state());
+        assert_eq!(Some(&std::path::PathBuf::from("plugins/recording.so")), ctx.plugin_sources.get("recording"));
+    }
+
+    #[test]
+    fn manifest_prefixes_route_commands() {
+        let manifests = hashmap!("recording".to_owned() => recording_manifest(""));
+
+        assert!(handles_command(&manifests, "recording", "rec"));
+        assert!(handles_command(&manifests, "recording", "recording"));
+        assert!(!handles_command(&manifests, "recording", "calc"));
+        assert!(handles_command(&manifests, "calc", "calc"));
+    }
+
+    #[test]
+    fn clipboard_events_need_the_capability() {
+        let plugin = RecordingPlugin::default();
+        let events = plugin.events.clone();
+        let mut ctx = context_with(Arc::new(FakePlatform::new()), hashmap!("recording".to_owned() => Box::new(plugin) as Box<dyn plugin_interface::Plugin>));
+
+        ctx.manifests.insert("recording".to_owned(), recording_manifest(""));
+        notify_clipboard_changed(&mut ctx, "secret".to_owned());
+        ctx.plugins["recording"].sync();
+        assert!(events.lock().unwrap().is_empty());
+
+        ctx.manifests.insert("recording".to_owned(), recording_manifest("'clipboard_events'"));
+        notify_clipboard_changed(&mut ctx, "granted".to_owned());
+        ctx.plugins["recording"].sync();
+        assert_eq!(vec!["granted".to_owned()], *events.lock().unwrap());
+    }
+
+    #[test]
+    fn set_clipboard_action_goes_through_platform() {
+        let platform = Arc::new(FakePlatform::new());
+        let mut ctx = test_context(platform.clone());
+        let entry = PuszEntry { actions : btreemap!(PuszEvent::Click => PuszAction::SetClipboard), label : "label".to_owned(), content : "content".to_owned() };
+
+        assert!(run_action(&PuszAction::SetClipboard, &entry, &PuszRowIdentifier::new("test", "label".to_owned()), &ctx));
+
+        assert_eq!(Ok(Some("content".to_owned())), platform.get_clipboard());
+    }
+
+    #[test]
+    fn paste_action_fills_clipboard_before_asking_the_ui() {
+        let platform = Arc::new(FakePlatform::new());
+        let mut ctx = test_context(platform.clone());
+        let (tx, rx) = mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        ctx.events = Arc::new(move |event| tx.lock().unwrap().send(event).unwrap());
+        let entry = PuszEntry { actions : btreemap!(PuszEvent::KeyPress(KeyChord::Return) => PuszAction::Paste), label : "label".to_owned(), content : "content".to_owned() };
+
+        assert!(run_action(&PuszAction::Paste, &entry, &PuszRowIdentifier::new("test", "label".to_owned()), &ctx));
+
+        assert_eq!(Ok(Some("content".to_owned())), platform.get_clipboard());
+        assert_eq!(PuszInternalEvent::Paste, rx.try_recv().unwrap());
+        // the platform only pastes once the window is hidden.
+        assert_eq!(0, platform.pasted());
+    }
+}
@@ -0,0 +1,406 @@
+use std::sync::Arc;
+use std::str::FromStr;
+use std::fmt;
+
+use serde::{Deserialize, Deserializer};
+
+pub type BindHandler = Arc<dyn Fn(i32) + Send + Sync + 'static>;
+pub type ClipboardHandler = Arc<dyn Fn(String) + Send + Sync + 'static>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Modifiers {
+    pub ctrl : bool,
+    pub alt : bool,
+    pub shift : bool,
+    pub win : bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[allow(unused)]
+pub enum Key {
+    A, B, C, D, E, F, G, H, I, J, K, L, M,
+    N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    Num0, Num1, Num2, Num3, Num4, Num5, Num6, Num7, Num8, Num9,
+    F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    F13, F14, F15, F16, F17, F18, F19, F20, F21, F22, F23, F24,
+    Space, Tab, Return, Escape, Backspace, Delete, Insert,
+    Home, End, PageUp, PageDown, Up, Down, Left, Right,
+    Minus, Equal, LeftBracket, RightBracket, Backslash, Semicolon, Apostrophe, Grave, Comma, Period, Slash,
+}
+
+// first name is the one used when printing, the rest are accepted aliases.
+const KEY_NAMES : &[(Key, &[&str])] = &[
+    (Key::A, &["A"]),
+    (Key::B, &["B"]),
+    (Key::C, &["C"]),
+    (Key::D, &["D"]),
+    (Key::E, &["E"]),
+    (Key::F, &["F"]),
+    (Key::G, &["G"]),
+    (Key::H, &["H"]),
+    (Key::I, &["I"]),
+    (Key::J, &["J"]),
+    (Key::K, &["K"]),
+    (Key::L, &["L"]),
+    (Key::M, &["M"]),
+    (Key::N, &["N"]),
+    (Key::O, &["O"]),
+    (Key::P, &["P"]),
+    (Key::Q, &["Q"]),
+    (Key::R, &["R"]),
+    (Key::S, &["S"]),
+    (Key::T, &["T"]),
+    (Key::U, &["U"]),
+    (Key::V, &["V"]),
+    (Key::W, &["W"]),
+    (Key::X, &["X"]),
+    (Key::Y, &["Y"]),
+    (Key::Z, &["Z"]),
+    (Key::Num0, &["0"]),
+    (Key::Num1, &["1"]),
+    (Key::Num2, &["2"]),
+    (Key::Num3, &["3"]),
+    (Key::Num4, &["4"]),
+    (Key::Num5, &["5"]),
+    (Key::Num6, &["6"]),
+    (Key::Num7, &["7"]),
+    (Key::Num8, &["8"]),
+    (Key::Num9, &["9"]),
+    (Key::F1, &["F1"]),
+    (Key::F2, &["F2"]),
+    (Key::F3, &["F3"]),
+    (Key::F4, &["F4"]),
+    (Key::F5, &["F5"]),
+    (Key::F6, &["F6"]),
+    (Key::F7, &["F7"]),
+    (Key::F8, &["F8"]),
+    (Key::F9, &["F9"]),
+    (Key::F10, &["F10"]),
+    (Key::F11, &["F11"]),
+    (Key::F12, &["F12"]),
+    (Key::F13, &["F13"]),
+    (Key::F14, &["F14"]),
+    (Key::F15, &["F15"]),
+    (Key::F16, &["F16"]),
+    (Key::F17, &["F17"]),
+    (Key::F18, &["F18"]),
+    (Key::F19, &["F19"]),
+    (Key::F20, &["F20"]),
+    (Key::F21, &["F21"]),
+    (Key::F22, &["F22"]),
+    (Key::F23, &["F23"]),
+    (Key::F24, &["F24"]),
+    (Key::Space, &["Space"]),
+    (Key::Tab, &["Tab"]),
+    (Key::Return, &["Return", "Enter"]),
+    (Key::Escape, &["Escape", "Esc"]),
+    (Key::Backspace, &["Backspace"]),
+    (Key::Delete, &["Delete", "Del"]),
+    (Key::Insert, &["Insert", "Ins"]),
+    (Key::Home, &["Home"]),
+    (Key::End, &["End"]),
+    (Key::PageUp, &["PageUp", "PgUp"]),
+    (Key::PageDown, &["PageDown", "PgDn"]),
+    (Key::Up, &["Up"]),
+    (Key::Down, &["Down"]),
+    (Key::Left, &["Left"]),
+    (Key::Right, &["Right"]),
+    (Key::Minus, &["-", "Minus"]),
+    (Key::Equal, &["=", "Equal"]),
+    (Key::LeftBracket, &["[", "LeftBracket"]),
+    (Key::RightBracket, &["]", "RightBracket"]),
+    (Key::Backslash, &["\\", "Backslash"]),
+    (Key::Semicolon, &[";", "Semicolon"]),
+    (Key::Apostrophe, &["'", "Apostrophe", "Quote"]),
+    (Key::Grave, &["`", "Grave", "Backtick"]),
+    (Key::Comma, &[",", "Comma"]),
+    (Key::Period, &[".", "Period"]),
+    (Key::Slash, &["/", "Slash"]),
+];
+
+impl Key {
+    pub fn name(self) -> &'static str {
+        KEY_NAMES.iter().find(|(key, _)| *key == self).map(|(_, names)| names[0]).expect("every key has a name")
+    }
+
+    fn from_name(name : &str) -> Option<Key> {
+        KEY_NAMES.iter().find(|(_, names)| names.iter().any(|n| n.eq_ignore_ascii_case(name))).map(|(key, _)| *key)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Hotkey {
+    pub key : Key,
+    pub modifiers : Modifiers,
+}
+
+impl Hotkey {
+    pub fn new(key : Key, modifiers : Modifiers) -> Self {
+        Self {
+            key,
+            modifiers,
+        }
+    }
+}
+
+// "Ctrl+Alt+Space": any number of modifiers followed by exactly one key, case does not matter.
+impl FromStr for Hotkey {
+    type Err = String;
+
+    fn from_str(text : &str) -> Result<Self, Self::Err> {
+        let mut parts : Vec<&str> = text.split('+').map(|part| part.trim()).collect();
+        let key_name = parts.pop().unwrap_or_default();
+
+        let mut modifiers = Modifiers::default();
+        for part in parts {
+            match part.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => modifiers.ctrl = true,
+                "alt" => modifiers.alt = true,
+                "shift" => modifiers.shift = true,
+                "win" | "super" | "meta" | "cmd" => modifiers.win = true,
+                _ => return Err(format!("unknown modifier '{}' in hotkey '{}'", part, text)),
+            }
+        }
+
+        match Key::from_name(key_name) {
+            Some(key) => Ok(Hotkey::new(key, modifiers)),
+            None if key_name.is_empty() => Err(format!("hotkey '{}' has no key", text)),
+            None => Err(format!("unknown key '{}' in hotkey '{}'", key_name, text)),
+        }
+    }
+}
+
+impl fmt::Display for Hotkey {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let modifiers = [(self.modifiers.ctrl, "Ctrl"), (self.modifiers.alt, "Alt"), (self.modifiers.shift, "Shift"), (self.modifiers.win, "Win")];
+        for (_, name) in modifiers.iter().filter(|(pressed, _)| *pressed) {
+            write!(f, "{}+", name)?;
+        }
+
+        write!(f, "{}", self.key.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Hotkey {
+    fn deserialize<D : Deserializer<'de>>(deserializer : D) -> Result<Self, D::Error> {
+        crate::config::deserialize_parsed(deserializer, "a hotkey like Ctrl+Alt+Space", |text| text.parse())
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum PlatformError {
+    HotkeyTaken(Hotkey),
+    UnsupportedKey(Key),
+    // the desktop has no way to do it at all.
+    Unsupported(String),
+    Failure(String),
+}
+
+impl fmt::Display for PlatformError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlatformError::HotkeyTaken(hotkey) => write!(f, "{} is already registered by another application", hotkey),
+            PlatformError::UnsupportedKey(key) => write!(f, "key {} is not available on this keyboard layout", key.name()),
+            PlatformError::Unsupported(reason) => write!(f, "{}", reason),
+            PlatformError::Failure(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// everything the ui needs from the operating system, implemented once per desktop.
+pub trait Platform : Send + Sync {
+    fn register_hotkey(&self, id : i32, hotkey : Hotkey, handler : BindHandler) -> Result<(), PlatformError>;
+    fn unregister_hotkey(&self, id : i32);
+
+    fn add_clipboard_listener(&self, handler : ClipboardHandler) -> Result<(), PlatformError>;
+    // None when there is no text in the clipboard or its owner didnt hand it over in time.
+    fn get_clipboard(&self) -> Result<Option<String>, PlatformError>;
+    fn set_clipboard(&self, text : &str) -> Result<(), PlatformError>;
+
+    // invoked right before the window is presented, some desktops need to be asked nicely.
+    fn bring_to_front(&self);
+
+    // focuses the window that had the focus when a hotkey last fired and presses the paste shortcut there.
+    // pusz is hidden by then, so the ui only logs the error.
+    fn paste(&self) -> Result<(), PlatformError>;
+}
+
+// runs before gtk starts, platforms sharing gtk's connection to the desktop set themselves up for it here.
+#[cfg(windows)]
+pub fn init() {}
+
+#[cfg(target_os = "linux")]
+pub fn init() {
+    crate::x11_stuff::init_threads();
+}
+
+#[cfg(windows)]
+pub fn native() -> Arc<dyn Platform> {
+    Arc::new(crate::winapi_stuff::WindowsPlatform)
+}
+
+// on wayland sessions x11 only sees clipboard of XWayland apps, prefer wlr-data-control when compositor has it.
+#[cfg(target_os = "linux")]
+pub fn native() -> Arc<dyn Platform> {
+    if ::std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        match crate::wayland_stuff::WaylandPlatform::connect() {
+            Ok(platform) => return Arc::new(platform),
+            Err(err) => warn!("wayland clipboard unavailable, falling back to x11: {}", err),
+        }
+    }
+
+    Arc::new(crate::x11_stuff::X11Platform)
+}
+
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+    use std::sync::Mutex;
+    use std::collections::{HashMap, HashSet};
+
+    #[derive(Default)]
+    struct FakeState {
+        hotkeys : HashMap<i32, (Hotkey, BindHandler)>,
+        // hotkeys grabbed by other applications.
+        occupied : HashSet<Hotkey>,
+        clipboard : Option<String>,
+        clipboard_listeners : Vec<ClipboardHandler>,
+        brought_to_front : usize,
+        pasted : usize,
+    }
+
+    // in-memory desktop: tests drive it by pressing hotkeys and copying text.
+    #[derive(Default)]
+    pub struct FakePlatform {
+        state : Mutex<FakeState>,
+    }
+
+    impl FakePlatform {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn press_hotkey(&self, id : i32) {
+            let handler = self.state.lock().unwrap().hotkeys.get(&id).map(|(_, handler)| handler.clone());
+            if let Some(handler) = handler {
+                handler(id);
+            }
+        }
+
+        // another application put text into the clipboard.
+        pub fn copy(&self, text : &str) {
+            let listeners = {
+                let state = &mut self.state.lock().unwrap();
+                state.clipboard = Some(text.to_owned());
+                state.clipboard_listeners.clone()
+            };
+
+            for listener in listeners {
+                listener(text.to_owned());
+            }
+        }
+
+        pub fn registered_hotkey(&self, id : i32) -> Option<Hotkey> {
+            self.state.lock().unwrap().hotkeys.get(&id).map(|(hotkey, _)| *hotkey)
+        }
+
+        pub fn occupy(&self, hotkey : Hotkey) {
+            self.state.lock().unwrap().occupied.insert(hotkey);
+        }
+
+        pub fn brought_to_front(&self) -> usize {
+            self.state.lock().unwrap().brought_to_front
+        }
+
+        pub fn pasted(&self) -> usize {
+            self.state.lock().unwrap().pasted
+        }
+    }
+
+    impl Platform for FakePlatform {
+        fn register_hotkey(&self, id : i32, hotkey : Hotkey, handler : BindHandler) -> Result<(), PlatformError> {
+            let state = &mut self.state.lock().unwrap();
+            if state.occupied.contains(&hotkey) {
+                return Err(PlatformError::HotkeyTaken(hotkey));
+            }
+
+            state.hotkeys.insert(id, (hotkey, handler));
+            Ok(())
+        }
+
+        fn unregister_hotkey(&self, id : i32) {
+            self.state.lock().unwrap().hotkeys.remove(&id);
+        }
+
+        fn add_clipboard_listener(&self, handler : ClipboardHandler) -> Result<(), PlatformError> {
+            self.state.lock().unwrap().clipboard_listeners.push(handler);
+            Ok(())
+        }
+
+        fn get_clipboard(&self) -> Result<Option<String>, PlatformError> {
+            Ok(self.state.lock().unwrap().clipboard.clone())
+        }
+
+        // like the real backends, setting the clipboard ourselves does not notify listeners.
+        fn set_clipboard(&self, text : &str) -> Result<(), PlatformError> {
+            self.state.lock().unwrap().clipboard = Some(text.to_owned());
+            Ok(())
+        }
+
+        fn bring_to_front(&self) {
+            self.state.lock().unwrap().brought_to_front += 1;
+        }
+
+        fn paste(&self) -> Result<(), PlatformError> {
+            self.state.lock().unwrap().pasted += 1;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hotkey(key : Key, ctrl : bool, alt : bool, shift : bool, win : bool) -> Hotkey {
+        Hotkey::new(key, Modifiers { ctrl, alt, shift, win })
+    }
+
+    #[test]
+    fn parse_hotkeys() {
+        assert_eq!(Ok(hotkey(Key::F1, false, false, false, false)), "F1".parse());
+        assert_eq!(Ok(hotkey(Key::Space, true, true, false, false)), "Ctrl+Alt+Space".parse());
+        assert_eq!(Ok(hotkey(Key::V, true, false, true, false)), "ctrl + shift + v".parse());
+        assert_eq!(Ok(hotkey(Key::Num5, false, false, false, true)), "Super+5".parse());
+        assert_eq!(Ok(hotkey(Key::F24, false, true, false, false)), "Alt+F24".parse());
+        assert_eq!(Ok(hotkey(Key::Slash, true, false, false, false)), "Ctrl+/".parse());
+        assert_eq!(Ok(hotkey(Key::PageDown, false, false, true, false)), "Shift+PgDn".parse());
+    }
+
+    #[test]
+    fn parse_hotkey_errors() {
+        assert_eq!(Err::<Hotkey, _>("unknown key 'F25' in hotkey 'Ctrl+F25'".to_owned()), "Ctrl+F25".parse());
+        assert_eq!(Err::<Hotkey, _>("unknown modifier 'Hyper' in hotkey 'Hyper+A'".to_owned()), "Hyper+A".parse());
+        assert_eq!(Err::<Hotkey, _>("hotkey 'Ctrl+' has no key".to_owned()), "Ctrl+".parse());
+        assert_eq!(Err::<Hotkey, _>("hotkey '' has no key".to_owned()), "".parse());
+    }
+
+    #[test]
+    fn display_roundtrips() {
+        for text in &["F1", "Ctrl+Alt+Space", "Ctrl+Shift+V", "Win+`", "Alt+Shift+PageUp"] {
+            let hotkey : Hotkey = text.parse().unwrap();
+            assert_eq!(*text, hotkey.to_string());
+        }
+    }
+
+    #[test]
+    fn taken_hotkey_is_reported() {
+        let platform = fake::FakePlatform::new();
+        let taken : Hotkey = "Ctrl+Alt+Space".parse().unwrap();
+        platform.occupy(taken);
+
+        let result = platform.register_hotkey(1, taken, Arc::new(|_| {}));
+        assert_eq!(Err(PlatformError::HotkeyTaken(taken)), result);
+        assert_eq!("Ctrl+Alt+Space is already registered by another application", result.unwrap_err().to_string());
+    }
+}
@@ -0,0 +1,210 @@
+// the services behind every plugin's HostContext.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use plugin_interface::host::{Host, HostCall, HostContext, HostReply, LogLevel};
+
+use crate::kv_store::KvStore;
+use crate::platform::Platform;
+
+const STORE_FILE : &str = "store.redb";
+
+// what a plugin asked of the ui, handled on the ui thread.
+#[derive(PartialEq, Debug)]
+pub enum HostEvent {
+    Toast { plugin : String, message : String },
+    Refresh { plugin : String },
+}
+
+pub type HostEvents = Arc<dyn Fn(HostEvent) + Send + Sync>;
+
+// 1 error .. 5 trace, what wasm modules pass.
+pub fn log_level(level : i32) -> log::Level {
+    match level {
+        1 => log::Level::Error,
+        2 => log::Level::Warn,
+        3 => log::Level::Info,
+        4 => log::Level::Debug,
+        _ => log::Level::Trace,
+    }
+}
+
+fn to_log_level(level : LogLevel) -> log::Level {
+    match level {
+        LogLevel::Error => log::Level::Error,
+        LogLevel::Warn => log::Level::Warn,
+        LogLevel::Info => log::Level::Info,
+        LogLevel::Debug => log::Level::Debug,
+        LogLevel::Trace => log::Level::Trace,
+    }
+}
+
+// hands out the HostContext of each plugin, all of them share the platform and the ui.
+#[derive(Clone)]
+pub struct PluginHosts {
+    platform : Arc<dyn Platform>,
+    data_root : PathBuf,
+    events : HostEvents,
+    // opened on first use and kept, a reloaded plugin gets the same store back.
+    stores : Arc<Mutex<HashMap<String, Arc<KvStore>>>>,
+}
+
+impl PluginHosts {
+    pub fn new(platform : Arc<dyn Platform>, data_root : PathBuf, events : HostEvents) -> Self {
+        Self {
+            platform,
+            data_root,
+            events,
+            stores : Arc::default(),
+        }
+    }
+
+    pub fn for_plugin(&self, plugin : &str) -> HostContext {
+        HostContext::new(Arc::new(PluginHost {
+            plugin : plugin.to_owned(),
+            platform : self.platform.clone(),
+            data_dir : self.data_root.join(plugin),
+            events : self.events.clone(),
+            stores : self.stores.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+impl PluginHosts {
+    // fake desktop and nobody listening to the ui events.
+    pub fn fake(data_root : &Path) -> Self {
+        Self::new(Arc::new(crate::platform::fake::FakePlatform::new()), data_root.to_owned(), Arc::new(|_| {}))
+    }
+}
+
+struct PluginHost {
+    plugin : String,
+    platform : Arc<dyn Platform>,
+    data_dir : PathBuf,
+    events : HostEvents,
+    stores : Arc<Mutex<HashMap<String, Arc<KvStore>>>>,
+}
+
+impl PluginHost {
+    // plain file names only, a plugin stays inside its own data dir.
+    fn data_file(&self, file : &str) -> Result<PathBuf, String> {
+        let mut components = Path::new(file).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(self.data_dir.join(file)),
+            _ => Err(format!("{} is not a file name", file)),
+        }
+    }
+
+    fn read_data(&self, file : &str) -> Result<Option<Vec<u8>>, String> {
+        let path = self.data_file(file)?;
+        match fs::read(&path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(format!("{}: {}", path.display(), err)),
+        }
+    }
+
+    fn write_data(&self, file : &str, contents : &[u8]) -> Result<(), String> {
+        let path = self.data_file(file)?;
+        fs::create_dir_all(&self.data_dir).map_err(|e| format!("{}: {}", self.data_dir.display(), e))?;
+        fs::write(&path, contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn store(&self) -> Result<Arc<KvStore>, String> {
+        let mut stores = self.stores.lock().unwrap();
+        if let Some(store) = stores.get(&self.plugin) {
+            return Ok(store.clone());
+        }
+
+        fs::create_dir_all(&self.data_dir).map_err(|e| format!("{}: {}", self.data_dir.display(), e))?;
+        let store = Arc::new(KvStore::open(&self.data_dir.join(STORE_FILE))?);
+        stores.insert(self.plugin.clone(), store.clone());
+        Ok(store)
+    }
+}
+
+impl Host for PluginHost {
+    fn call(&self, call : HostCall) -> Result<HostReply, String> {
+        match call {
+            HostCall::SetClipboard(text) => self.platform.set_clipboard(&text).map_err(|e| e.to_string())?,
+            HostCall::GetClipboard => return self.platform.get_clipboard().map(HostReply::Clipboard).map_err(|e| e.to_string()),
+            HostCall::OpenUrl(link) => {
+                url::Url::parse(&link).map_err(|e| format!("{}: {}", link, e))?;
+                webbrowser::open(&link).map_err(|e| format!("couldnt open {}: {}", link, e))?;
+            },
+            HostCall::ShowToast(message) => (self.events)(HostEvent::Toast { plugin : self.plugin.clone(), message }),
+            HostCall::Log { level, message } => log!(to_log_level(level), "{}: {}", self.plugin, message),
+            HostCall::RefreshResults => (self.events)(HostEvent::Refresh { plugin : self.plugin.clone() }),
+            HostCall::ReadData(file) => return self.read_data(&file).map(HostReply::Data),
+            HostCall::WriteData { file, contents } => self.write_data(&file, &contents)?,
+            HostCall::KvGet(key) => return self.store()?.get(&key).map(HostReply::Data),
+            HostCall::KvScan(prefix) => return self.store()?.scan(&prefix).map(HostReply::Entries),
+            HostCall::KvWrite(ops) => self.store()?.write(&ops)?,
+        }
+        Ok(HostReply::Done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Mutex};
+    use crate::platform::fake::FakePlatform;
+
+    fn hosts(data_root : &Path) -> (PluginHosts, mpsc::Receiver<HostEvent>) {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        (PluginHosts::new(Arc::new(FakePlatform::new()), data_root.to_owned(), Arc::new(move |event| tx.lock().unwrap().send(event).unwrap())), rx)
+    }
+
+    #[test]
+    fn plugins_reach_clipboard_and_ui() {
+        let (hosts, events) = hosts(Path::new("data"));
+        let host = hosts.for_plugin("clip");
+
+        host.set_clipboard("copied").unwrap();
+        assert_eq!(Some("copied".to_owned()), host.clipboard().unwrap());
+
+        host.show_toast("saved").unwrap();
+        host.refresh_results().unwrap();
+        assert_eq!(vec![HostEvent::Toast { plugin : "clip".to_owned(), message : "saved".to_owned() }, HostEvent::Refresh { plugin : "clip".to_owned() }], events.try_iter().collect::<Vec<_>>());
+
+        assert_eq!(Ok(()), host.log(LogLevel::Warn, "careful"));
+        assert!(host.open_url("not a url").is_err());
+    }
+
+    #[test]
+    fn data_stays_in_the_plugin_dir() {
+        let root = std::env::temp_dir().join(format!("pusz_plugin_host_tests_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (hosts, _events) = hosts(&root);
+        let host = hosts.for_plugin("store");
+
+        assert_eq!(None, host.read_data("notes.txt").unwrap());
+        host.write_data("notes.txt", b"milk").unwrap();
+        assert_eq!(Some(b"milk".to_vec()), host.read_data("notes.txt").unwrap());
+        assert_eq!(b"milk".to_vec(), fs::read(root.join("store").join("notes.txt")).unwrap());
+
+        assert_eq!(Err("../clip/clips.toml is not a file name".to_owned()), host.read_data("../clip/clips.toml"));
+        assert!(host.write_data("/etc/passwd", b"").is_err());
+    }
+
+    #[test]
+    fn reloaded_plugins_get_their_store_back() {
+        let root = std::env::temp_dir().join(format!("pusz_plugin_host_store_tests_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (hosts, _events) = hosts(&root);
+
+        let host = hosts.for_plugin("clip");
+        host.kv_put("clips/a", b"1").unwrap();
+        // the old instance is still alive while the new one loads.
+        let reloaded = hosts.for_plugin("clip");
+        assert_eq!(Some(b"1".to_vec()), reloaded.kv_get("clips/a").unwrap());
+        assert_eq!(None, hosts.for_plugin("calc").kv_get("clips/a").unwrap());
+        assert!(root.join("clip").join(STORE_FILE).exists());
+    }
+}
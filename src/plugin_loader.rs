@@ -0,0 +1,402 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use plugin_interface::{Plugin, PluginContext};
+use plugin_interface::manifest::PluginManifest;
+
+use crate::config::PluginsConfig;
+use crate::plugin_host::PluginHosts;
+use crate::process_plugin::ProcessPlugin;
+use crate::wasm_plugin::WasmPlugin;
+use crate::script_plugin::{self, ScriptPlugin};
+
+// plugin library that couldnt be loaded, listed by /plugins.
+#[derive(PartialEq, Debug, Clone)]
+pub struct PluginFailure {
+    pub source : String,
+    pub reason : String,
+}
+
+fn is_wasm_module(path : &Path) -> bool {
+    path.extension().map_or(false, |extension| extension == plugin_interface::wasm::EXTENSION)
+}
+
+fn is_script(path : &Path) -> bool {
+    path.extension().map_or(false, |extension| extension == script_plugin::EXTENSION)
+}
+
+fn is_plugin_library(path : &Path) -> bool {
+    path.is_file() && (is_wasm_module(path) || is_script(path) || path.extension().map_or(false, |extension| extension == std::env::consts::DLL_EXTENSION))
+}
+
+// .so/.dylib/.dll files (whatever this platform uses), .wasm modules and .rhai scripts from all search paths, in search path order and each file once.
+pub fn discover(search_paths : &[PathBuf]) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut libraries = vec![];
+
+    for search_path in search_paths {
+        let entries = match fs::read_dir(search_path) {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                debug!("plugins dir {} doesnt exist", search_path.display());
+                continue;
+            },
+            Err(err) => {
+                warn!("couldnt read plugins dir {}: {}", search_path.display(), err);
+                continue;
+            }
+        };
+
+        let mut found : Vec<_> = entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|path| is_plugin_library(path)).collect();
+        // read_dir order is arbitrary, keep the pick between duplicates stable.
+        found.sort();
+
+        for path in found {
+            let key = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+            if seen.insert(key) {
+                libraries.push(path);
+            }
+        }
+    }
+
+    libraries
+}
+
+fn shadow_root() -> PathBuf {
+    std::env::temp_dir().join("pusz-shadow")
+}
+
+fn shadow_dir() -> PathBuf {
+    shadow_root().join(std::process::id().to_string())
+}
+
+// signal 0 only checks the pid, EPERM means it exists but belongs to someone else.
+#[cfg(unix)]
+fn is_running(pid : u32) -> bool {
+    use nix::errno::Errno;
+    use nix::sys::signal::kill;
+    use nix::unistd::Pid;
+
+    match kill(Pid::from_raw(pid as i32), None) {
+        Ok(()) | Err(Errno::EPERM) => true,
+        Err(_) => false,
+    }
+}
+
+#[cfg(windows)]
+fn is_running(pid : u32) -> bool {
+    use winapi::shared::minwindef::{DWORD, FALSE};
+    use winapi::shared::winerror::ERROR_ACCESS_DENIED;
+    use winapi::um::errhandlingapi::GetLastError;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::minwinbase::STILL_ACTIVE;
+    use winapi::um::processthreadsapi::{GetExitCodeProcess, OpenProcess};
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid);
+        if process.is_null() {
+            // processes of other users cant be opened, they are running though.
+            return GetLastError() == ERROR_ACCESS_DENIED;
+        }
+
+        // a handle outlives the process, the exit code says whether it is gone.
+        let mut code : DWORD = 0;
+        let running = GetExitCodeProcess(process, &mut code) == 0 || code == STILL_ACTIVE;
+        CloseHandle(process);
+        running
+    }
+}
+
+// cant tell elsewhere, the copies stay until the temp dir is cleaned.
+#[cfg(not(any(unix, windows)))]
+fn is_running(_pid : u32) -> bool {
+    true
+}
+
+// copies left behind by earlier runs, other instances still have theirs loaded.
+fn remove_stale_shadows(root : &Path) {
+    let _ = fs::remove_dir_all(shadow_dir());
+
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let pid = entry.file_name().to_str().and_then(|name| name.parse().ok());
+        if pid.map_or(false, |pid| !is_running(pid)) {
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+// libraries are loaded from a copy so a rebuild can overwrite the original while pusz runs.
+struct ShadowLibrary {
+    library : Option<libloading::Library>,
+    copy : PathBuf,
+}
+
+impl ShadowLibrary {
+    fn open(path : &Path) -> Result<Self, String> {
+        static COPIES : AtomicUsize = AtomicUsize::new(0);
+
+        let file_name = path.file_name().ok_or_else(|| format!("{} is not a file", path.display()))?;
+        let dir = shadow_dir();
+        fs::create_dir_all(&dir).map_err(|e| format!("couldnt create {}: {}", dir.display(), e))?;
+
+        // every load gets its own name, the old build may still be loaded while the new one is.
+        let copy = dir.join(format!("{}-{}", COPIES.fetch_add(1, Ordering::SeqCst), file_name.to_string_lossy()));
+        fs::copy(path, &copy).map_err(|e| format!("couldnt copy to {}: {}", copy.display(), e))?;
+
+        match libloading::Library::new(&copy) {
+            Ok(library) => Ok(Self { library : Some(library), copy }),
+            Err(err) => {
+                let _ = fs::remove_file(&copy);
+                Err(err.to_string())
+            }
+        }
+    }
+}
+
+impl Drop for ShadowLibrary {
+    fn drop(&mut self) {
+        drop(self.library.take());
+        let _ = fs::remove_file(&self.copy);
+    }
+}
+
+unsafe fn load_plugin(path : &Path, context : &PluginContext) -> Result<Box<dyn Plugin>, String> {
+    let shadow = ShadowLibrary::open(path)?;
+    let plugin = {
+        let library = shadow.library.as_ref().expect("open until dropped");
+        let declaration: libloading::Symbol<*const plugin_interface::ffi::PluginDeclaration> = library.get(plugin_interface::ffi::DECLARATION_SYMBOL)
+            .map_err(|e| format!("not a pusz plugin: {}", e))?;
+        plugin_interface::ffi::PluginProxy::load(*declaration, context)
+    };
+
+    // the library goes away together with the plugin.
+    plugin.map(|plugin| Box::new(plugin.owning(shadow)) as Box<dyn Plugin>).map_err(|e| e.to_string())
+}
+
+// what is known about a library before loading it.
+struct Candidate {
+    manifest : PluginManifest,
+    // scripts are compiled to read their manifest, kept so they dont have to be again.
+    script : Option<ScriptPlugin>,
+}
+
+fn read_manifest(path : &Path) -> Result<Candidate, String> {
+    // scripts describe themselves.
+    if is_script(path) {
+        return ScriptPlugin::load(path).map(|(manifest, script)| Candidate { manifest, script : Some(script) });
+    }
+
+    let manifest = PluginManifest::load(&PluginManifest::find_for(path))?;
+    manifest.check_interface()?;
+    Ok(Candidate { manifest, script : None })
+}
+
+type LoadedLibrary = (PluginManifest, Box<dyn Plugin>);
+
+// a single library, Ok(None) when the manifest says it is disabled.
+pub fn load_library(config : &PluginsConfig, hosts : &PluginHosts, path : &Path) -> Result<Option<LoadedLibrary>, String> {
+    load_candidate(config, hosts, path, read_manifest(path)?)
+}
+
+fn load_candidate(config : &PluginsConfig, hosts : &PluginHosts, path : &Path, candidate : Candidate) -> Result<Option<LoadedLibrary>, String> {
+    let Candidate { manifest, script } = candidate;
+    if !config.is_enabled(&manifest.name) {
+        info!("plugin {} from {} is disabled", manifest.name, path.display());
+        return Ok(None);
+    }
+
+    let context = PluginContext::new(config.settings.clone(), config.data_dir.clone()).with_host(hosts.for_plugin(&manifest.name));
+    let plugin : Box<dyn Plugin> = if let Some(script) = script {
+        Box::new(script.with_host(context.host().clone()))
+    } else if is_wasm_module(path) {
+        Box::new(WasmPlugin::load(path, &manifest, &context)?)
+    } else {
+        unsafe { load_plugin(path, &context) }?
+    };
+    if plugin.name() != manifest.name {
+        return Err(format!("manifest is for {} but the library provides {}", manifest.name, plugin.name()));
+    }
+
+    Ok(Some((manifest, plugin)))
+}
+
+#[derive(Default)]
+pub struct LoadedPlugins {
+    pub plugins : HashMap<String, Box<dyn Plugin>>,
+    pub manifests : HashMap<String, PluginManifest>,
+    // library each plugin came from.
+    pub sources : HashMap<String, PathBuf>,
+    pub failures : Vec<PluginFailure>,
+}
+
+// when two libraries provide the same plugin the one from the earlier search path wins.
+pub fn load_plugins(config : &PluginsConfig, hosts : &PluginHosts) -> LoadedPlugins {
+    remove_stale_shadows(&shadow_root());
+
+    let mut loaded = LoadedPlugins::default();
+    for path in discover(&config.search_paths) {
+        // checked up front so a duplicate is never loaded.
+        let loaded_plugin = read_manifest(&path).and_then(|candidate| {
+            match loaded.sources.get(&candidate.manifest.name) {
+                Some(source) => {
+                    info!("ignoring plugin {} from {}, already loaded from {}", candidate.manifest.name, path.display(), source.display());
+                    Ok(None)
+                },
+                None => load_candidate(config, hosts, &path, candidate),
+            }
+        });
+
+        match loaded_plugin {
+            Ok(Some((manifest, plugin))) => {
+                info!("loaded plugin {} {} from {}", manifest.name, manifest.version, path.display());
+                loaded.sources.insert(manifest.name.clone(), path);
+                loaded.plugins.insert(manifest.name.clone(), plugin);
+                loaded.manifests.insert(manifest.name.clone(), manifest);
+            },
+            Ok(None) => {},
+            Err(reason) => {
+                error!("plugin {} failed to load: {}", path.display(), reason);
+                loaded.failures.push(PluginFailure { source : path.display().to_string(), reason });
+            }
+        }
+    }
+
+    for process in &config.processes {
+        if loaded.plugins.contains_key(&process.name) {
+            info!("ignoring plugin process {}, a library already provides it", process.name);
+            continue;
+        }
+        if !config.is_enabled(&process.name) {
+            info!("plugin process {} is disabled", process.name);
+            continue;
+        }
+
+        let context = PluginContext::new(config.settings.clone(), config.data_dir.clone());
+        let spawned = context.data_dir(&process.name).map_err(|e| e.to_string())
+            .and_then(|data_dir| ProcessPlugin::spawn(process, config.settings.get(&process.name), &data_dir));
+        match spawned {
+            Ok(plugin) => {
+                info!("started plugin process {}: {:?}", process.name, process.command);
+                loaded.plugins.insert(process.name.clone(), Box::new(plugin));
+            },
+            Err(reason) => {
+                error!("plugin process {} failed to start: {}", process.name, reason);
+                loaded.failures.push(PluginFailure { source : process.command.join(" "), reason });
+            }
+        }
+    }
+
+    loaded
+}
+
+// calls `on_change` with every script created or rewritten in the search paths, and with libraries and
+// wasm modules too when `libraries` is set.
+pub fn watch<F>(search_paths : &[PathBuf], libraries : bool, on_change : F) where F : Fn(PathBuf) + Send + 'static {
+    use notify::{DebouncedEvent, RecursiveMode, Watcher};
+
+    let (tx, rx) = mpsc::channel();
+    // builds write the library in several steps, only look once it settles.
+    let mut watcher = match notify::watcher(tx, Duration::from_millis(500)) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            error!("couldnt watch plugin dirs: {}", err);
+            return;
+        }
+    };
+
+    for search_path in search_paths.iter().filter(|path| path.is_dir()) {
+        if let Err(err) = watcher.watch(search_path, RecursiveMode::NonRecursive) {
+            warn!("couldnt watch plugins dir {}: {}", search_path.display(), err);
+        }
+    }
+
+    std::thread::spawn(move || {
+        let _watcher = watcher;
+        for event in rx {
+            match event {
+                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Rename(_, path) if is_plugin_library(&path) && (libraries || is_script(&path)) => on_change(path),
+                DebouncedEvent::Error(err, path) => warn!("plugin watcher error {:?}: {}", path, err),
+                _ => {},
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library(dir : &Path, stem : &str) -> PathBuf {
+        let path = dir.join(stem).with_extension(std::env::consts::DLL_EXTENSION);
+        fs::write(&path, b"").unwrap();
+        path
+    }
+
+    #[test]
+    fn manifest_problems_are_failures_without_loading() {
+        let dir = std::env::temp_dir().join(format!("pusz_plugin_manifest_tests_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // both libraries are garbage, loading either would fail differently.
+        let missing = library(&dir, "missing_plugin");
+        let future = library(&dir, "future_plugin");
+        fs::write(PluginManifest::path_for(&future), "name = 'future'\nversion = '2.0.0'\ninterface = '^9.0'").unwrap();
+
+        let config = PluginsConfig { search_paths : vec![dir.clone()], ..PluginsConfig::default() };
+        let loaded = load_plugins(&config, &PluginHosts::fake(&config.data_dir));
+
+        assert!(loaded.plugins.is_empty());
+        let failures : HashMap<_, _> = loaded.failures.into_iter().map(|failure| (failure.source, failure.reason)).collect();
+        assert!(failures[&missing.display().to_string()].starts_with("couldnt read manifest"), "{:?}", failures);
+        assert_eq!(format!("needs interface ^9.0, pusz provides {}", plugin_interface::COMMON_INTERFACE_VERSION), failures[&future.display().to_string()]);
+    }
+
+    #[test]
+    fn only_shadows_of_finished_processes_are_removed() {
+        let root = std::env::temp_dir().join(format!("pusz_plugin_shadow_tests_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        // pids never get this high.
+        let (running, finished, unrelated) = (root.join(std::process::id().to_string()), root.join("999999999"), root.join("keep"));
+        for dir in &[&running, &finished, &unrelated] {
+            fs::create_dir_all(dir).unwrap();
+        }
+
+        remove_stale_shadows(&root);
+
+        assert!(running.exists() && unrelated.exists());
+        assert!(!finished.exists());
+    }
+
+    #[test]
+    fn discovers_platform_libraries_once_in_search_order() {
+        let root = std::env::temp_dir().join(format!("pusz_plugin_discovery_tests_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (user, system) = (root.join("user"), root.join("system"));
+        fs::create_dir_all(&user).unwrap();
+        fs::create_dir_all(&system).unwrap();
+
+        let user_calc = library(&user, "calc_plugin");
+        let system_calc = library(&system, "calc_plugin");
+        let system_clip = library(&system, "clipboard_plugin");
+        let system_wasm = system.join("weather.wasm");
+        fs::write(&system_wasm, b"").unwrap();
+        let user_script = user.join("upper.rhai");
+        fs::write(&user_script, b"").unwrap();
+        fs::write(system.join("readme.txt"), b"").unwrap();
+        fs::create_dir_all(system.join("nested").with_extension(std::env::consts::DLL_EXTENSION)).unwrap();
+
+        let search_paths = vec![user.clone(), system.clone(), root.join("missing"), user.clone()];
+        assert_eq!(vec![user_calc, user_script, system_calc, system_clip, system_wasm], discover(&search_paths));
+    }
+}
@@ -0,0 +1,430 @@
+// every plugin lives on its own thread and the ui only hands it jobs, so a slow plugin cant freeze typing.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use plugin_interface::{CustomAction, Plugin, PluginEvent, PluginResult, PluginSettings, PuszRow, PuszRowIdentifier, ResultSink, panic_message};
+
+type Job = Box<dyn FnOnce(&mut Box<dyn Plugin>) + Send>;
+type AnswerFn = Box<dyn FnMut(Answer) + Send>;
+
+// a panicking plugin only loses its own results.
+fn guarded(f : impl FnOnce() -> PluginResult) -> PluginResult {
+    catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| PluginResult::Error(format!("plugin panicked: {}", panic_message(&*payload))))
+}
+
+pub fn query_plugin(plugin : &mut Box<dyn Plugin>, query : &str, sink : &mut dyn ResultSink) -> PluginResult {
+    guarded(|| plugin.query_streaming(query, sink))
+}
+
+// what a query sends back to the ui, any number of batches and then how it ended.
+#[derive(PartialEq, Debug)]
+pub enum Answer {
+    Rows(Vec<PuszRow>),
+    Done(PluginResult),
+}
+
+// where the answers of one query go, whichever of the plugin and its deadline finishes it first wins.
+struct Reply(Mutex<Option<AnswerFn>>);
+
+impl Reply {
+    fn send(&self, answer : Answer) {
+        if let Some(send) = self.0.lock().unwrap().as_mut() {
+            send(answer);
+        }
+    }
+
+    fn finish(&self, answer : Answer) {
+        let send = self.0.lock().unwrap().take();
+        if let Some(mut send) = send {
+            send(answer);
+        }
+    }
+
+    // nobody waits for this one anymore.
+    fn cancel(&self) {
+        self.0.lock().unwrap().take();
+    }
+}
+
+// the query a worker runs next, a newer one replaces it while the plugin is still busy.
+struct QueuedQuery {
+    query : String,
+    generation : u64,
+    deadline : Instant,
+    reply : Arc<Reply>,
+}
+
+struct AnswerSink<'a> {
+    reply : &'a Reply,
+    generation : u64,
+    current : &'a Generation,
+    deadline : Instant,
+}
+
+impl<'a> ResultSink for AnswerSink<'a> {
+    fn push(&mut self, rows : Vec<PuszRow>) {
+        if !self.cancelled() {
+            self.reply.send(Answer::Rows(rows));
+        }
+    }
+
+    // past the deadline nothing the plugin finds is shown anymore either.
+    fn cancelled(&self) -> bool {
+        self.current.current() != self.generation || Instant::now() > self.deadline
+    }
+}
+
+// the query generation the ui is currently showing, bumped on every keystroke.
+#[derive(Clone, Default, Debug)]
+pub struct Generation(Arc<AtomicU64>);
+
+impl Generation {
+    pub fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn current(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+pub struct PluginWorker {
+    name : &'static str,
+    settings : PluginSettings,
+    timeout : Duration,
+    jobs : mpsc::Sender<Job>,
+    queued : Arc<Mutex<Option<QueuedQuery>>>,
+    deadlines : mpsc::Sender<(Instant, Arc<Reply>)>,
+}
+
+// queries all get the same timeout, so deadlines come in the order they expire.
+fn watch_deadlines(name : &'static str, timeout : Duration, deadlines : mpsc::Receiver<(Instant, Arc<Reply>)>) {
+    for (deadline, reply) in deadlines {
+        let now = Instant::now();
+        if deadline > now {
+            std::thread::sleep(deadline - now);
+        }
+        reply.finish(Answer::Done(PluginResult::Error(format!("{} didnt answer within {}ms", name, timeout.as_millis()))));
+    }
+}
+
+impl PluginWorker {
+    pub fn spawn(plugin : Box<dyn Plugin>, timeout : Duration) -> Self {
+        let name = plugin.name();
+        let settings = plugin.settings();
+        let (jobs, rx) = mpsc::channel::<Job>();
+
+        let spawned = std::thread::Builder::new().name(format!("plugin {}", name)).spawn(move || {
+            let mut plugin = plugin;
+            for job in rx {
+                if let Err(payload) = catch_unwind(AssertUnwindSafe(|| job(&mut plugin))) {
+                    error!("plugin {} panicked: {}", name, panic_message(&*payload));
+                }
+            }
+            // the ui let go of the worker, the plugin is dropped here.
+        });
+        if let Err(err) = spawned {
+            error!("couldnt start a thread for plugin {}: {}", name, err);
+        }
+
+        let (deadlines, expiring) = mpsc::channel();
+        let watchdog = std::thread::Builder::new().name(format!("plugin {} deadlines", name)).spawn(move || watch_deadlines(name, timeout, expiring));
+        if let Err(err) = watchdog {
+            error!("couldnt start a deadline thread for plugin {}: {}", name, err);
+        }
+
+        Self {
+            name,
+            settings,
+            timeout,
+            jobs,
+            queued : Arc::new(Mutex::new(None)),
+            deadlines,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn settings(&self) -> &PluginSettings {
+        &self.settings
+    }
+
+    fn post<F>(&self, job : F) where F : FnOnce(&mut Box<dyn Plugin>) + Send + 'static {
+        if self.jobs.send(Box::new(job)).is_err() {
+            warn!("plugin {} is gone", self.name);
+        }
+    }
+
+    // `answer` gets the batches and the final result on the plugin thread, unless the ui moved on to a newer
+    // query before the plugin got to this one. once the timeout passes `answer` gets a timeout error right away,
+    // even when the plugin hangs, and nothing it sends later. while the plugin is busy only the newest query waits.
+    pub fn query<F>(&self, query : String, generation : u64, current : &Generation, answer : F)
+        where F : FnMut(Answer) + Send + 'static {
+        let deadline = Instant::now() + self.timeout;
+        let reply = Arc::new(Reply(Mutex::new(Some(Box::new(answer)))));
+        let _ = self.deadlines.send((deadline, reply.clone()));
+
+        let replaced = self.queued.lock().unwrap().replace(QueuedQuery { query, generation, deadline, reply });
+        if let Some(replaced) = replaced {
+            // the job already posted for it runs this one instead.
+            replaced.reply.cancel();
+            return;
+        }
+
+        let (queued, current) = (self.queued.clone(), current.clone());
+        self.post(move |plugin| {
+            let next = queued.lock().unwrap().take();
+            let QueuedQuery { query, generation, deadline, reply } = match next {
+                Some(next) => next,
+                None => return,
+            };
+            if current.current() != generation {
+                reply.cancel();
+                return;
+            }
+
+            let mut sink = AnswerSink { reply : &reply, generation, current : &current, deadline };
+            let result = query_plugin(plugin, &query, &mut sink);
+            reply.finish(Answer::Done(result));
+        });
+    }
+
+    // no timeout here, whatever the action answers still applies when it arrives.
+    pub fn action<F>(&self, row : PuszRowIdentifier, action : CustomAction, answer : F)
+        where F : FnOnce(PluginResult) + Send + 'static {
+        self.post(move |plugin| answer(guarded(|| plugin.action_request(&row, &action))));
+    }
+
+    pub fn notify(&self, event : PluginEvent) {
+        self.post(move |plugin| plugin.on_subscribed_event(&event));
+    }
+
+    // the state of `old` goes to this plugin on its own thread before anything else it is asked,
+    // `old` and with it its library is dropped there afterwards.
+    pub fn take_over(&self, old : PluginWorker) {
+        self.post(move |plugin| {
+            if let Some(state) = old.save_state() {
+                plugin.restore_state(&state);
+            }
+        });
+    }
+
+    // blocks until the plugin handed over its state, gives up after the query timeout.
+    pub fn save_state(&self) -> Option<String> {
+        let (tx, rx) = mpsc::channel();
+        self.post(move |plugin| {
+            let _ = tx.send(plugin.save_state());
+        });
+
+        rx.recv_timeout(self.timeout).unwrap_or_else(|_| {
+            warn!("plugin {} didnt save its state in time", self.name);
+            None
+        })
+    }
+
+    // waits for everything posted so far.
+    #[cfg(test)]
+    pub fn sync(&self) {
+        let (tx, rx) = mpsc::channel();
+        self.post(move |_| tx.send(()).unwrap());
+        rx.recv().unwrap();
+    }
+}
+
+impl std::fmt::Debug for PluginWorker {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "PluginWorker({})", self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plugin_interface::{PuszRowBuilder, PuszRowIdentifier};
+
+    #[derive(Debug)]
+    struct SlowPlugin {
+        delay : Duration,
+    }
+
+    impl Plugin for SlowPlugin {
+        fn query(&mut self, query : &str) -> PluginResult {
+            if query == "panic" {
+                panic!("query blew up");
+            }
+            std::thread::sleep(self.delay);
+            PluginResult::Error(query.to_owned())
+        }
+
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+    }
+
+    struct NoSink;
+
+    impl ResultSink for NoSink {
+        fn push(&mut self, _rows : Vec<PuszRow>) {}
+
+        fn cancelled(&self) -> bool {
+            false
+        }
+    }
+
+    // hands out one row per word, then the query itself as an error so tests can tell where it ended.
+    #[derive(Debug)]
+    struct WordsPlugin;
+
+    impl Plugin for WordsPlugin {
+        fn query(&mut self, query : &str) -> PluginResult {
+            PluginResult::Error(query.to_owned())
+        }
+
+        fn query_streaming(&mut self, query : &str, sink : &mut dyn ResultSink) -> PluginResult {
+            for word in query.split_whitespace() {
+                sink.push(vec![PuszRowBuilder::new(word.to_owned(), PuszRowIdentifier::new(self.name(), word.to_owned())).build().unwrap()]);
+            }
+            self.query(query)
+        }
+
+        fn name(&self) -> &'static str {
+            "words"
+        }
+    }
+
+    fn worker(delay_ms : u64, timeout_ms : u64) -> PluginWorker {
+        PluginWorker::spawn(Box::new(SlowPlugin { delay : Duration::from_millis(delay_ms) }), Duration::from_millis(timeout_ms))
+    }
+
+    #[test]
+    fn panicking_plugin_only_loses_its_results() {
+        let mut plugin = Box::new(SlowPlugin { delay : Duration::from_millis(0) }) as Box<dyn Plugin>;
+
+        assert_eq!(PluginResult::Error("plugin panicked: query blew up".to_owned()), query_plugin(&mut plugin, "panic", &mut NoSink));
+    }
+
+    #[test]
+    fn stale_queries_are_skipped() {
+        let worker = worker(50, 1000);
+        let current = Generation::default();
+        let (tx, rx) = mpsc::channel();
+
+        // the plugin is busy while the user types three characters.
+        let (release, gate) = mpsc::channel::<()>();
+        worker.post(move |_| gate.recv().unwrap());
+        for query in &["a", "ab", "abc"] {
+            let generation = current.next();
+            let tx = tx.clone();
+            worker.query(query.to_string(), generation, &current, move |answer| tx.send((generation, answer)).unwrap());
+        }
+        release.send(()).unwrap();
+        worker.sync();
+        drop(tx);
+
+        assert_eq!(vec![(3, Answer::Done(PluginResult::Error("abc".to_owned())))], rx.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn late_answers_become_errors() {
+        let worker = worker(100, 10);
+        let current = Generation::default();
+        let (tx, rx) = mpsc::channel();
+
+        worker.query("x".to_owned(), current.next(), &current, move |answer| tx.send(answer).unwrap());
+
+        match rx.recv().unwrap() {
+            Answer::Done(PluginResult::Error(err)) => assert_eq!("slow didnt answer within 10ms", err),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        worker.sync();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn hung_plugin_times_out_and_keeps_only_the_newest_query() {
+        let worker = worker(0, 50);
+        let current = Generation::default();
+        let (tx, rx) = mpsc::channel();
+
+        let (release, gate) = mpsc::channel::<()>();
+        worker.post(move |_| gate.recv().unwrap());
+        let started = Instant::now();
+        for query in &["a", "ab", "abc"] {
+            let generation = current.next();
+            let tx = tx.clone();
+            worker.query(query.to_string(), generation, &current, move |answer| tx.send((generation, answer)).unwrap());
+        }
+        drop(tx);
+
+        assert_eq!((3, Answer::Done(PluginResult::Error("slow didnt answer within 50ms".to_owned()))), rx.recv().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        release.send(()).unwrap();
+        worker.sync();
+        assert_eq!(Vec::<(u64, Answer)>::new(), rx.iter().collect::<Vec<_>>());
+    }
+
+    // remembers the last query, hands it over as its state.
+    #[derive(Debug, Default)]
+    struct LastQueryPlugin {
+        last : String,
+    }
+
+    impl Plugin for LastQueryPlugin {
+        fn query(&mut self, query : &str) -> PluginResult {
+            self.last = query.to_owned();
+            PluginResult::None
+        }
+
+        fn name(&self) -> &'static str {
+            "last"
+        }
+
+        fn save_state(&mut self) -> Option<String> {
+            Some(self.last.clone())
+        }
+
+        fn restore_state(&mut self, state : &str) {
+            self.last = state.to_owned();
+        }
+    }
+
+    #[test]
+    fn state_is_handed_over_on_the_new_plugin_thread() {
+        let current = Generation::default();
+        let old = PluginWorker::spawn(Box::new(LastQueryPlugin::default()), Duration::from_millis(1000));
+        let (release, gate) = mpsc::channel::<()>();
+        old.query("kept".to_owned(), current.next(), &current, |_| {});
+        old.post(move |_| gate.recv().unwrap());
+
+        // the old plugin is still busy, taking over doesnt wait for it.
+        let new = PluginWorker::spawn(Box::new(LastQueryPlugin::default()), Duration::from_millis(1000));
+        new.take_over(old);
+        release.send(()).unwrap();
+
+        assert_eq!(Some("kept".to_owned()), new.save_state());
+    }
+
+    #[test]
+    fn batches_arrive_before_the_result() {
+        let worker = PluginWorker::spawn(Box::new(WordsPlugin), Duration::from_millis(1000));
+        let current = Generation::default();
+        let (tx, rx) = mpsc::channel();
+
+        worker.query("a b".to_owned(), current.next(), &current, move |answer| tx.send(answer).unwrap());
+        worker.sync();
+
+        let labels = rx.try_iter().map(|answer| match answer {
+            Answer::Rows(rows) => rows.into_iter().map(|row| row.main_entry.label).collect::<Vec<_>>().join(","),
+            Answer::Done(result) => format!("done {:?}", result),
+        }).collect::<Vec<_>>();
+        assert_eq!(vec!["a", "b", "done Error(\"a b\")"], labels);
+    }
+}
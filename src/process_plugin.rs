@@ -0,0 +1,410 @@
+// plugins as child processes, so they can be written in anything that reads and writes lines.
+//
+// every message is a single line of json-rpc 2.0. pusz calls:
+//   initialize { interface_version, config, data_dir } -> { requires_explicit_query, interested_in_clipboard }
+//   query / query_return { query } -> [row] or null
+//   action_request { row, id, payload } -> [row], null or "close", row is the id of the row the action belongs to
+//   on_subscribed_event { event } as a notification, e.g. { "event" : { "Clipboard" : "copied text" } }
+// a row is { label, content, id?, score?, actions?, additional? }, actions maps click/double_click/return
+// to set_clipboard/open_browser_if_link/{ "custom" : { id, payload? } } and defaults to click => set_clipboard.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use plugin_interface::{CustomAction, Plugin, PluginEvent, PluginResult, PluginSettings, PuszAction, PuszEntry, PuszEvent, PuszRow, PuszRowIdentifier, KeyChord};
+
+use crate::config::ProcessPluginConfig;
+
+#[derive(Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+enum WireEvent {
+    Click,
+    DoubleClick,
+    Return,
+    ShiftReturn,
+    CtrlReturn,
+    AltReturn,
+    Delete,
+    CtrlC,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum WireAction {
+    SetClipboard,
+    OpenBrowserIfLink,
+    Custom(CustomAction),
+    Paste,
+}
+
+#[derive(Deserialize, Debug)]
+struct WireEntry {
+    label : Option<String>,
+    content : String,
+    actions : Option<BTreeMap<WireEvent, WireAction>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WireRow {
+    #[serde(flatten)]
+    main : WireEntry,
+    id : Option<String>,
+    score : Option<i64>,
+    #[serde(default)]
+    additional : Vec<WireEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct WireSettings {
+    requires_explicit_query : bool,
+    interested_in_clipboard : bool,
+}
+
+impl Default for WireSettings {
+    fn default() -> Self {
+        Self {
+            requires_explicit_query : true,
+            interested_in_clipboard : false,
+        }
+    }
+}
+
+impl WireEntry {
+    fn into_entry(self) -> PuszEntry {
+        let actions = match self.actions {
+            Some(actions) => actions.into_iter().map(|(event, action)| {
+                let event = match event {
+                    WireEvent::Click => PuszEvent::Click,
+                    WireEvent::DoubleClick => PuszEvent::DoubleClick,
+                    WireEvent::Return => PuszEvent::KeyPress(KeyChord::Return),
+                    WireEvent::ShiftReturn => PuszEvent::KeyPress(KeyChord::ShiftReturn),
+                    WireEvent::CtrlReturn => PuszEvent::KeyPress(KeyChord::CtrlReturn),
+                    WireEvent::AltReturn => PuszEvent::KeyPress(KeyChord::AltReturn),
+                    WireEvent::Delete => PuszEvent::KeyPress(KeyChord::Delete),
+                    WireEvent::CtrlC => PuszEvent::KeyPress(KeyChord::CtrlC),
+                };
+                let action = match action {
+                    WireAction::SetClipboard => PuszAction::SetClipboard,
+                    WireAction::OpenBrowserIfLink => PuszAction::OpenBrowserIfLink,
+                    WireAction::Custom(action) => PuszAction::CustomAction(action),
+                    WireAction::Paste => PuszAction::Paste,
+                };
+                (event, action)
+            }).collect(),
+            None => btreemap!(PuszEvent::Click => PuszAction::SetClipboard),
+        };
+
+        let content = self.content;
+        PuszEntry {
+            actions,
+            label : self.label.unwrap_or_else(|| content.clone()),
+            content,
+        }
+    }
+}
+
+// rows in the format above, also what script plugins return.
+pub fn to_result(plugin : &str, result : Value) -> PluginResult {
+    if result.is_null() {
+        return PluginResult::None;
+    }
+    if result == "close" {
+        return PluginResult::Close;
+    }
+
+    match serde_json::from_value::<Vec<WireRow>>(result) {
+        Ok(rows) => PluginResult::Ok(rows.into_iter().map(|row| {
+            let identifier = row.id.clone().unwrap_or_else(|| row.main.content.clone());
+            PuszRow {
+                main_entry : row.main.into_entry(),
+                additional_entries : row.additional.into_iter().map(WireEntry::into_entry).collect(),
+                identifier : PuszRowIdentifier::new(plugin, identifier),
+                is_removable : false,
+                score : row.score,
+            }
+        }).collect()),
+        Err(err) => PluginResult::Error(format!("{} sent unreadable rows: {}", plugin, err)),
+    }
+}
+
+struct Running {
+    child : Child,
+    stdin : ChildStdin,
+    // stdout lines, disconnected once the child closes it.
+    lines : Receiver<String>,
+    next_id : u64,
+}
+
+// why a call didnt produce a result.
+enum CallError {
+    // the child answered with a json-rpc error, it keeps running.
+    Remote(String),
+    // crash, timeout or garbage, the plugin gets disabled.
+    Broken(String),
+}
+
+impl Running {
+    fn spawn(config : &ProcessPluginConfig) -> Result<Self, String> {
+        let mut child = Command::new(&config.command[0])
+            .args(&config.command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("couldnt start {}: {}", config.command[0], e))?;
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        let (tx, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => if tx.send(line).is_err() { break },
+                    Err(_) => break,
+                }
+            }
+        });
+
+        Ok(Self {
+            child,
+            stdin,
+            lines,
+            next_id : 1,
+        })
+    }
+
+    fn send(&mut self, message : &Value) -> Result<(), CallError> {
+        writeln!(self.stdin, "{}", message).and_then(|_| self.stdin.flush()).map_err(|e| CallError::Broken(format!("couldnt write to plugin: {}", e)))
+    }
+
+    fn call(&mut self, method : &str, params : Value, timeout : Duration) -> Result<Value, CallError> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.send(&json!({ "jsonrpc" : "2.0", "id" : id, "method" : method, "params" : params }))?;
+
+        loop {
+            let line = match self.lines.recv_timeout(timeout) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => return Err(CallError::Broken(format!("{} took longer than {}ms", method, timeout.as_millis()))),
+                Err(RecvTimeoutError::Disconnected) => return Err(CallError::Broken(format!("exited during {}", method))),
+            };
+
+            let reply : Value = serde_json::from_str(&line).map_err(|e| CallError::Broken(format!("unreadable reply '{}': {}", line, e)))?;
+            // anything else on stdout, e.g. notifications, is not an answer to this call.
+            if reply.get("id") != Some(&json!(id)) {
+                continue;
+            }
+
+            if let Some(error) = reply.get("error") {
+                let message = error.get("message").and_then(Value::as_str).unwrap_or("unknown error");
+                return Err(CallError::Remote(message.to_owned()));
+            }
+
+            return Ok(reply.get("result").cloned().unwrap_or(Value::Null));
+        }
+    }
+
+    fn notify(&mut self, method : &str, params : Value) -> Result<(), CallError> {
+        self.send(&json!({ "jsonrpc" : "2.0", "method" : method, "params" : params }))
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub struct ProcessPlugin {
+    // Plugin::name hands out &'static str, one leaked name per plugin is fine.
+    name : &'static str,
+    settings : PluginSettings,
+    timeout : Duration,
+    // None once the child crashed or hung, only this plugin goes quiet.
+    running : Option<Running>,
+}
+
+impl ProcessPlugin {
+    pub fn spawn(config : &ProcessPluginConfig, section : Option<&toml::Value>, data_dir : &Path) -> Result<Self, String> {
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let mut running = Running::spawn(config)?;
+
+        let params = json!({
+            "interface_version" : plugin_interface::COMMON_INTERFACE_VERSION,
+            "config" : section.map_or(Ok(Value::Null), serde_json::to_value).map_err(|e| e.to_string())?,
+            "data_dir" : data_dir,
+        });
+        let settings = match running.call("initialize", params, timeout) {
+            Ok(settings) => serde_json::from_value::<Option<WireSettings>>(settings).map_err(|e| format!("unreadable initialize reply: {}", e))?.unwrap_or_default(),
+            Err(CallError::Remote(message)) | Err(CallError::Broken(message)) => return Err(message),
+        };
+
+        Ok(Self {
+            name : Box::leak(config.name.clone().into_boxed_str()),
+            settings : PluginSettings {
+                requies_explicit_query : settings.requires_explicit_query,
+                interested_in_clipboard : settings.interested_in_clipboard,
+            },
+            timeout,
+            running : Some(running),
+        })
+    }
+
+    fn disable(&mut self, reason : &str) {
+        error!("plugin {} disabled: {}", self.name, reason);
+        self.running = None;
+    }
+
+    fn call_query(&mut self, method : &str, params : Value) -> PluginResult {
+        let timeout = self.timeout;
+        let result = match self.running.as_mut() {
+            Some(running) => running.call(method, params, timeout),
+            None => return PluginResult::Error(format!("{} is disabled", self.name)),
+        };
+
+        match result {
+            Ok(result) => to_result(self.name, result),
+            Err(CallError::Remote(message)) => PluginResult::Error(message),
+            Err(CallError::Broken(reason)) => {
+                self.disable(&reason);
+                PluginResult::Error(reason)
+            }
+        }
+    }
+}
+
+impl Plugin for ProcessPlugin {
+    fn query(&mut self, query : &str) -> PluginResult {
+        self.call_query("query", json!({ "query" : query }))
+    }
+
+    fn query_return(&mut self, query : &str) -> PluginResult {
+        self.call_query("query_return", json!({ "query" : query }))
+    }
+
+    fn action_request(&mut self, row : &PuszRowIdentifier, action : &CustomAction) -> PluginResult {
+        self.call_query("action_request", json!({ "row" : row.identifier, "id" : action.id, "payload" : action.payload }))
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn settings(&self) -> PluginSettings {
+        self.settings.clone()
+    }
+
+    fn on_subscribed_event(&mut self, event : &PluginEvent) {
+        let notified = match self.running.as_mut() {
+            Some(running) => running.notify("on_subscribed_event", json!({ "event" : event })),
+            None => return,
+        };
+
+        if let Err(CallError::Broken(reason)) | Err(CallError::Remote(reason)) = notified {
+            self.disable(&reason);
+        }
+    }
+}
+
+impl std::fmt::Debug for ProcessPlugin {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ProcessPlugin({})", self.name)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    // child answering initialize and then replying to each call with the given lines.
+    fn plugin(replies : &[&str], timeout_ms : u64) -> Result<ProcessPlugin, String> {
+        let mut script = "read line; echo '{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":{\"requires_explicit_query\":false}}'\n".to_owned();
+        for reply in replies {
+            script.push_str(&format!("read line; {}\n", reply));
+        }
+
+        let config = ProcessPluginConfig { name : "script".to_owned(), command : vec!["sh".to_owned(), "-c".to_owned(), script], timeout_ms };
+        ProcessPlugin::spawn(&config, None, Path::new("data"))
+    }
+
+    #[test]
+    fn rows_are_mapped() {
+        let mut plugin = plugin(&[r#"echo '{"jsonrpc":"2.0","id":2,"result":[{"content":"https://example.com","label":"example","actions":{"return":"open_browser_if_link","ctrl_c":"set_clipboard","shift_return":"paste"}},{"content":"plain","id":"p"}]}'"#], 2000).unwrap();
+        assert!(!plugin.settings().requies_explicit_query);
+
+        match plugin.query("ex") {
+            PluginResult::Ok(rows) => {
+                assert_eq!("example", rows[0].main_entry.label);
+                assert_eq!(btreemap!(PuszEvent::KeyPress(KeyChord::Return) => PuszAction::OpenBrowserIfLink, PuszEvent::KeyPress(KeyChord::CtrlC) => PuszAction::SetClipboard, PuszEvent::KeyPress(KeyChord::ShiftReturn) => PuszAction::Paste), rows[0].main_entry.actions);
+                assert_eq!(PuszRowIdentifier::new("script", "https://example.com".to_owned()), rows[0].identifier);
+                assert_eq!("plain", rows[1].main_entry.label);
+                assert_eq!(btreemap!(PuszEvent::Click => PuszAction::SetClipboard), rows[1].main_entry.actions);
+                assert_eq!(PuszRowIdentifier::new("script", "p".to_owned()), rows[1].identifier);
+            },
+            other => panic!("expected rows, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn custom_actions_go_back_to_the_plugin() {
+        let mut plugin = plugin(&[
+            r#"echo '{"jsonrpc":"2.0","id":2,"result":[{"content":"42","actions":{"return":{"custom":{"id":"delete","payload":"42"}}}}]}'"#,
+            r#"for part in '"row":"42"' '"id":"delete"' '"payload":"42"'; do case "$line" in *"$part"*) ;; *) line=;; esac; done; [ -n "$line" ] && echo '{"jsonrpc":"2.0","id":3,"result":"close"}' || echo '{"jsonrpc":"2.0","id":3,"result":null}'"#,
+        ], 2000).unwrap();
+
+        let rows = match plugin.query("x") {
+            PluginResult::Ok(rows) => rows,
+            other => panic!("expected rows, got {:?}", other),
+        };
+        let action = match &rows[0].main_entry.actions[&PuszEvent::KeyPress(KeyChord::Return)] {
+            PuszAction::CustomAction(action) => action.clone(),
+            other => panic!("expected a custom action, got {:?}", other),
+        };
+        assert_eq!(CustomAction { id : "delete".to_owned(), payload : Some("42".to_owned()) }, action);
+        assert_eq!(PluginResult::Close, plugin.action_request(&rows[0].identifier, &action));
+    }
+
+    #[test]
+    fn remote_errors_keep_the_plugin() {
+        let mut plugin = plugin(&[
+            r#"echo '{"jsonrpc":"2.0","id":2,"error":{"code":1,"message":"no weather today"}}'"#,
+            r#"echo '{"jsonrpc":"2.0","id":3,"result":null}'"#,
+        ], 2000).unwrap();
+
+        assert_eq!(PluginResult::Error("no weather today".to_owned()), plugin.query("x"));
+        assert_eq!(PluginResult::None, plugin.query("y"));
+    }
+
+    #[test]
+    fn crash_disables_only_the_plugin() {
+        let mut plugin = plugin(&["exit 3"], 2000).unwrap();
+
+        assert_eq!(PluginResult::Error("exited during query".to_owned()), plugin.query("x"));
+        assert_eq!(PluginResult::Error("script is disabled".to_owned()), plugin.query("y"));
+    }
+
+    #[test]
+    fn timeout_disables_the_plugin() {
+        let mut plugin = plugin(&["sleep 5"], 100).unwrap();
+
+        assert_eq!(PluginResult::Error("query took longer than 100ms".to_owned()), plugin.query("x"));
+        assert!(plugin.running.is_none());
+    }
+
+    #[test]
+    fn missing_program_fails_to_load() {
+        let config = ProcessPluginConfig { name : "nope".to_owned(), command : vec!["/nonexistent/pusz-plugin".to_owned()], timeout_ms : 100 };
+        let err = ProcessPlugin::spawn(&config, None, Path::new("data")).unwrap_err();
+        assert!(err.starts_with("couldnt start /nonexistent/pusz-plugin"), "{}", err);
+    }
+}
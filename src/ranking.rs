@@ -0,0 +1,126 @@
+// orders the rows of every plugin as one list, instead of plugin after plugin in whatever order they answered.
+//
+// a row scores its match with the query, from the plugin or fuzzy matching its label, plus the priority
+// of its plugin from config, plus its frecency from the usage log.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use fuzzy_matcher::skim::fuzzy_match;
+
+use plugin_interface::{PuszRow, PuszRowIdentifier};
+
+use crate::usage::UsageLog;
+
+// a row picked daily already wins against anything, more picks dont push it further.
+const MAX_USAGE_BOOST : i64 = 100;
+
+#[derive(Default, Debug)]
+pub struct Ranker {
+    priorities : BTreeMap<String, i64>,
+    usage : UsageLog,
+}
+
+impl Ranker {
+    pub fn new(priorities : BTreeMap<String, i64>, usage : UsageLog) -> Self {
+        Self {
+            priorities,
+            usage,
+        }
+    }
+
+    pub fn picked(&mut self, identifier : &PuszRowIdentifier) {
+        self.usage.record(identifier, SystemTime::now());
+    }
+
+    // rows the plugin returned although their label doesnt match are still its answer, they score 0.
+    pub fn score(&self, row : &PuszRow, query : &str, now : SystemTime) -> i64 {
+        let matched = row.score.or_else(|| fuzzy_match(&row.main_entry.label, query)).unwrap_or(0);
+        let priority = self.priorities.get(&row.identifier.plugin_id).copied().unwrap_or(0);
+        let used = (self.usage.frecency(&row.identifier, now) / 10).min(MAX_USAGE_BOOST);
+
+        matched + priority + used
+    }
+
+    // equal scores go by plugin name so the order doesnt depend on which plugin answered first,
+    // rows of one plugin keep the order the plugin gave them.
+    pub fn merge(&self, results : &mut Vec<PuszRow>, query : &str, rows : Vec<PuszRow>) {
+        let now = SystemTime::now();
+        results.extend(rows);
+        results.sort_by_cached_key(|row| (Reverse(self.score(row, query, now)), row.identifier.plugin_id.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plugin_interface::PuszRowBuilder;
+
+    fn row(plugin : &str, label : &str) -> PuszRow {
+        PuszRowBuilder::new(label.to_owned(), PuszRowIdentifier::new(plugin, label.to_owned())).build().unwrap()
+    }
+
+    fn labels(rows : &[PuszRow]) -> Vec<String> {
+        rows.iter().map(|row| format!("{}:{}", row.identifier.plugin_id, row.main_entry.label)).collect()
+    }
+
+    #[test]
+    fn plugins_are_interleaved_by_match() {
+        let ranker = Ranker::default();
+        let mut results = vec![];
+
+        ranker.merge(&mut results, "choice", vec![row("store", "cxhxoxixcxex"), row("store", "choice")]);
+        ranker.merge(&mut results, "choice", vec![row("clip", "c-hoice"), row("clip", "unrelated")]);
+
+        assert_eq!(vec!["clip:c-hoice", "store:choice", "store:cxhxoxixcxex", "clip:unrelated"], labels(&results));
+    }
+
+    #[test]
+    fn ties_dont_depend_on_answer_order() {
+        let ranker = Ranker::default();
+        let (mut first, mut second) = (vec![], vec![]);
+
+        ranker.merge(&mut first, "", vec![row("store", "b"), row("store", "a")]);
+        ranker.merge(&mut first, "", vec![row("clip", "z")]);
+        ranker.merge(&mut second, "", vec![row("clip", "z")]);
+        ranker.merge(&mut second, "", vec![row("store", "b"), row("store", "a")]);
+
+        assert_eq!(vec!["clip:z", "store:b", "store:a"], labels(&first));
+        assert_eq!(labels(&first), labels(&second));
+    }
+
+    #[test]
+    fn priority_plugin_score_and_usage_add_up() {
+        let mut ranker = Ranker::new(btreemap!("calc".to_owned() => 50), UsageLog::default());
+        let mut scored = row("calc", "4");
+        scored.score = Some(100);
+        let now = SystemTime::now();
+
+        assert_eq!(150, ranker.score(&scored, "2+2", now));
+        assert_eq!(0, ranker.score(&row("clip", "4"), "2+2", now));
+
+        let picked = row("clip", "4");
+        ranker.picked(&picked.identifier);
+        assert_eq!(10, ranker.score(&picked, "2+2", now));
+        for _ in 0..20 {
+            ranker.picked(&picked.identifier);
+        }
+        assert_eq!(MAX_USAGE_BOOST, ranker.score(&picked, "2+2", now));
+    }
+
+    #[test]
+    fn picked_rows_float_up_whichever_plugin_made_them() {
+        let mut ranker = Ranker::default();
+        let daily = row("store", "daily");
+        for _ in 0..5 {
+            ranker.picked(&daily.identifier);
+        }
+
+        let mut results = vec![];
+        ranker.merge(&mut results, "d", vec![row("clip", "d")]);
+        ranker.merge(&mut results, "d", vec![row("store", "dx"), daily]);
+
+        assert_eq!(vec!["store:daily", "clip:d", "store:dx"], labels(&results));
+    }
+}
@@ -0,0 +1,305 @@
+// small commands written as rhai scripts dropped into a plugins dir, no crate needed.
+//
+// fn name() { "upper" }
+// fn prefix() { "up" }                       optional, /up routes to the script besides /upper
+// fn description() { "shouts the query" }    optional, so is fn version() { "0.1.0" }
+// fn query(text) { [#{ content: text.to_upper() }] }
+//
+// fn action(row, id, payload) { "close" }   optional, runs the #{ custom: #{ id, payload } } actions of its rows
+//
+// query returns rows like process plugins do, or () for nothing. action returns rows replacing the results,
+// () to leave them or "close".
+//
+// scripts can call set_clipboard(text), clipboard(), open_url(url), toast(message), refresh(),
+// read_data(file), write_data(file, text), kv_get(key), kv_put(key, text), kv_delete(key) and
+// kv_scan(prefix), see plugin_interface::host.
+//
+// only the functions are ever called, statements outside of them never run.
+
+use std::path::Path;
+
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Scope, AST};
+use semver::{Version, VersionReq};
+
+use plugin_interface::{CustomAction, Plugin, PluginResult, PluginSettings, PuszRowIdentifier};
+use plugin_interface::host::HostContext;
+use plugin_interface::manifest::{interface_version, PluginManifest};
+
+use crate::process_plugin::to_result;
+
+pub const EXTENSION : &str = "rhai";
+
+// a query running longer than this is a bug in the script, not something to wait for.
+const MAX_OPERATIONS : u64 = 10_000_000;
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.on_print(|text| info!("script: {}", text));
+    engine.on_debug(|text, source, position| debug!("script {}:{}: {}", source.unwrap_or(""), position, text));
+    engine
+}
+
+pub struct ScriptPlugin {
+    // Plugin::name hands out &'static str, one leaked name per load is fine.
+    name : &'static str,
+    engine : Engine,
+    ast : AST,
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+impl ScriptPlugin {
+    pub fn with_host(mut self, host : HostContext) -> Self {
+        let engine = &mut self.engine;
+        let h = host.clone();
+        engine.register_fn("set_clipboard", move |text : &str| -> ScriptResult<()> { Ok(h.set_clipboard(text)?) });
+        let h = host.clone();
+        engine.register_fn("clipboard", move || -> ScriptResult<Dynamic> { Ok(h.clipboard()?.map_or(Dynamic::UNIT, Dynamic::from)) });
+        let h = host.clone();
+        engine.register_fn("open_url", move |url : &str| -> ScriptResult<()> { Ok(h.open_url(url)?) });
+        let h = host.clone();
+        engine.register_fn("toast", move |message : &str| -> ScriptResult<()> { Ok(h.show_toast(message)?) });
+        let h = host.clone();
+        engine.register_fn("refresh", move || -> ScriptResult<()> { Ok(h.refresh_results()?) });
+        let h = host.clone();
+        engine.register_fn("read_data", move |file : &str| -> ScriptResult<Dynamic> {
+            let contents = h.read_data(file)?.map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+            Ok(contents.map_or(Dynamic::UNIT, Dynamic::from))
+        });
+        let h = host.clone();
+        engine.register_fn("write_data", move |file : &str, text : &str| -> ScriptResult<()> { Ok(h.write_data(file, text.as_bytes())?) });
+        let h = host.clone();
+        engine.register_fn("kv_get", move |key : &str| -> ScriptResult<Dynamic> {
+            let value = h.kv_get(key)?.map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+            Ok(value.map_or(Dynamic::UNIT, Dynamic::from))
+        });
+        let h = host.clone();
+        engine.register_fn("kv_scan", move |prefix : &str| -> ScriptResult<rhai::Map> {
+            Ok(h.kv_scan(prefix)?.into_iter().map(|(key, value)| (key.into(), Dynamic::from(String::from_utf8_lossy(&value).into_owned()))).collect())
+        });
+        let h = host.clone();
+        engine.register_fn("kv_put", move |key : &str, text : &str| -> ScriptResult<()> { Ok(h.kv_put(key, text.as_bytes())?) });
+        engine.register_fn("kv_delete", move |key : &str| -> ScriptResult<()> { Ok(host.kv_delete(key)?) });
+        self
+    }
+
+    fn has_function(&self, name : &str, params : usize) -> bool {
+        self.ast.iter_functions().any(|function| function.name == name && function.params.len() == params)
+    }
+
+    fn call<T : Clone + Send + Sync + 'static>(&self, function : &str, args : impl rhai::FuncArgs) -> Result<T, String> {
+        let options = CallFnOptions::new().eval_ast(false);
+        self.engine.call_fn_with_options::<T>(options, &mut Scope::new(), &self.ast, function, args).map_err(|e| format!("{}: {}", function, e))
+    }
+
+    fn call_result(&self, function : &str, args : impl rhai::FuncArgs) -> PluginResult {
+        let result = self.call::<Dynamic>(function, args)
+            .and_then(|result| rhai::serde::from_dynamic::<serde_json::Value>(&result).map_err(|e| e.to_string()));
+
+        match result {
+            Ok(result) => to_result(self.name, result),
+            Err(err) => PluginResult::Error(err),
+        }
+    }
+
+    // fn `function`() returning a string, None when the script doesnt define it.
+    fn optional(&self, function : &str) -> Result<Option<String>, String> {
+        if self.has_function(function, 0) {
+            self.call::<String>(function, ()).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn load(path : &Path) -> Result<(PluginManifest, Self), String> {
+        let engine = engine();
+        let ast = engine.compile_file(path.to_path_buf()).map_err(|e| e.to_string())?;
+        let mut plugin = Self { name : "", engine, ast };
+
+        if !plugin.has_function("query", 1) {
+            return Err("script has no fn query(text)".to_owned());
+        }
+
+        let name = plugin.optional("name")?.ok_or_else(|| "script has no fn name()".to_owned())?;
+        let version = match plugin.optional("version")? {
+            Some(version) => Version::parse(&version).map_err(|e| format!("version {}: {}", version, e))?,
+            None => Version::new(0, 0, 0),
+        };
+
+        // scripts go through the same routing and /plugins listing as plugins with a real manifest.
+        let manifest = PluginManifest {
+            name : name.clone(),
+            version,
+            author : String::new(),
+            description : plugin.optional("description")?.unwrap_or_default(),
+            interface : VersionReq::parse(&format!("^{}", interface_version())).expect("interface version is semver"),
+            prefixes : plugin.optional("prefix")?.into_iter().collect(),
+            capabilities : vec![],
+        };
+
+        plugin.name = Box::leak(name.into_boxed_str());
+        Ok((manifest, plugin))
+    }
+}
+
+impl Plugin for ScriptPlugin {
+    fn query(&mut self, query : &str) -> PluginResult {
+        self.call_result("query", (query.to_owned(),))
+    }
+
+    fn action_request(&mut self, row : &PuszRowIdentifier, action : &CustomAction) -> PluginResult {
+        if !self.has_function("action", 3) {
+            return PluginResult::Error(format!("{} has no fn action(row, id, payload) for {}", self.name, action.id));
+        }
+
+        let payload = action.payload.clone().map_or(Dynamic::UNIT, Dynamic::from);
+        self.call_result("action", (row.identifier.clone(), action.id.clone(), payload))
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn settings(&self) -> PluginSettings {
+        PluginSettings {
+            requies_explicit_query : true,
+            interested_in_clipboard : false,
+        }
+    }
+}
+
+impl std::fmt::Debug for ScriptPlugin {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ScriptPlugin({})", self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use plugin_interface::{KeyChord, PuszAction, PuszEvent};
+
+    fn script(file : &str, text : &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pusz_script_plugin_tests_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(file);
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn script_declares_manifest_and_answers_queries() {
+        let path = script("upper.rhai", r#"
+            fn name() { "upper" }
+            fn prefix() { "up" }
+            fn description() { "shouts" }
+            fn query(text) {
+                if text == "" { return (); }
+                [#{ content: text.to_upper(), actions: #{ "return": "open_browser_if_link" } }]
+            }
+        "#);
+
+        let (manifest, mut plugin) = ScriptPlugin::load(&path).unwrap();
+        assert_eq!("upper", manifest.name);
+        assert!(manifest.handles_command("up"));
+        assert_eq!("shouts", manifest.description);
+        assert_eq!(Ok(()), manifest.check_interface());
+
+        match plugin.query("hi") {
+            PluginResult::Ok(rows) => {
+                assert_eq!("HI", rows[0].main_entry.label);
+                assert_eq!(btreemap!(PuszEvent::KeyPress(KeyChord::Return) => PuszAction::OpenBrowserIfLink), rows[0].main_entry.actions);
+                assert_eq!(PuszRowIdentifier::new("upper", "HI".to_owned()), rows[0].identifier);
+            },
+            other => panic!("expected rows, got {:?}", other),
+        }
+        assert_eq!(PluginResult::None, plugin.query(""));
+    }
+
+    #[test]
+    fn actions_run_in_the_script() {
+        let path = script("todo.rhai", r#"
+            fn name() { "todo" }
+            fn query(text) {
+                let done = #{ custom: #{ id: "done", payload: text } };
+                [#{ content: text, id: "t1", actions: #{ "return": done } }]
+            }
+            fn action(row, id, payload) {
+                if id == "done" && row == "t1" { return "close"; }
+                [#{ content: `${id} ${payload}` }]
+            }
+        "#);
+        let (_, mut plugin) = ScriptPlugin::load(&path).unwrap();
+
+        let rows = match plugin.query("milk") {
+            PluginResult::Ok(rows) => rows,
+            other => panic!("expected rows, got {:?}", other),
+        };
+        let action = CustomAction { id : "done".to_owned(), payload : Some("milk".to_owned()) };
+        assert_eq!(Some(&PuszAction::CustomAction(action.clone())), rows[0].main_entry.actions.get(&PuszEvent::KeyPress(KeyChord::Return)));
+        assert_eq!(PluginResult::Close, plugin.action_request(&rows[0].identifier, &action));
+
+        match plugin.action_request(&PuszRowIdentifier::new("todo", "t2".to_owned()), &CustomAction { id : "undo".to_owned(), payload : None }) {
+            PluginResult::Ok(rows) => assert_eq!("undo ", rows[0].main_entry.label),
+            other => panic!("expected rows, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scripts_reach_the_host() {
+        use crate::plugin_host::PluginHosts;
+
+        let data = std::env::temp_dir().join(format!("pusz_script_host_tests_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data);
+        let path = script("copy.rhai", r#"
+            fn name() { "copy" }
+            fn query(text) {
+                let before = read_data("last");
+                let seen = kv_get("seen");
+                set_clipboard(text);
+                write_data("last", text);
+                kv_put("seen", text);
+                kv_put("seen twice", text);
+                kv_delete("seen twice");
+                [#{ content: `${before} ${clipboard()} ${read_data("last")} ${seen} ${kv_scan("seen")}` }]
+            }
+        "#);
+        let host = PluginHosts::fake(&data).for_plugin("copy");
+        let mut plugin = ScriptPlugin::load(&path).unwrap().1.with_host(host);
+
+        match plugin.query("milk") {
+            PluginResult::Ok(rows) => assert_eq!(r#" milk milk  #{"seen": "milk"}"#, rows[0].main_entry.label),
+            other => panic!("expected rows, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn broken_scripts_are_reported() {
+        let err = ScriptPlugin::load(&script("nameless.rhai", "fn query(text) { () }")).unwrap_err();
+        assert_eq!("script has no fn name()", err);
+
+        let err = ScriptPlugin::load(&script("typo.rhai", "fn name() { \"typo\" ")).unwrap_err();
+        assert!(err.contains("line 1"), "{}", err);
+    }
+
+    #[test]
+    fn statements_outside_functions_never_run() {
+        let (manifest, mut plugin) = ScriptPlugin::load(&script("body.rhai", "throw \"ran\";\nfn name() { \"body\" }\nfn query(text) { () }")).unwrap();
+
+        assert_eq!("body", manifest.name);
+        assert!(matches!(plugin.query("x"), PluginResult::None));
+    }
+
+    #[test]
+    fn runaway_script_errors_instead_of_hanging() {
+        let (_, mut plugin) = ScriptPlugin::load(&script("loop.rhai", "fn name() { \"loop\" }\nfn query(text) { loop {} }")).unwrap();
+
+        match plugin.query("x") {
+            PluginResult::Error(err) => assert!(err.starts_with("query: Too many operations"), "{}", err),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+}
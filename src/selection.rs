@@ -0,0 +1,193 @@
+// which row and which of its entries Return runs. rows keep moving while plugins answer, so the selection
+// remembers the row itself rather than its position, and until the user moves it sticks to the best match.
+
+use plugin_interface::{KeyChord, PuszAction, PuszEntry, PuszEvent, PuszRow, PuszRowIdentifier};
+
+// rows PageUp and PageDown jump over.
+pub const PAGE_ROWS : usize = 8;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Step {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+}
+
+#[derive(Default, Debug)]
+pub struct Selection {
+    // None follows the first row.
+    row : Option<PuszRowIdentifier>,
+    // 0 is the main entry, then the additional entries in order.
+    entry : usize,
+}
+
+impl Selection {
+    // a row that got dropped from the results hands the selection back to the first one.
+    pub fn index(&self, rows : &[PuszRow]) -> Option<usize> {
+        let chosen = self.row.as_ref().and_then(|selected| rows.iter().position(|row| row.identifier == *selected));
+        chosen.or_else(|| if rows.is_empty() { None } else { Some(0) })
+    }
+
+    pub fn entry_index(&self, rows : &[PuszRow]) -> usize {
+        match &self.row {
+            Some(selected) if rows.iter().any(|row| row.identifier == *selected) => self.entry,
+            _ => 0,
+        }
+    }
+
+    pub fn entry<'a>(&self, rows : &'a [PuszRow]) -> Option<(&'a PuszRow, &'a PuszEntry)> {
+        let row = &rows[self.index(rows)?];
+        let entry = match self.entry_index(rows) {
+            0 => &row.main_entry,
+            n => row.additional_entries.get(n - 1)?,
+        };
+        Some((row, entry))
+    }
+
+    pub fn step(&mut self, rows : &[PuszRow], step : Step) {
+        let current = match self.index(rows) {
+            Some(current) => current,
+            None => return,
+        };
+        let last = rows.len() - 1;
+
+        let next = match step {
+            Step::Up => current.saturating_sub(1),
+            Step::Down => (current + 1).min(last),
+            Step::PageUp => current.saturating_sub(PAGE_ROWS),
+            Step::PageDown => (current + PAGE_ROWS).min(last),
+            Step::Home => 0,
+            Step::End => last,
+        };
+        if next != current || self.row.is_none() {
+            self.row = Some(rows[next].identifier.clone());
+            self.entry = 0;
+        }
+    }
+
+    // Tab, wraps around to the main entry after the last additional one.
+    pub fn next_entry(&mut self, rows : &[PuszRow]) {
+        if let Some(current) = self.index(rows) {
+            let entries = rows[current].additional_entries.len() + 1;
+            self.entry = (self.entry_index(rows) + 1) % entries;
+            self.row = Some(rows[current].identifier.clone());
+        }
+    }
+}
+
+// plain Return does what a click does when the entry has nothing else for it.
+pub fn chord_action(entry : &PuszEntry, chord : KeyChord) -> Option<&PuszAction> {
+    match entry.action(&PuszEvent::KeyPress(chord)) {
+        None if chord == KeyChord::Return => entry.action(&PuszEvent::Click),
+        action => action,
+    }
+}
+
+fn describe(action : &PuszAction) -> &str {
+    match action {
+        PuszAction::SetClipboard => "copy",
+        PuszAction::OpenBrowserIfLink => "open",
+        PuszAction::CustomAction(action) => &action.id,
+        PuszAction::Paste => "paste",
+    }
+}
+
+// the chords of an entry and what they do, shown below the results for the selected one.
+pub fn hints(entry : &PuszEntry) -> String {
+    let hints : Vec<_> = KeyChord::ALL.iter()
+        .filter_map(|chord| chord_action(entry, *chord).map(|action| format!("{}: {}", chord.label(), describe(action))))
+        .collect();
+    hints.join("   ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plugin_interface::PuszRowBuilder;
+
+    fn rows(labels : &[&str]) -> Vec<PuszRow> {
+        labels.iter().map(|label| PuszRowBuilder::new(label.to_string(), PuszRowIdentifier::new("test", label.to_string())).build().unwrap()).collect()
+    }
+
+    fn entry(label : &str) -> PuszEntry {
+        PuszEntry { actions : Default::default(), label : label.to_owned(), content : label.to_owned() }
+    }
+
+    fn selected(selection : &Selection, rows : &[PuszRow]) -> Option<String> {
+        selection.entry(rows).map(|(_, entry)| entry.label.clone())
+    }
+
+    #[test]
+    fn steps_stop_at_either_end() {
+        let labels : Vec<String> = (0..20).map(|n| n.to_string()).collect();
+        let rows = rows(&labels.iter().map(String::as_str).collect::<Vec<_>>());
+        let mut selection = Selection::default();
+        assert_eq!(Some(0), selection.index(&rows));
+
+        selection.step(&rows, Step::Up);
+        assert_eq!(Some(0), selection.index(&rows));
+        selection.step(&rows, Step::Down);
+        assert_eq!(Some(1), selection.index(&rows));
+        selection.step(&rows, Step::PageDown);
+        assert_eq!(Some(1 + PAGE_ROWS), selection.index(&rows));
+        selection.step(&rows, Step::End);
+        selection.step(&rows, Step::Down);
+        assert_eq!(Some(19), selection.index(&rows));
+        selection.step(&rows, Step::PageUp);
+        assert_eq!(Some(19 - PAGE_ROWS), selection.index(&rows));
+        selection.step(&rows, Step::Home);
+        assert_eq!(Some(0), selection.index(&rows));
+
+        assert_eq!(None, Selection::default().index(&[]));
+    }
+
+    #[test]
+    fn selection_follows_the_row_not_the_position() {
+        let mut selection = Selection::default();
+        let first = rows(&["a", "b"]);
+        // untouched, it stays on whatever ranks first.
+        assert_eq!(Some("a".to_owned()), selected(&selection, &first));
+        assert_eq!(Some("c".to_owned()), selected(&selection, &rows(&["c", "a", "b"])));
+
+        selection.step(&first, Step::Down);
+        assert_eq!(Some("b".to_owned()), selected(&selection, &rows(&["c", "a", "b"])));
+        assert_eq!(Some("c".to_owned()), selected(&selection, &rows(&["c", "a"])));
+    }
+
+    #[test]
+    fn hints_list_the_chords_an_entry_binds() {
+        let mut bound = entry("a");
+        bound.actions = btreemap!(
+            PuszEvent::Click => PuszAction::SetClipboard,
+            PuszEvent::KeyPress(KeyChord::CtrlReturn) => PuszAction::OpenBrowserIfLink,
+            PuszEvent::KeyPress(KeyChord::Delete) => PuszAction::CustomAction(plugin_interface::CustomAction { id : "forget".to_owned(), payload : None })
+        );
+
+        assert_eq!("Return: copy   Ctrl+Return: open   Delete: forget", hints(&bound));
+        assert_eq!(None, chord_action(&bound, KeyChord::ShiftReturn));
+        assert_eq!("", hints(&entry("b")));
+    }
+
+    #[test]
+    fn tab_cycles_through_the_entries_of_a_row() {
+        let mut rows = rows(&["a", "b"]);
+        rows[0].additional_entries = vec![entry("open"), entry("copy")];
+        let mut selection = Selection::default();
+
+        selection.next_entry(&rows);
+        assert_eq!(Some("open".to_owned()), selected(&selection, &rows));
+        selection.next_entry(&rows);
+        assert_eq!(Some("copy".to_owned()), selected(&selection, &rows));
+        selection.next_entry(&rows);
+        assert_eq!(Some("a".to_owned()), selected(&selection, &rows));
+
+        selection.next_entry(&rows);
+        selection.step(&rows, Step::Down);
+        assert_eq!((Some(1), 0), (selection.index(&rows), selection.entry_index(&rows)));
+        selection.next_entry(&rows);
+        assert_eq!(0, selection.entry_index(&rows));
+    }
+}
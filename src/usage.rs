@@ -0,0 +1,228 @@
+// rows the user picked and when, kept across restarts so what gets picked daily ranks first.
+//
+// frecency is the pick count weighted by how recent the last few picks were, a row picked
+// once today scores 100, the same row picked once two months ago 30. rows not picked for
+// FORGET_AFTER_DAYS are dropped from the log.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+
+use plugin_interface::PuszRowIdentifier;
+
+// only the most recent picks of a row are weighted, the count covers all of them.
+const KEPT_PICKS : usize = 10;
+
+const DAY : u64 = 24 * 60 * 60;
+// by then every pick of a row has decayed to the lowest weight.
+const FORGET_AFTER_DAYS : u64 = 90;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+struct Usage {
+    identifier : PuszRowIdentifier,
+    count : u32,
+    // seconds since the epoch, oldest first.
+    picked_at : Vec<u64>,
+}
+
+fn seconds(time : SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+fn age_weight(age : Duration) -> i64 {
+    match age.as_secs() / DAY {
+        0..=3 => 100,
+        4..=13 => 70,
+        14..=30 => 50,
+        31..=89 => 30,
+        _ => 10,
+    }
+}
+
+// writes the log on a thread of its own so picking a row never waits for the disk,
+// snapshots queued up while a write is going are skipped for the newest one.
+#[derive(Debug)]
+struct Saver {
+    tx : Option<Sender<Vec<Usage>>>,
+    thread : Option<JoinHandle<()>>,
+}
+
+impl Saver {
+    fn spawn(path : PathBuf) -> Self {
+        let (tx, rx) = channel::<Vec<Usage>>();
+        let thread = spawn(move || {
+            while let Ok(mut rows) = rx.recv() {
+                while let Ok(newer) = rx.try_recv() {
+                    rows = newer;
+                }
+                if let Err(err) = save(&path, rows) {
+                    error!("couldnt save usage log: {}", err);
+                }
+            }
+        });
+
+        Self { tx : Some(tx), thread : Some(thread) }
+    }
+
+    fn save(&self, rows : Vec<Usage>) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(rows);
+        }
+    }
+}
+
+// whatever is queued is written before the log goes away.
+impl Drop for Saver {
+    fn drop(&mut self) {
+        drop(self.tx.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct UsageLog {
+    // None keeps the log in memory only.
+    saver : Option<Saver>,
+    rows : HashMap<PuszRowIdentifier, Usage>,
+}
+
+impl UsageLog {
+    // a missing file is an empty log, an unreadable one is reported and started over.
+    pub fn load(path : &Path) -> Self {
+        let rows = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str::<Vec<Usage>>(&text).unwrap_or_else(|err| {
+                warn!("ignoring broken usage log {}: {}", path.display(), err);
+                vec![]
+            }),
+            Err(_) => vec![],
+        };
+
+        let mut log = Self {
+            saver : Some(Saver::spawn(path.to_owned())),
+            rows : rows.into_iter().map(|usage| (usage.identifier.clone(), usage)).collect(),
+        };
+        log.forget_old(SystemTime::now());
+        log
+    }
+
+    pub fn record(&mut self, identifier : &PuszRowIdentifier, now : SystemTime) {
+        let usage = self.rows.entry(identifier.clone()).or_insert_with(|| Usage { identifier : identifier.clone(), count : 0, picked_at : vec![] });
+        usage.count += 1;
+        usage.picked_at.push(seconds(now));
+        if usage.picked_at.len() > KEPT_PICKS {
+            usage.picked_at.remove(0);
+        }
+
+        self.forget_old(now);
+        if let Some(saver) = &self.saver {
+            saver.save(self.rows.values().cloned().collect());
+        }
+    }
+
+    pub fn frecency(&self, identifier : &PuszRowIdentifier, now : SystemTime) -> i64 {
+        let usage = match self.rows.get(identifier) {
+            Some(usage) if !usage.picked_at.is_empty() => usage,
+            _ => return 0,
+        };
+
+        let now = seconds(now);
+        let weights : i64 = usage.picked_at.iter().map(|at| age_weight(Duration::from_secs(now.saturating_sub(*at)))).sum();
+        usage.count as i64 * weights / usage.picked_at.len() as i64
+    }
+
+    fn forget_old(&mut self, now : SystemTime) {
+        let oldest = seconds(now).saturating_sub(FORGET_AFTER_DAYS * DAY);
+        self.rows.retain(|_, usage| usage.picked_at.last().is_some_and(|at| *at > oldest));
+    }
+}
+
+// written aside and renamed over the old log, so a crash leaves one or the other.
+fn save(path : &Path, mut rows : Vec<Usage>) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+
+    rows.sort_by(|a, b| (&a.identifier.plugin_id, &a.identifier.identifier).cmp(&(&b.identifier.plugin_id, &b.identifier.identifier)));
+    let text = serde_json::to_string_pretty(&rows).map_err(|e| e.to_string())?;
+
+    let written = path.with_extension("json.new");
+    fs::write(&written, text).map_err(|e| format!("{}: {}", written.display(), e))?;
+    fs::rename(&written, path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn days_ago(now : SystemTime, days : u64) -> SystemTime {
+        now - Duration::from_secs(days * DAY)
+    }
+
+    #[test]
+    fn recent_picks_weigh_more() {
+        let now = SystemTime::now();
+        let (daily, old) = (PuszRowIdentifier::new("clip", "daily".to_owned()), PuszRowIdentifier::new("clip", "old".to_owned()));
+        let mut log = UsageLog::default();
+
+        log.record(&daily, now);
+        log.record(&old, days_ago(now, 60));
+
+        assert_eq!(100, log.frecency(&daily, now));
+        assert_eq!(30, log.frecency(&old, now));
+        assert_eq!(0, log.frecency(&PuszRowIdentifier::new("clip", "never".to_owned()), now));
+
+        log.record(&old, now);
+        assert_eq!(2 * (30 + 100) / 2, log.frecency(&old, now));
+    }
+
+    #[test]
+    fn count_outlives_the_kept_picks() {
+        let now = SystemTime::now();
+        let row = PuszRowIdentifier::new("store", "row".to_owned());
+        let mut log = UsageLog::default();
+
+        for _ in 0..15 {
+            log.record(&row, days_ago(now, 100));
+        }
+        for _ in 0..KEPT_PICKS {
+            log.record(&row, now);
+        }
+
+        assert_eq!(KEPT_PICKS, log.rows[&row].picked_at.len());
+        assert_eq!(25 * 100, log.frecency(&row, now));
+    }
+
+    #[test]
+    fn rows_not_picked_for_long_are_forgotten() {
+        let now = SystemTime::now();
+        let (recent, forgotten) = (PuszRowIdentifier::new("clip", "recent".to_owned()), PuszRowIdentifier::new("clip", "forgotten".to_owned()));
+        let mut log = UsageLog::default();
+
+        log.record(&forgotten, days_ago(now, FORGET_AFTER_DAYS + 1));
+        log.record(&recent, now);
+
+        assert!(log.rows.contains_key(&recent));
+        assert!(!log.rows.contains_key(&forgotten));
+    }
+
+    #[test]
+    fn log_survives_restart() {
+        let path = std::env::temp_dir().join(format!("pusz_usage_tests_{}", std::process::id())).join("usage.json");
+        let _ = fs::remove_file(&path);
+        let now = SystemTime::now();
+        let row = PuszRowIdentifier::new("calc", "4".to_owned());
+
+        UsageLog::load(&path).record(&row, now);
+        assert_eq!(100, UsageLog::load(&path).frecency(&row, now));
+
+        fs::write(&path, "not json").unwrap();
+        assert_eq!(0, UsageLog::load(&path).frecency(&row, now));
+    }
+}
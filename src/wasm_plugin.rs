@@ -0,0 +1,420 @@
+// runs plugin_interface::wasm modules in wasmtime. a module only sees its own memory and the host
+// functions its manifest grants, runaway loops run out of fuel and memory is capped.
+
+use std::fs;
+use std::path::Path;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use wasmtime::{Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, Trap, TypedFunc};
+
+use plugin_interface::{CustomAction, Plugin, PluginContext, PluginEvent, PluginResult, PluginSettings, PuszRowIdentifier, LoadError};
+use plugin_interface::host::HostContext;
+use plugin_interface::manifest::{Capability, PluginManifest};
+use plugin_interface::wasm::{self, method, pack, unpack};
+
+use crate::plugin_host::log_level;
+
+// roughly instructions, plenty for a query and nowhere near enough for an endless loop to hang the ui.
+const FUEL_PER_CALL : u64 = 500_000_000;
+const MEMORY_LIMIT : usize = 64 << 20;
+
+struct HostState {
+    plugin : String,
+    // behind the clipboard and storage functions, storage is the plugin's key value store.
+    host : HostContext,
+    limits : StoreLimits,
+}
+
+fn memory(caller : &mut Caller<'_, HostState>) -> wasmtime::Result<Memory> {
+    caller.get_export("memory").and_then(Extern::into_memory).ok_or_else(|| wasmtime::Error::msg("module exports no memory"))
+}
+
+fn read_string(caller : &mut Caller<'_, HostState>, ptr : i32, len : i32) -> wasmtime::Result<String> {
+    let mut bytes = vec![0; len as u32 as usize];
+    memory(caller)?.read(&*caller, ptr as u32 as usize, &mut bytes)?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+// copies `bytes` into a buffer of the module, which owns it from here on. -1 for nothing to give.
+fn give(caller : &mut Caller<'_, HostState>, bytes : Option<Vec<u8>>) -> wasmtime::Result<i64> {
+    let bytes = match bytes {
+        Some(bytes) => bytes,
+        None => return Ok(-1),
+    };
+
+    let alloc = caller.get_export("pusz_alloc").and_then(Extern::into_func).ok_or_else(|| wasmtime::Error::msg("module exports no pusz_alloc"))?;
+    let ptr = alloc.typed::<i32, i32>(&*caller)?.call(&mut *caller, bytes.len() as i32)?;
+    memory(caller)?.write(&mut *caller, ptr as u32 as usize, &bytes)?;
+    Ok(pack(ptr as u32, bytes.len() as u32))
+}
+
+// host failures are the host's business, the module just gets nothing.
+fn or_warn<T : Default>(caller : &Caller<'_, HostState>, what : &str, result : Result<T, String>) -> T {
+    result.unwrap_or_else(|err| {
+        warn!("{}: {} failed: {}", caller.data().plugin, what, err);
+        T::default()
+    })
+}
+
+fn linker(engine : &Engine, manifest : &PluginManifest) -> wasmtime::Result<Linker<HostState>> {
+    let mut linker = Linker::new(engine);
+
+    linker.func_wrap("pusz", "log", |mut caller : Caller<'_, HostState>, level : i32, ptr : i32, len : i32| -> wasmtime::Result<()> {
+        let message = read_string(&mut caller, ptr, len)?;
+        log!(log_level(level), "{}: {}", caller.data().plugin, message);
+        Ok(())
+    })?;
+
+    if manifest.has_capability(Capability::Storage) {
+        linker.func_wrap("pusz", "storage_get", |mut caller : Caller<'_, HostState>, key_ptr : i32, key_len : i32| -> wasmtime::Result<i64> {
+            let key = read_string(&mut caller, key_ptr, key_len)?;
+            let value = caller.data().host.kv_get(&key);
+            let value = or_warn(&caller, "storage_get", value);
+            give(&mut caller, value)
+        })?;
+
+        linker.func_wrap("pusz", "storage_set", |mut caller : Caller<'_, HostState>, key_ptr : i32, key_len : i32, value_ptr : i32, value_len : i32| -> wasmtime::Result<()> {
+            let key = read_string(&mut caller, key_ptr, key_len)?;
+            let value = read_string(&mut caller, value_ptr, value_len)?;
+            let written = caller.data().host.kv_put(&key, value.as_bytes());
+            or_warn(&caller, "storage_set", written);
+            Ok(())
+        })?;
+    }
+
+    if manifest.has_capability(Capability::Clipboard) {
+        linker.func_wrap("pusz", "clipboard_get", |mut caller : Caller<'_, HostState>| -> wasmtime::Result<i64> {
+            let text = caller.data().host.clipboard();
+            let text = or_warn(&caller, "clipboard_get", text);
+            give(&mut caller, text.map(String::into_bytes))
+        })?;
+
+        linker.func_wrap("pusz", "clipboard_set", |mut caller : Caller<'_, HostState>, ptr : i32, len : i32| -> wasmtime::Result<()> {
+            let text = read_string(&mut caller, ptr, len)?;
+            let set = caller.data().host.set_clipboard(&text);
+            or_warn(&caller, "clipboard_set", set);
+            Ok(())
+        })?;
+    }
+
+    Ok(linker)
+}
+
+// checked up front so a missing capability reads better than wasmtime's unknown import error.
+fn check_imports(module : &Module, manifest : &PluginManifest) -> Result<(), String> {
+    for import in module.imports() {
+        let needs = match (import.module(), import.name()) {
+            ("pusz", "log") => None,
+            ("pusz", "storage_get") | ("pusz", "storage_set") => Some(("storage", Capability::Storage)),
+            ("pusz", "clipboard_get") | ("pusz", "clipboard_set") => Some(("clipboard", Capability::Clipboard)),
+            (module, name) => return Err(format!("imports {}.{} which pusz doesnt provide", module, name)),
+        };
+
+        if let Some((name, capability)) = needs {
+            if !manifest.has_capability(capability) {
+                return Err(format!("imports pusz.{} but its manifest doesnt ask for the {} capability", import.name(), name));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn describe(err : wasmtime::Error) -> String {
+    match err.downcast_ref::<Trap>() {
+        Some(Trap::OutOfFuel) => "ran out of fuel".to_owned(),
+        Some(trap) => format!("trapped: {}", trap),
+        None => err.to_string(),
+    }
+}
+
+pub struct WasmPlugin {
+    name : &'static str,
+    settings : PluginSettings,
+    store : Store<HostState>,
+    memory : Memory,
+    alloc : TypedFunc<i32, i32>,
+    free : TypedFunc<(i32, i32), ()>,
+    call : TypedFunc<(i32, i32, i32), i64>,
+    fuel_per_call : u64,
+    // set once the module trapped, its memory cant be trusted after that.
+    broken : bool,
+}
+
+impl WasmPlugin {
+    pub fn load(path : &Path, manifest : &PluginManifest, context : &PluginContext) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("couldnt read {}: {}", path.display(), e))?;
+        Self::from_bytes(&bytes, manifest, context)
+    }
+
+    fn from_bytes(bytes : &[u8], manifest : &PluginManifest, context : &PluginContext) -> Result<Self, String> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(describe)?;
+
+        let module = Module::new(&engine, bytes).map_err(|e| format!("invalid module: {}", describe(e)))?;
+        check_imports(&module, manifest)?;
+
+        let state = HostState {
+            plugin : manifest.name.clone(),
+            host : context.host().clone(),
+            limits : StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build(),
+        };
+        let mut store = Store::new(&engine, state);
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(FUEL_PER_CALL).map_err(describe)?;
+
+        let instance = linker(&engine, manifest).and_then(|linker| linker.instantiate(&mut store, &module)).map_err(describe)?;
+        let abi_version = instance.get_typed_func::<(), i32>(&mut store, "pusz_abi_version").and_then(|f| f.call(&mut store, ())).map_err(describe)?;
+        if abi_version != wasm::ABI_VERSION {
+            return Err(format!("module built for wasm abi version {}, pusz supports {}", abi_version, wasm::ABI_VERSION));
+        }
+
+        let memory = instance.get_memory(&mut store, "memory").ok_or_else(|| "module exports no memory".to_owned())?;
+        let alloc = instance.get_typed_func(&mut store, "pusz_alloc").map_err(describe)?;
+        let free = instance.get_typed_func(&mut store, "pusz_free").map_err(describe)?;
+        let call = instance.get_typed_func(&mut store, "pusz_call").map_err(describe)?;
+
+        let mut plugin = Self {
+            name : "",
+            settings : PluginSettings { requies_explicit_query : true, interested_in_clipboard : false },
+            store,
+            memory,
+            alloc,
+            free,
+            call,
+            fuel_per_call : FUEL_PER_CALL,
+            broken : false,
+        };
+
+        let (name, settings) = plugin.invoke::<_, Result<(String, PluginSettings), LoadError>>(method::LOAD, context)?.map_err(|e| e.to_string())?;
+        // Plugin::name hands out &'static str, one leaked name per load is fine.
+        plugin.name = Box::leak(name.into_boxed_str());
+        plugin.settings = settings;
+        Ok(plugin)
+    }
+
+    fn call_raw(&mut self, method : i32, input : &[u8]) -> wasmtime::Result<Vec<u8>> {
+        self.store.set_fuel(self.fuel_per_call)?;
+
+        let ptr = self.alloc.call(&mut self.store, input.len() as i32)?;
+        self.memory.write(&mut self.store, ptr as u32 as usize, input)?;
+
+        let (output_ptr, output_len) = unpack(self.call.call(&mut self.store, (method, ptr, input.len() as i32))?);
+        let mut output = vec![0; output_len as usize];
+        self.memory.read(&self.store, output_ptr as usize, &mut output)?;
+        self.free.call(&mut self.store, (output_ptr as i32, output_len as i32))?;
+
+        Ok(output)
+    }
+
+    fn invoke_raw<I : Serialize>(&mut self, method : i32, input : &I) -> Result<Vec<u8>, String> {
+        if self.broken {
+            return Err(format!("{} is disabled", self.name));
+        }
+
+        let input = serde_json::to_vec(input).map_err(|e| e.to_string())?;
+        self.call_raw(method, &input).map_err(|err| {
+            self.broken = true;
+            let reason = describe(err);
+            error!("plugin {} disabled: {}", self.name, reason);
+            reason
+        })
+    }
+
+    fn invoke<I : Serialize, O : DeserializeOwned>(&mut self, method : i32, input : &I) -> Result<O, String> {
+        let output = self.invoke_raw(method, input)?;
+        serde_json::from_slice(&output).map_err(|e| format!("unreadable reply: {}", e))
+    }
+
+    // for methods without output, a broken plugin has been logged already.
+    fn invoke_unit<I : Serialize>(&mut self, method : i32, input : &I) {
+        let _ = self.invoke_raw(method, input);
+    }
+
+    fn query_method(&mut self, method : i32, query : &str) -> PluginResult {
+        self.invoke(method, &query).unwrap_or_else(PluginResult::Error)
+    }
+}
+
+impl Plugin for WasmPlugin {
+    fn query(&mut self, query : &str) -> PluginResult {
+        self.query_method(method::QUERY, query)
+    }
+
+    fn query_return(&mut self, query : &str) -> PluginResult {
+        self.query_method(method::QUERY_RETURN, query)
+    }
+
+    fn action_request(&mut self, row : &PuszRowIdentifier, action : &CustomAction) -> PluginResult {
+        self.invoke(method::ACTION_REQUEST, &(row, action)).unwrap_or_else(PluginResult::Error)
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn settings(&self) -> PluginSettings {
+        self.settings.clone()
+    }
+
+    fn on_subscribed_event(&mut self, event : &PluginEvent) {
+        self.invoke_unit(method::ON_SUBSCRIBED_EVENT, event)
+    }
+
+    fn save_state(&mut self) -> Option<String> {
+        self.invoke(method::SAVE_STATE, &()).unwrap_or_else(|err| {
+            warn!("plugin {} couldnt save its state: {}", self.name, err);
+            None
+        })
+    }
+
+    fn restore_state(&mut self, state : &str) {
+        self.invoke_unit(method::RESTORE_STATE, &state)
+    }
+}
+
+impl std::fmt::Debug for WasmPlugin {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "WasmPlugin({})", self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use plugin_interface::{PuszRowBuilder, PuszRowIdentifier};
+    use crate::plugin_host::PluginHosts;
+
+    fn manifest(capabilities : &str) -> PluginManifest {
+        PluginManifest::parse(&format!("name = 'echo'\nversion = '0.1.0'\ninterface = '^1.0'\ncapabilities = [{}]", capabilities)).unwrap()
+    }
+
+    fn context() -> PluginContext {
+        PluginContext::new(BTreeMap::new(), std::env::temp_dir().join(format!("pusz_wasm_plugin_tests_{}", std::process::id())))
+    }
+
+    fn wat_string(json : &str) -> String {
+        json.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+
+    // module answering LOAD with `load_reply` and every other method with `reply`, `body` runs before that.
+    fn module(reply : &str, imports : &str, body : &str) -> String {
+        let load_reply = r#"{"Ok":["echo",{"requies_explicit_query":false,"interested_in_clipboard":false}]}"#;
+        format!(r#"(module
+            {imports}
+            (memory (export "memory") 1)
+            (global $next (mut i32) (i32.const 8192))
+            (data (i32.const 0) "{load}")
+            (data (i32.const 4096) "{reply}")
+            (func (export "pusz_abi_version") (result i32) i32.const {abi})
+            (func (export "pusz_alloc") (param $len i32) (result i32)
+                global.get $next
+                global.get $next
+                local.get $len
+                i32.add
+                global.set $next)
+            (func (export "pusz_free") (param i32 i32))
+            (func (export "pusz_call") (param $method i32) (param $ptr i32) (param $len i32) (result i64)
+                local.get $method
+                i32.eqz
+                if (result i64)
+                    i64.const {load_len}
+                else
+                    {body}
+                    i64.const {reply_packed}
+                end))"#,
+            imports = imports,
+            load = wat_string(load_reply),
+            reply = wat_string(reply),
+            abi = wasm::ABI_VERSION,
+            load_len = load_reply.len(),
+            body = body,
+            reply_packed = pack(4096, reply.len() as u32))
+    }
+
+    fn load(wat : &str, manifest : &PluginManifest) -> Result<WasmPlugin, String> {
+        WasmPlugin::from_bytes(wat.as_bytes(), manifest, &context())
+    }
+
+    #[test]
+    fn queries_cross_into_the_module() {
+        let row = PuszRowBuilder::new("hello".to_owned(), PuszRowIdentifier::new("echo", "hello".to_owned())).build().unwrap();
+        let reply = serde_json::to_string(&PluginResult::Ok(vec![row.clone()])).unwrap();
+
+        let mut plugin = load(&module(&reply, "", ""), &manifest("")).unwrap();
+
+        assert_eq!("echo", plugin.name());
+        assert!(!plugin.settings().requies_explicit_query);
+        assert_eq!(PluginResult::Ok(vec![row]), plugin.query("hel"));
+    }
+
+    #[test]
+    fn host_functions_need_the_capability() {
+        let imports = r#"(import "pusz" "storage_set" (func $set (param i32 i32 i32 i32)))"#;
+        let err = load(&module("null", imports, ""), &manifest("")).unwrap_err();
+        assert_eq!("imports pusz.storage_set but its manifest doesnt ask for the storage capability", err);
+        assert!(load(&module("null", imports, ""), &manifest("'storage'")).is_ok());
+
+        let imports = r#"(import "pusz" "clipboard_get" (func $get (result i64)))"#;
+        let err = load(&module("null", imports, ""), &manifest("'storage'")).unwrap_err();
+        assert_eq!("imports pusz.clipboard_get but its manifest doesnt ask for the clipboard capability", err);
+        assert!(load(&module("null", imports, ""), &manifest("'clipboard'")).is_ok());
+
+        let err = load(&module("null", r#"(import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))"#, ""), &manifest("")).unwrap_err();
+        assert_eq!("imports wasi_snapshot_preview1.fd_write which pusz doesnt provide", err);
+    }
+
+    #[test]
+    fn storage_goes_to_the_plugin_store() {
+        let root = std::env::temp_dir().join(format!("pusz_wasm_plugin_tests_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let hosts = PluginHosts::fake(&root);
+        let context = PluginContext::new(BTreeMap::new(), root.clone()).with_host(hosts.for_plugin("echo"));
+
+        // stores the first two bytes of the query under the key "k", from data at 4000.
+        let imports = r#"(import "pusz" "storage_set" (func $set (param i32 i32 i32 i32)))"#;
+        let body = "i32.const 4000 i32.const 1 local.get $ptr i32.const 2 call $set";
+        let wat = module(r#""None""#, imports, body).replace("(data (i32.const 0)", "(data (i32.const 4000) \"k\")\n(data (i32.const 0)");
+
+        let mut plugin = WasmPlugin::from_bytes(wat.as_bytes(), &manifest("'storage'"), &context).unwrap();
+        assert_eq!(PluginResult::None, plugin.query("x"));
+        assert_eq!(Some(b"\"x".to_vec()), hosts.for_plugin("echo").kv_get("k").unwrap());
+    }
+
+    #[test]
+    fn clipboard_goes_through_the_host() {
+        let hosts = PluginHosts::fake(Path::new("data"));
+        let context = PluginContext::new(BTreeMap::new(), "data".into()).with_host(hosts.for_plugin("echo"));
+        let clipboard = hosts.for_plugin("clip");
+
+        // replies with whatever is on the clipboard.
+        let imports = r#"(import "pusz" "clipboard_get" (func $get (result i64)))"#;
+        let mut plugin = WasmPlugin::from_bytes(module("null", imports, "call $get return").as_bytes(), &manifest("'clipboard'"), &context).unwrap();
+        clipboard.set_clipboard(r#"{"Error":"copied"}"#).unwrap();
+        assert_eq!(PluginResult::Error("copied".to_owned()), plugin.query("x"));
+
+        // copies the query.
+        let imports = r#"(import "pusz" "clipboard_set" (func $set (param i32 i32)))"#;
+        let mut plugin = WasmPlugin::from_bytes(module(r#""None""#, imports, "local.get $ptr local.get $len call $set").as_bytes(), &manifest("'clipboard'"), &context).unwrap();
+        assert_eq!(PluginResult::None, plugin.query("x"));
+        assert_eq!(Some("\"x\"".to_owned()), clipboard.clipboard().unwrap());
+    }
+
+    #[test]
+    fn endless_loop_runs_out_of_fuel() {
+        let mut plugin = load(&module("null", "", "(loop br 0)"), &manifest("")).unwrap();
+        plugin.fuel_per_call = 1_000_000;
+
+        assert_eq!(PluginResult::Error("ran out of fuel".to_owned()), plugin.query("x"));
+        assert_eq!(PluginResult::Error("echo is disabled".to_owned()), plugin.query("x"));
+    }
+
+    #[test]
+    fn abi_version_is_checked() {
+        let wat = module("null", "", "").replace(&format!("i32.const {})", wasm::ABI_VERSION), "i32.const 99)");
+        assert_eq!(format!("module built for wasm abi version 99, pusz supports {}", wasm::ABI_VERSION), load(&wat, &manifest("")).unwrap_err());
+    }
+}
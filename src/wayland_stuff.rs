@@ -0,0 +1,403 @@
+use std::sync::{Mutex, Arc};
+use std::sync::mpsc::{Sender, Receiver, self};
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{RawFd, FromRawFd};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::pipe2;
+
+use wayland_client::{Display, GlobalManager, Main};
+use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_protocols::wlr::unstable::data_control::v1::client::{
+    zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+    zwlr_data_control_offer_v1::{self, ZwlrDataControlOfferV1},
+    zwlr_data_control_source_v1::{self, ZwlrDataControlSourceV1},
+};
+
+use crate::platform::{Platform, PlatformError, BindHandler, ClipboardHandler, Hotkey};
+
+// in order of preference.
+const TEXT_MIME_TYPES : [&str; 5] = ["text/plain;charset=utf-8", "UTF8_STRING", "text/plain", "STRING", "TEXT"];
+
+const RECEIVE_TIMEOUT : Duration = Duration::from_millis(500);
+const GET_CLIPBOARD_TIMEOUT : Duration = Duration::from_secs(1);
+
+pub enum WaylandApiEvent {
+    SetClipboard { text : String },
+    GetClipboard { reply : Sender<Option<String>> },
+    AddClipboardListener { handler : ClipboardHandler },
+}
+
+#[derive(Default)]
+struct ClipboardState {
+    offer_mime_types : HashMap<u32, Vec<String>>,
+    selection : Option<ZwlrDataControlOfferV1>,
+    // set when the selection changed, listeners are notified outside of the dispatch.
+    selection_changed : bool,
+
+    owned_text : Option<String>,
+    owned_source : Option<Main<ZwlrDataControlSourceV1>>,
+
+    listeners : Vec<ClipboardHandler>,
+}
+
+// clipboard through wlr-data-control, the only way for a non focused client to see and set the selection.
+// there is no global hotkey protocol on wayland so hotkeys stay with the x11 backend (XWayland).
+pub struct WaylandPlatform {
+    tx : Mutex<Sender<WaylandApiEvent>>,
+    wakeup : Mutex<File>,
+    hotkeys : crate::x11_stuff::X11Platform,
+}
+
+impl WaylandPlatform {
+    pub fn connect() -> Result<Self, String> {
+        let (tx, rx) = mpsc::channel();
+        let (wakeup_read, wakeup_write) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK).map_err(|e| format!("wakeup pipe: {}", e))?;
+        // owned right away, so both ends get closed whichever way this goes.
+        let (wakeup_read, wakeup_write) = unsafe { (File::from_raw_fd(wakeup_read), File::from_raw_fd(wakeup_write)) };
+        let (tx_ready, rx_ready) = mpsc::channel();
+
+        thread::spawn(move || {
+            let connection = match Connection::new() {
+                Ok(connection) => {
+                    tx_ready.send(Ok(())).expect("ready send failure");
+                    connection
+                },
+                Err(err) => {
+                    tx_ready.send(Err(err)).expect("ready send failure");
+                    return;
+                }
+            };
+
+            connection.run(rx, wakeup_read);
+        });
+
+        rx_ready.recv().map_err(|e| format!("wayland thread died: {}", e))??;
+
+        Ok(Self {
+            tx : Mutex::new(tx),
+            wakeup : Mutex::new(wakeup_write),
+            hotkeys : crate::x11_stuff::X11Platform,
+        })
+    }
+
+    // hotkeys and paste go through XWayland, a session without it has neither.
+    fn xwayland(&self) -> Result<&crate::x11_stuff::X11Platform, PlatformError> {
+        crate::x11_stuff::HotkeyData::connect().map_err(|err| PlatformError::Unsupported(format!("needs XWayland, which is not running ({})", err)))?;
+        Ok(&self.hotkeys)
+    }
+
+    pub fn post_event(&self, event : WaylandApiEvent) -> Result<(), PlatformError> {
+        self.tx.lock().unwrap().send(event).map_err(|_| PlatformError::Failure("wayland thread is gone".to_owned()))?;
+        let _ = self.wakeup.lock().unwrap().write(&[0]);
+        Ok(())
+    }
+}
+
+impl Platform for WaylandPlatform {
+    fn register_hotkey(&self, id : i32, hotkey : Hotkey, handler : BindHandler) -> Result<(), PlatformError> {
+        self.xwayland()?.register_hotkey(id, hotkey, handler)
+    }
+
+    fn unregister_hotkey(&self, id : i32) {
+        self.hotkeys.unregister_hotkey(id)
+    }
+
+    fn add_clipboard_listener(&self, handler : ClipboardHandler) -> Result<(), PlatformError> {
+        self.post_event(WaylandApiEvent::AddClipboardListener { handler })
+    }
+
+    fn get_clipboard(&self) -> Result<Option<String>, PlatformError> {
+        let (reply, rx) = mpsc::channel();
+        self.post_event(WaylandApiEvent::GetClipboard { reply })?;
+        Ok(rx.recv_timeout(GET_CLIPBOARD_TIMEOUT).ok().and_then(|text| text))
+    }
+
+    fn set_clipboard(&self, text : &str) -> Result<(), PlatformError> {
+        self.post_event(WaylandApiEvent::SetClipboard { text : text.to_owned() })
+    }
+
+    fn bring_to_front(&self) {
+        // compositors decide on activation themselves, gtk present() is all we can do.
+    }
+
+    // wayland has no way to type into other clients, this only reaches XWayland windows.
+    fn paste(&self) -> Result<(), PlatformError> {
+        self.xwayland()?.paste()
+    }
+}
+
+struct Connection {
+    display : Display,
+    event_queue : wayland_client::EventQueue,
+    manager : Main<ZwlrDataControlManagerV1>,
+    device : Main<ZwlrDataControlDeviceV1>,
+    state : Rc<RefCell<ClipboardState>>,
+}
+
+impl Connection {
+    fn new() -> Result<Self, String> {
+        let display = Display::connect_to_env().map_err(|e| format!("couldnt connect to wayland: {}", e))?;
+        let mut event_queue = display.create_event_queue();
+        let attached = display.attach(event_queue.token());
+        let globals = GlobalManager::new(&attached);
+
+        event_queue.sync_roundtrip(&mut (), |_, _, _| {}).map_err(|e| format!("wayland roundtrip: {}", e))?;
+
+        let manager = globals.instantiate_range::<ZwlrDataControlManagerV1>(1, 2)
+            .map_err(|_| "compositor does not support wlr-data-control".to_owned())?;
+        let seat = globals.instantiate_range::<WlSeat>(1, 7)
+            .map_err(|_| "compositor has no seat".to_owned())?;
+
+        let state = Rc::new(RefCell::new(ClipboardState::default()));
+        let device = manager.get_data_device(&seat);
+
+        {
+            let state_clone = state.clone();
+            device.quick_assign(move |_, event, _| {
+                let state = &mut state_clone.borrow_mut();
+                match event {
+                    zwlr_data_control_device_v1::Event::DataOffer { id } => {
+                        state.offer_mime_types.insert(id.as_ref().id(), vec![]);
+
+                        let offer_state = state_clone.clone();
+                        id.quick_assign(move |offer, event, _| {
+                            if let zwlr_data_control_offer_v1::Event::Offer { mime_type } = event {
+                                offer_state.borrow_mut().offer_mime_types.entry(offer.as_ref().id()).or_default().push(mime_type);
+                            }
+                        });
+                    },
+                    zwlr_data_control_device_v1::Event::Selection { id } => {
+                        if let Some(previous) = state.selection.take() {
+                            state.offer_mime_types.remove(&previous.as_ref().id());
+                            previous.destroy();
+                        }
+                        state.selection = id;
+                        // a selection we own is what set_clipboard did, do not report it back.
+                        state.selection_changed = state.owned_text.is_none();
+                    },
+                    zwlr_data_control_device_v1::Event::Finished => {
+                        warn!("wayland data device is gone, clipboard history will not be recorded.");
+                    },
+                    _ => {},
+                }
+            });
+        }
+
+        event_queue.sync_roundtrip(&mut (), |_, _, _| {}).map_err(|e| format!("wayland roundtrip: {}", e))?;
+
+        Ok(Self {
+            display,
+            event_queue,
+            manager,
+            device,
+            state,
+        })
+    }
+
+    fn run(mut self, rx : Receiver<WaylandApiEvent>, mut wakeup : File) {
+        loop {
+            if let Err(err) = self.event_queue.dispatch_pending(&mut (), |_, _, _| {}) {
+                error!("wayland dispatch failed: {}", err);
+                return;
+            }
+
+            self.notify_listeners();
+
+            while let Ok(request) = rx.try_recv() {
+                match request {
+                    WaylandApiEvent::SetClipboard { text } => self.set_selection(text),
+                    WaylandApiEvent::GetClipboard { reply } => {
+                        let _ = reply.send(self.selection_text());
+                    },
+                    WaylandApiEvent::AddClipboardListener { handler } => {
+                        self.state.borrow_mut().listeners.push(handler);
+                    },
+                }
+            }
+
+            if let Err(err) = self.display.flush() {
+                error!("wayland flush failed: {}", err);
+                return;
+            }
+
+            if let Some(guard) = self.event_queue.prepare_read() {
+                let mut fds = [PollFd::new(self.display.get_connection_fd(), PollFlags::POLLIN),
+                               PollFd::new(wakeup_fd(&wakeup), PollFlags::POLLIN)];
+                let _ = poll(&mut fds, -1);
+
+                if is_readable(&fds[0]) {
+                    if let Err(err) = guard.read_events() {
+                        error!("wayland read failed: {}", err);
+                        return;
+                    }
+                } else {
+                    guard.cancel();
+                }
+
+                if is_readable(&fds[1]) {
+                    let mut drain = [0u8; 64];
+                    while let Ok(read) = wakeup.read(&mut drain) {
+                        if read == 0 {
+                            // every WaylandPlatform handle is gone.
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn notify_listeners(&mut self) {
+        if !::std::mem::replace(&mut self.state.borrow_mut().selection_changed, false) {
+            return;
+        }
+
+        if self.state.borrow().listeners.is_empty() {
+            return;
+        }
+
+        if let Some(text) = self.selection_text() {
+            let listeners = self.state.borrow().listeners.clone();
+            for listener in &listeners {
+                listener(text.clone());
+            }
+        }
+    }
+
+    fn set_selection(&mut self, text : String) {
+        let source = self.manager.create_data_source();
+        for mime_type in TEXT_MIME_TYPES.iter() {
+            source.offer(mime_type.to_string());
+        }
+
+        {
+            let state = self.state.clone();
+            source.quick_assign(move |source, event, _| {
+                match event {
+                    zwlr_data_control_source_v1::Event::Send { mime_type : _, fd } => {
+                        let mut file = unsafe { File::from_raw_fd(fd) };
+                        // the receiver reads at its own pace, a slow one must not stall the event loop.
+                        if let Some(text) = state.borrow().owned_text.clone() {
+                            thread::spawn(move || {
+                                let _ = file.write_all(text.as_bytes());
+                            });
+                        }
+                    },
+                    zwlr_data_control_source_v1::Event::Cancelled => {
+                        // someone else owns the clipboard now.
+                        let state = &mut state.borrow_mut();
+                        if state.owned_source.as_ref().map_or(false, |owned| owned.as_ref().equals(source.as_ref())) {
+                            state.owned_text = None;
+                            state.owned_source = None;
+                        }
+                        source.destroy();
+                    },
+                    _ => {},
+                }
+            });
+        }
+
+        self.device.set_selection(Some(&source));
+
+        let state = &mut self.state.borrow_mut();
+        if let Some(previous) = state.owned_source.replace(source) {
+            previous.destroy();
+        }
+        state.owned_text = Some(text);
+    }
+
+    fn selection_text(&self) -> Option<String> {
+        let state = self.state.borrow();
+        if let Some(text) = &state.owned_text {
+            return Some(text.clone());
+        }
+
+        let offer = state.selection.as_ref()?;
+        let offered = state.offer_mime_types.get(&offer.as_ref().id())?;
+        let mime_type = TEXT_MIME_TYPES.iter().find(|mime_type| offered.iter().any(|offered| offered == *mime_type))?;
+
+        let (read, write) = pipe2(OFlag::O_CLOEXEC).ok()?;
+        offer.receive(mime_type.to_string(), write);
+        let _ = self.display.flush();
+        let _ = nix::unistd::close(write);
+
+        read_with_timeout(unsafe { File::from_raw_fd(read) }, RECEIVE_TIMEOUT)
+    }
+}
+
+fn wakeup_fd(file : &File) -> RawFd {
+    use std::os::unix::io::AsRawFd;
+    file.as_raw_fd()
+}
+
+fn is_readable(fd : &PollFd) -> bool {
+    fd.revents().map_or(false, |events| events.intersects(PollFlags::POLLIN | PollFlags::POLLHUP))
+}
+
+// the sending client may never write anything, do not let it hang the clipboard thread.
+fn read_with_timeout(mut file : File, timeout : Duration) -> Option<String> {
+    let deadline = Instant::now() + timeout;
+    let mut content = vec![];
+    let mut buffer = [0u8; 4096];
+
+    loop {
+        let remaining = deadline.checked_duration_since(Instant::now())?;
+        let mut fds = [PollFd::new(wakeup_fd(&file), PollFlags::POLLIN)];
+        if poll(&mut fds, remaining.as_millis() as i32).ok()? == 0 {
+            warn!("clipboard owner did not send the content in time.");
+            return None;
+        }
+
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => content.extend_from_slice(&buffer[..read]),
+            Err(_) => return None,
+        }
+    }
+
+    Some(String::from_utf8_lossy(&content).into_owned())
+}
+
+// these need a compositor with wlr-data-control, e.g. `sway --headless` or `cage`, with WAYLAND_DISPLAY set:
+// `cargo test -- --ignored`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect() -> WaylandPlatform {
+        WaylandPlatform::connect().expect("compositor without wlr-data-control?")
+    }
+
+    #[test]
+    #[ignore]
+    fn set_then_get_clipboard() {
+        let platform = connect();
+        platform.set_clipboard("pusz wayland roundtrip").unwrap();
+        assert_eq!(Ok(Some("pusz wayland roundtrip".to_owned())), platform.get_clipboard());
+    }
+
+    #[test]
+    #[ignore]
+    fn listener_sees_other_client_selection() {
+        let (platform, other_client) = (connect(), connect());
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        platform.add_clipboard_listener(Arc::new(move |text| {
+            let _ = tx.lock().unwrap().send(text);
+        })).unwrap();
+
+        other_client.set_clipboard("from another client").unwrap();
+
+        assert_eq!("from another client", rx.recv_timeout(Duration::from_secs(5)).expect("no clipboard notification"));
+        assert_eq!(Ok(Some("from another client".to_owned())), platform.get_clipboard());
+    }
+}
@@ -1,25 +1,23 @@
-#![allow(unused)]
-
-use std::sync::atomic;
-use std::sync::{self, Mutex, Arc};
-use std::sync::mpsc::{Sender, Receiver, self, channel};
+use std::sync::Mutex;
+use std::sync::mpsc::{Sender, self};
 use std::collections::HashMap;
+use std::time::Duration;
 use clipboard_win::{get_clipboard_string, set_clipboard_string};
-use gdk::Window;
 
-pub type BindHandler = Arc<dyn Fn(i32) + Send + Sync + 'static>;
-pub type ClipboardHandler = Arc<dyn Fn(String) + Send + Sync + 'static>;
+use crate::platform::{Platform, PlatformError, BindHandler, ClipboardHandler, Key, Modifiers, Hotkey};
+
+const REGISTER_HOTKEY_TIMEOUT : Duration = Duration::from_secs(2);
 
-#[allow(unused)]
 pub enum WindowsApiEvent {
-    HotkeyRegister { id : i32, modifiers : u32, vk : u32, handler : BindHandler},
+    HotkeyRegister { id : i32, hotkey : Hotkey, handler : BindHandler, reply : Sender<Result<(), PlatformError>> },
+    HotkeyUnregister { id : i32 },
 
     SetClipboard { text : String },
     AddClipboardListener { handler  : ClipboardHandler},
+    Paste,
 }
 
 pub struct HotkeyData {
-    thread_handle : Option<::std::thread::JoinHandle<()>>,
     thread_id : usize,
     tx : Option<Sender<WindowsApiEvent>>,
 }
@@ -30,9 +28,10 @@ pub struct HotkeyProxy {
 }
 
 impl HotkeyProxy {
-    pub fn post_event(&self, event : WindowsApiEvent) {
-        self.tx.send(event).expect("post event failure");
+    pub fn post_event(&self, event : WindowsApiEvent) -> Result<(), PlatformError> {
+        self.tx.send(event).map_err(|_| PlatformError::Failure("windows api thread is gone".to_owned()))?;
         unsafe { winapi::um::winuser::PostThreadMessageA(self.thread_id as u32, 30000, 0, 0) };
+        Ok(())
     }
 }
 
@@ -43,8 +42,7 @@ pub enum ReceivedMessage {
 }
 
 fn get_single_message() -> ReceivedMessage {
-    use winapi::um::winuser::{LPMSG, GetMessageA, MSG, *, TranslateMessage, DispatchMessageA};
-    use std::default::Default;
+    use winapi::um::winuser::*;
     let mut msg = Default::default();
 
     let mut result = ReceivedMessage::Nothing;
@@ -72,26 +70,65 @@ fn get_single_message() -> ReceivedMessage {
 
 }
 
+fn key_input(vk : i32, flags : u32) -> winapi::um::winuser::INPUT {
+    use winapi::um::winuser::*;
+    unsafe {
+        let mut input : INPUT = ::std::mem::zeroed();
+        input.type_ = INPUT_KEYBOARD;
+        *input.u.ki_mut() = KEYBDINPUT { wVk : vk as u16, wScan : 0, dwFlags : flags, time : 0, dwExtraInfo : 0 };
+        input
+    }
+}
+
+// pusz got the last input, so windows lets it hand the foreground back.
+fn paste_into(window : winapi::shared::windef::HWND) {
+    use winapi::um::winuser::*;
+    if unsafe { SetForegroundWindow(window) } == 0 {
+        warn!("couldnt bring back window {:?}, pasting anyway", window);
+    }
+
+    let mut inputs = [key_input(VK_CONTROL, 0), key_input('V' as i32, 0), key_input('V' as i32, KEYEVENTF_KEYUP), key_input(VK_CONTROL, KEYEVENTF_KEYUP)];
+    let sent = unsafe { SendInput(inputs.len() as u32, inputs.as_mut_ptr(), ::std::mem::size_of::<INPUT>() as i32) };
+    if sent as usize != inputs.len() {
+        warn!("SendInput sent {} of {} keys", sent, inputs.len());
+    }
+}
+
 fn to_wstring(str: &str) -> Vec<u16> {
     use std::os::windows::ffi::OsStrExt;
     ::std::ffi::OsStr::new(str).encode_wide().chain(Some(0).into_iter()).collect()
 }
 
 impl HotkeyData {
-    pub fn do_it(hotkey : WindowsApiEvent) {
-        Self::init().post_event(hotkey);
+    pub fn do_it(hotkey : WindowsApiEvent) -> Result<(), PlatformError> {
+        Self::init().post_event(hotkey)
     }
 
-    pub fn set_clipboard(text: &str) {
-        Self::do_it(WindowsApiEvent::SetClipboard { text : text.to_owned()});
+    pub fn set_clipboard(text: &str) -> Result<(), PlatformError> {
+        Self::do_it(WindowsApiEvent::SetClipboard { text : text.to_owned()})
     }
 
-    pub fn get_clipboard() -> Option<String> {
-        get_clipboard_string().ok()
+    // an empty or non text clipboard is an error for clipboard_win too.
+    pub fn get_clipboard() -> Result<Option<String>, PlatformError> {
+        Ok(get_clipboard_string().ok())
     }
 
-    pub fn register_hotkey( id : i32, key : Key, modifiers : Modifier, handler : BindHandler) {
-        Self::do_it(WindowsApiEvent::HotkeyRegister { id, handler, vk : key.v(), modifiers : modifiers.v()} )
+    pub fn add_clipboard_listener(handler : ClipboardHandler) -> Result<(), PlatformError> {
+        Self::do_it(WindowsApiEvent::AddClipboardListener { handler })
+    }
+
+    pub fn register_hotkey( id : i32, hotkey : Hotkey, handler : BindHandler) -> Result<(), PlatformError> {
+        let (reply, rx) = mpsc::channel();
+        Self::do_it(WindowsApiEvent::HotkeyRegister { id, hotkey, handler, reply })?;
+        rx.recv_timeout(REGISTER_HOTKEY_TIMEOUT).unwrap_or_else(|_| Err(PlatformError::Failure("windows api thread did not answer".to_owned())))
+    }
+
+    pub fn unregister_hotkey(id : i32) {
+        let _ = Self::do_it(WindowsApiEvent::HotkeyUnregister { id });
+    }
+
+    pub fn paste() -> Result<(), PlatformError> {
+        Self::do_it(WindowsApiEvent::Paste)
     }
 
     fn init() -> HotkeyProxy {
@@ -99,7 +136,7 @@ impl HotkeyData {
         if context.is_none() {
             let ( tx_tid, rx_tid) =  mpsc::channel();
             let (tx, rx) = mpsc::channel();
-            let thread_handle = Some(::std::thread::spawn(move || {
+            ::std::thread::spawn(move || {
                 let win_thread_id = unsafe { winapi::um::processthreadsapi::GetCurrentThreadId() } as usize;
                 if win_thread_id == 0 {
                     panic!("win_thread_id == 0?");
@@ -110,6 +147,8 @@ impl HotkeyData {
                 let mut clipboard_handlers : Vec<ClipboardHandler> = vec![];
 
                 let mut last_set_clipboard = String::new();
+                // foreground window when a hotkey last fired.
+                let mut focus_before : winapi::shared::windef::HWND = ::std::ptr::null_mut();
 
                 let hwnd = unsafe {
                     let class_name = to_wstring("meh_window");
@@ -151,10 +190,10 @@ impl HotkeyData {
                     hwnd
                 };
 
-                let win_pid = unsafe { winapi::um::processthreadsapi::GetCurrentProcessId() } ;
                 loop {
                     match get_single_message() {
                         ReceivedMessage::Hotkey { id } => {
+                            focus_before = unsafe { winapi::um::winuser::GetForegroundWindow() };
                             if let Some(handler) = handlers.get(&id) {
                                 handler(id);
                             }
@@ -175,14 +214,32 @@ impl HotkeyData {
 
                     if let Ok(request) = rx.try_recv() {
                         match request {
-                            WindowsApiEvent::HotkeyRegister { id, modifiers, vk, handler } => {
-                                handlers.insert(id, handler);
-                                unsafe {
+                            WindowsApiEvent::HotkeyRegister { id, hotkey, handler, reply } => {
+                                let registered = unsafe {
                                     winapi::um::winuser::RegisterHotKey(
                                         0 as winapi::shared::windef::HWND,
                                         id,
-                                        modifiers, vk
-                                    );
+                                        modifier_flags(hotkey.modifiers), vk(hotkey.key)
+                                    )
+                                };
+
+                                if registered != 0 {
+                                    handlers.insert(id, handler);
+                                    let _ = reply.send(Ok(()));
+                                } else {
+                                    let error = unsafe { winapi::um::errhandlingapi::GetLastError() };
+                                    let _ = reply.send(Err(if error == winapi::shared::winerror::ERROR_HOTKEY_ALREADY_REGISTERED {
+                                        PlatformError::HotkeyTaken(hotkey)
+                                    } else {
+                                        PlatformError::Failure(format!("RegisterHotKey failed with error {}", error))
+                                    }));
+                                }
+                            },
+                            WindowsApiEvent::HotkeyUnregister { id } => {
+                                if handlers.remove(&id).is_some() {
+                                    unsafe {
+                                        winapi::um::winuser::UnregisterHotKey(0 as winapi::shared::windef::HWND, id);
+                                    }
                                 }
                             },
                             WindowsApiEvent::AddClipboardListener { handler } => {
@@ -198,16 +255,22 @@ impl HotkeyData {
                                 last_set_clipboard = text.clone();
                                 let _ = set_clipboard_string(&text);
                             }
+                            WindowsApiEvent::Paste => {
+                                if focus_before.is_null() {
+                                    warn!("no window to paste into, pusz wasnt opened by a hotkey");
+                                } else {
+                                    paste_into(focus_before);
+                                }
+                            }
                         }
                     }
                 }
-            }));
+            });
 
             let thread_id = rx_tid.recv().expect("failed to recv thread_handle");
 
             *context = Some(HotkeyData {
                 thread_id,
-                thread_handle,
                 tx : Some(tx),
             });
         }
@@ -222,62 +285,147 @@ impl HotkeyData {
 
 }
 
-#[derive(Clone, Copy, PartialEq, Debug)]
-#[allow(unused)]
-pub enum Modifier {
-    None = 0,
-    Alt = 1,
-    Ctrl = 2,
-    Shift = 4,
-    Win = 8,
-}
+pub struct WindowsPlatform;
 
-impl Modifier {
-    pub fn v(self) -> u32 {
-        self as u32
+impl Platform for WindowsPlatform {
+    fn register_hotkey(&self, id : i32, hotkey : Hotkey, handler : BindHandler) -> Result<(), PlatformError> {
+        HotkeyData::register_hotkey(id, hotkey, handler)
+    }
+
+    fn unregister_hotkey(&self, id : i32) {
+        HotkeyData::unregister_hotkey(id)
+    }
+
+    fn add_clipboard_listener(&self, handler : ClipboardHandler) -> Result<(), PlatformError> {
+        HotkeyData::add_clipboard_listener(handler)
+    }
+
+    fn get_clipboard(&self) -> Result<Option<String>, PlatformError> {
+        HotkeyData::get_clipboard()
     }
-}
 
-#[derive(Clone, Copy, PartialEq, Debug)]
-#[allow(unused)]
-pub enum Key {
-    Return = winapi::um::winuser::VK_RETURN as isize,
-    Control = winapi::um::winuser::VK_CONTROL as isize,
-    Alt = winapi::um::winuser::VK_MENU as isize,
-    Shift = winapi::um::winuser::VK_SHIFT as isize,
-    F1 = winapi::um::winuser::VK_F1 as isize,
-    A = 'A' as isize,
-    B = 'B' as isize,
-    C = 'C' as isize,
-    D = 'D' as isize,
-    E = 'E' as isize,
-    F = 'F' as isize,
-    G = 'G' as isize,
-    H = 'H' as isize,
-    I = 'I' as isize,
-    J = 'J' as isize,
-    K = 'K' as isize,
-    L = 'L' as isize,
-    M = 'M' as isize,
-    N = 'N' as isize,
-    O = 'O' as isize,
-    P = 'P' as isize,
-    Q = 'Q' as isize,
-    R = 'R' as isize,
-    S = 'S' as isize,
-    T = 'T' as isize,
-    U = 'U' as isize,
-    V = 'V' as isize,
-    W = 'W' as isize,
-    X = 'X' as isize,
-    Y = 'Y' as isize,
-    Z = 'Z' as isize,
+    fn set_clipboard(&self, text : &str) -> Result<(), PlatformError> {
+        HotkeyData::set_clipboard(text)
+    }
+
+    fn bring_to_front(&self) {
+        // windows refuses SetForegroundWindow to processes that did not receive the last input.
+        unsafe { winapi::um::winuser::AllowSetForegroundWindow(winapi::um::winuser::ASFW_ANY) };
+    }
+
+    fn paste(&self) -> Result<(), PlatformError> {
+        HotkeyData::paste()
+    }
 }
 
-impl Key {
-    pub fn v(self) -> u32 {
-        self as u32
+fn modifier_flags(modifiers : Modifiers) -> u32 {
+    use winapi::um::winuser::*;
+    let mut flags = 0;
+    if modifiers.ctrl {
+        flags |= MOD_CONTROL;
     }
+    if modifiers.alt {
+        flags |= MOD_ALT;
+    }
+    if modifiers.shift {
+        flags |= MOD_SHIFT;
+    }
+    if modifiers.win {
+        flags |= MOD_WIN;
+    }
+    flags as u32
+}
+
+fn vk(key : Key) -> u32 {
+    use winapi::um::winuser::*;
+    (match key {
+        Key::A => 'A' as i32,
+        Key::B => 'B' as i32,
+        Key::C => 'C' as i32,
+        Key::D => 'D' as i32,
+        Key::E => 'E' as i32,
+        Key::F => 'F' as i32,
+        Key::G => 'G' as i32,
+        Key::H => 'H' as i32,
+        Key::I => 'I' as i32,
+        Key::J => 'J' as i32,
+        Key::K => 'K' as i32,
+        Key::L => 'L' as i32,
+        Key::M => 'M' as i32,
+        Key::N => 'N' as i32,
+        Key::O => 'O' as i32,
+        Key::P => 'P' as i32,
+        Key::Q => 'Q' as i32,
+        Key::R => 'R' as i32,
+        Key::S => 'S' as i32,
+        Key::T => 'T' as i32,
+        Key::U => 'U' as i32,
+        Key::V => 'V' as i32,
+        Key::W => 'W' as i32,
+        Key::X => 'X' as i32,
+        Key::Y => 'Y' as i32,
+        Key::Z => 'Z' as i32,
+        Key::Num0 => '0' as i32,
+        Key::Num1 => '1' as i32,
+        Key::Num2 => '2' as i32,
+        Key::Num3 => '3' as i32,
+        Key::Num4 => '4' as i32,
+        Key::Num5 => '5' as i32,
+        Key::Num6 => '6' as i32,
+        Key::Num7 => '7' as i32,
+        Key::Num8 => '8' as i32,
+        Key::Num9 => '9' as i32,
+        Key::F1 => VK_F1,
+        Key::F2 => VK_F2,
+        Key::F3 => VK_F3,
+        Key::F4 => VK_F4,
+        Key::F5 => VK_F5,
+        Key::F6 => VK_F6,
+        Key::F7 => VK_F7,
+        Key::F8 => VK_F8,
+        Key::F9 => VK_F9,
+        Key::F10 => VK_F10,
+        Key::F11 => VK_F11,
+        Key::F12 => VK_F12,
+        Key::F13 => VK_F13,
+        Key::F14 => VK_F14,
+        Key::F15 => VK_F15,
+        Key::F16 => VK_F16,
+        Key::F17 => VK_F17,
+        Key::F18 => VK_F18,
+        Key::F19 => VK_F19,
+        Key::F20 => VK_F20,
+        Key::F21 => VK_F21,
+        Key::F22 => VK_F22,
+        Key::F23 => VK_F23,
+        Key::F24 => VK_F24,
+        Key::Space => VK_SPACE,
+        Key::Tab => VK_TAB,
+        Key::Return => VK_RETURN,
+        Key::Escape => VK_ESCAPE,
+        Key::Backspace => VK_BACK,
+        Key::Delete => VK_DELETE,
+        Key::Insert => VK_INSERT,
+        Key::Home => VK_HOME,
+        Key::End => VK_END,
+        Key::PageUp => VK_PRIOR,
+        Key::PageDown => VK_NEXT,
+        Key::Up => VK_UP,
+        Key::Down => VK_DOWN,
+        Key::Left => VK_LEFT,
+        Key::Right => VK_RIGHT,
+        Key::Minus => VK_OEM_MINUS,
+        Key::Equal => VK_OEM_PLUS,
+        Key::LeftBracket => VK_OEM_4,
+        Key::RightBracket => VK_OEM_6,
+        Key::Backslash => VK_OEM_5,
+        Key::Semicolon => VK_OEM_1,
+        Key::Apostrophe => VK_OEM_7,
+        Key::Grave => VK_OEM_3,
+        Key::Comma => VK_OEM_COMMA,
+        Key::Period => VK_OEM_PERIOD,
+        Key::Slash => VK_OEM_2,
+    }) as u32
 }
 
 lazy_static! {
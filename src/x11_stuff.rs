@@ -0,0 +1,750 @@
+use std::sync::{Mutex, Once};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Sender, self};
+use std::collections::{HashMap, VecDeque};
+use std::os::raw::{c_int, c_uint, c_ulong, c_long, c_uchar};
+use std::time::Duration;
+use std::ffi::CString;
+
+use x11::xlib;
+use x11::xtest;
+use x11::keysym::*;
+
+use crate::platform::{Platform, PlatformError, BindHandler, ClipboardHandler, Key, Modifiers, Hotkey};
+
+// x11 crate does not ship XFixes bindings, we only need these two.
+#[link(name = "Xfixes")]
+extern "C" {
+    fn XFixesQueryExtension(display : *mut xlib::Display, event_base : *mut c_int, error_base : *mut c_int) -> c_int;
+    fn XFixesSelectSelectionInput(display : *mut xlib::Display, window : xlib::Window, selection : xlib::Atom, event_mask : c_ulong);
+}
+
+const XFIXES_SELECTION_NOTIFY : c_int = 0;
+const XFIXES_SET_SELECTION_OWNER_NOTIFY_MASK : c_ulong = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XFixesSelectionNotifyEvent {
+    type_ : c_int,
+    serial : c_ulong,
+    send_event : c_int,
+    display : *mut xlib::Display,
+    window : xlib::Window,
+    subtype : c_int,
+    owner : xlib::Window,
+    selection : xlib::Atom,
+    timestamp : xlib::Time,
+    selection_timestamp : xlib::Time,
+}
+
+// grabs are exact on modifiers so we have to grab every combination of the lock keys too.
+const LOCK_MASKS : [c_uint; 4] = [0, xlib::LockMask, xlib::Mod2Mask, xlib::LockMask | xlib::Mod2Mask];
+const MODIFIERS_MASK : c_uint = xlib::ShiftMask | xlib::ControlMask | xlib::Mod1Mask | xlib::Mod4Mask;
+
+const GET_CLIPBOARD_TIMEOUT : Duration = Duration::from_millis(500);
+// how long the window manager gets to hand the focus back before paste goes out anyway.
+const FOCUS_TIMEOUT : Duration = Duration::from_millis(500);
+const REGISTER_HOTKEY_TIMEOUT : Duration = Duration::from_secs(2);
+
+// set by the error handler, grabs are checked with XSync right after they are made.
+static ACCESS_DENIED : AtomicBool = AtomicBool::new(false);
+
+static INIT_THREADS : Once = Once::new();
+static INSTALL_ERROR_HANDLER : Once = Once::new();
+
+type ErrorHandler = unsafe extern "C" fn(*mut xlib::Display, *mut xlib::XErrorEvent) -> c_int;
+
+pub enum X11ApiEvent {
+    HotkeyRegister { id : i32, hotkey : Hotkey, handler : BindHandler, reply : Sender<Result<(), PlatformError>> },
+    HotkeyUnregister { id : i32 },
+
+    SetClipboard { text : String },
+    GetClipboard { reply : Sender<Option<String>> },
+    AddClipboardListener { handler  : ClipboardHandler},
+    Paste,
+}
+
+struct DisplayPtr(*mut xlib::Display);
+
+// main calls init_threads before anything else, so the connection can be used from any thread.
+unsafe impl Send for DisplayPtr {}
+
+pub struct HotkeyData {
+    // separate connection used only to wake the event thread up, analogue of PostThreadMessage.
+    wakeup_display : DisplayPtr,
+    window : xlib::Window,
+    tx : Option<Sender<X11ApiEvent>>,
+}
+
+pub struct HotkeyProxy {
+    wakeup_display : *mut xlib::Display,
+    window : xlib::Window,
+    tx : Sender<X11ApiEvent>,
+}
+
+impl HotkeyProxy {
+    pub fn post_event(&self, event : X11ApiEvent) -> Result<(), PlatformError> {
+        self.tx.send(event).map_err(|_| PlatformError::Failure("x11 thread is gone".to_owned()))?;
+        unsafe {
+            let mut wakeup : xlib::XEvent = ::std::mem::zeroed();
+            wakeup.client_message.type_ = xlib::ClientMessage;
+            wakeup.client_message.window = self.window;
+            wakeup.client_message.format = 32;
+            xlib::XSendEvent(self.wakeup_display, self.window, xlib::False, xlib::NoEventMask, &mut wakeup);
+            xlib::XFlush(self.wakeup_display);
+        }
+        Ok(())
+    }
+}
+
+struct Atoms {
+    clipboard : xlib::Atom,
+    targets : xlib::Atom,
+    utf8_string : xlib::Atom,
+    property : xlib::Atom,
+    active_window : xlib::Atom,
+}
+
+impl Atoms {
+    fn new(display : *mut xlib::Display) -> Self {
+        let intern = |name : &str| {
+            let name = CString::new(name).expect("atom name");
+            unsafe { xlib::XInternAtom(display, name.as_ptr(), xlib::False) }
+        };
+
+        Self {
+            clipboard : intern("CLIPBOARD"),
+            targets : intern("TARGETS"),
+            utf8_string : intern("UTF8_STRING"),
+            property : intern("PUSZ_SELECTION"),
+            active_window : intern("_NET_ACTIVE_WINDOW"),
+        }
+    }
+}
+
+// who is waiting for the result of XConvertSelection, answers arrive in request order.
+enum PendingConversion {
+    Listeners,
+    Get(Sender<Option<String>>),
+}
+
+// Xlib has to be told before any display is opened, gtk's included, so this runs first thing in main.
+pub fn init_threads() {
+    INIT_THREADS.call_once(|| unsafe {
+        xlib::XInitThreads();
+    });
+}
+
+// errors on connections gtk opened are none of our business, they go on to whatever handler was there before.
+unsafe extern "C" fn error_handler(display : *mut xlib::Display, event : *mut xlib::XErrorEvent) -> c_int {
+    if !OWN_DISPLAYS.lock().unwrap().contains(&(display as usize)) {
+        let previous = *PREVIOUS_ERROR_HANDLER.lock().unwrap();
+        return previous.map_or(0, |previous| previous(display, event));
+    }
+
+    // default handler terminates the process, BadAccess on XGrabKey is enough to trigger it.
+    let event = &*event;
+    if event.error_code == xlib::BadAccess {
+        ACCESS_DENIED.store(true, Ordering::SeqCst);
+    } else {
+        warn!("x11 error code: {} request: {}", event.error_code, event.request_code);
+    }
+    0
+}
+
+fn install_error_handler() {
+    INSTALL_ERROR_HANDLER.call_once(|| {
+        let previous = unsafe { xlib::XSetErrorHandler(Some(error_handler)) };
+        *PREVIOUS_ERROR_HANDLER.lock().unwrap() = previous;
+    });
+}
+
+// opens a connection whose errors go to error_handler, null when the display cant be reached.
+fn open_display(name : &Option<CString>) -> *mut xlib::Display {
+    let display = unsafe { xlib::XOpenDisplay(name.as_ref().map_or(::std::ptr::null(), |name| name.as_ptr())) };
+    if !display.is_null() {
+        OWN_DISPLAYS.lock().unwrap().push(display as usize);
+    }
+    display
+}
+
+fn close_display(display : *mut xlib::Display) {
+    OWN_DISPLAYS.lock().unwrap().retain(|own| *own != display as usize);
+    unsafe { xlib::XCloseDisplay(display) };
+}
+
+unsafe fn read_property(display : *mut xlib::Display, window : xlib::Window, property : xlib::Atom) -> Option<String> {
+    let mut actual_type = 0;
+    let mut actual_format = 0;
+    let mut items = 0;
+    let mut bytes_after = 0;
+    let mut data : *mut c_uchar = ::std::ptr::null_mut();
+
+    let status = xlib::XGetWindowProperty(display, window, property, 0, c_long::MAX / 4, xlib::True,
+                                          xlib::AnyPropertyType as c_ulong, &mut actual_type, &mut actual_format,
+                                          &mut items, &mut bytes_after, &mut data);
+
+    if status != xlib::Success as c_int || data.is_null() {
+        return None;
+    }
+
+    let text = if actual_format == 8 {
+        Some(String::from_utf8_lossy(::std::slice::from_raw_parts(data, items as usize)).into_owned())
+    } else {
+        None
+    };
+
+    xlib::XFree(data as *mut _);
+
+    text
+}
+
+// where the keyboard was when a hotkey fired, so paste can go back there.
+#[derive(Clone, Copy, Default)]
+struct Focus {
+    // the top level window per the window manager, 0 without an ewmh one.
+    active : xlib::Window,
+    focused : xlib::Window,
+}
+
+unsafe fn current_focus(display : *mut xlib::Display, root : xlib::Window, atoms : &Atoms) -> Focus {
+    let mut focused = 0;
+    let mut revert_to = 0;
+    xlib::XGetInputFocus(display, &mut focused, &mut revert_to);
+
+    let mut actual_type = 0;
+    let mut actual_format = 0;
+    let mut items = 0;
+    let mut bytes_after = 0;
+    let mut data : *mut c_uchar = ::std::ptr::null_mut();
+    let status = xlib::XGetWindowProperty(display, root, atoms.active_window, 0, 1, xlib::False, xlib::XA_WINDOW,
+                                          &mut actual_type, &mut actual_format, &mut items, &mut bytes_after, &mut data);
+
+    let mut active = 0;
+    if status == xlib::Success as c_int && !data.is_null() {
+        if actual_format == 32 && items == 1 {
+            active = *(data as *const xlib::Window);
+        }
+        xlib::XFree(data as *mut _);
+    }
+
+    Focus { active, focused }
+}
+
+// XTest presses go wherever the focus is, so it is handed back first and the keys only go out once it arrived.
+unsafe fn paste_into(display : *mut xlib::Display, root : xlib::Window, atoms : &Atoms, focus : Focus) {
+    if focus.active != 0 {
+        let mut activate : xlib::XEvent = ::std::mem::zeroed();
+        activate.client_message.type_ = xlib::ClientMessage;
+        activate.client_message.window = focus.active;
+        activate.client_message.message_type = atoms.active_window;
+        activate.client_message.format = 32;
+        // 2 is a pager asking, which window managers dont second guess like an application activating itself.
+        activate.client_message.data.set_long(0, 2);
+        activate.client_message.data.set_long(1, xlib::CurrentTime as c_long);
+        xlib::XSendEvent(display, root, xlib::False, xlib::SubstructureRedirectMask | xlib::SubstructureNotifyMask, &mut activate);
+    }
+    xlib::XSetInputFocus(display, focus.focused, xlib::RevertToParent, xlib::CurrentTime);
+    xlib::XSync(display, xlib::False);
+
+    let started = ::std::time::Instant::now();
+    while current_focus(display, root, atoms).focused != focus.focused {
+        if started.elapsed() > FOCUS_TIMEOUT {
+            warn!("window {} didnt get the focus back, pasting anyway", focus.focused);
+            break;
+        }
+        ::std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let control = xlib::XKeysymToKeycode(display, XK_Control_L as c_ulong) as c_uint;
+    let v = xlib::XKeysymToKeycode(display, XK_v as c_ulong) as c_uint;
+    for (keycode, pressed) in [(control, xlib::True), (v, xlib::True), (v, xlib::False), (control, xlib::False)].iter() {
+        xtest::XTestFakeKeyEvent(display, *keycode, *pressed, 0);
+    }
+    xlib::XFlush(display);
+}
+
+unsafe fn serve_selection_request(display : *mut xlib::Display, atoms : &Atoms, request : &xlib::XSelectionRequestEvent, text : Option<&String>) {
+    // obsolete clients may pass None as property.
+    let property = if request.property == 0 { request.target } else { request.property };
+
+    let mut reply_property = property;
+    match text {
+        Some(_) if request.target == atoms.targets => {
+            let targets = [atoms.targets, atoms.utf8_string, xlib::XA_STRING];
+            xlib::XChangeProperty(display, request.requestor, property, xlib::XA_ATOM, 32, xlib::PropModeReplace,
+                                  targets.as_ptr() as *const c_uchar, targets.len() as c_int);
+        }
+        Some(text) if request.target == atoms.utf8_string || request.target == xlib::XA_STRING => {
+            xlib::XChangeProperty(display, request.requestor, property, request.target, 8, xlib::PropModeReplace,
+                                  text.as_ptr(), text.len() as c_int);
+        }
+        _ => {
+            reply_property = 0;
+        }
+    }
+
+    let mut notify : xlib::XEvent = ::std::mem::zeroed();
+    notify.selection = xlib::XSelectionEvent {
+        type_ : xlib::SelectionNotify,
+        serial : 0,
+        send_event : xlib::True,
+        display,
+        requestor : request.requestor,
+        selection : request.selection,
+        target : request.target,
+        property : reply_property,
+        time : request.time,
+    };
+    xlib::XSendEvent(display, request.requestor, xlib::False, xlib::NoEventMask, &mut notify);
+}
+
+impl HotkeyData {
+    pub fn do_it(hotkey : X11ApiEvent) -> Result<(), PlatformError> {
+        Self::init()?.post_event(hotkey)
+    }
+
+    pub fn set_clipboard(text: &str) -> Result<(), PlatformError> {
+        Self::do_it(X11ApiEvent::SetClipboard { text : text.to_owned()})
+    }
+
+    pub fn get_clipboard() -> Result<Option<String>, PlatformError> {
+        let (reply, rx) = mpsc::channel();
+        Self::do_it(X11ApiEvent::GetClipboard { reply })?;
+        Ok(rx.recv_timeout(GET_CLIPBOARD_TIMEOUT).ok().and_then(|text| text))
+    }
+
+    pub fn add_clipboard_listener(handler : ClipboardHandler) -> Result<(), PlatformError> {
+        Self::do_it(X11ApiEvent::AddClipboardListener { handler })
+    }
+
+    pub fn register_hotkey( id : i32, hotkey : Hotkey, handler : BindHandler) -> Result<(), PlatformError> {
+        let (reply, rx) = mpsc::channel();
+        Self::do_it(X11ApiEvent::HotkeyRegister { id, hotkey, handler, reply })?;
+        rx.recv_timeout(REGISTER_HOTKEY_TIMEOUT).unwrap_or_else(|_| Err(PlatformError::Failure("x11 thread did not answer".to_owned())))
+    }
+
+    pub fn unregister_hotkey(id : i32) {
+        // without a display nothing was grabbed in the first place.
+        let _ = Self::do_it(X11ApiEvent::HotkeyUnregister { id });
+    }
+
+    pub fn paste() -> Result<(), PlatformError> {
+        Self::do_it(X11ApiEvent::Paste)
+    }
+
+    pub fn connect() -> Result<(), PlatformError> {
+        Self::init().map(|_| ())
+    }
+
+    // the first call connects, a display that couldnt be reached is reported to every later call too.
+    fn init() -> Result<HotkeyProxy, PlatformError> {
+        let context = &mut (*HOTKEY_DAYA.lock().unwrap());
+        if context.is_none() {
+            let started = Self::start(None);
+            if let Err(err) = &started {
+                error!("x11 unavailable: {}", err);
+            }
+            *context = Some(started);
+        }
+
+        let context = context.as_ref().unwrap().as_ref().map_err(Clone::clone)?;
+
+        Ok(HotkeyProxy {
+            wakeup_display : context.wakeup_display.0,
+            window : context.window,
+            tx : context.tx.clone().unwrap(),
+        })
+    }
+
+    // None connects to DISPLAY.
+    fn start(display_name : Option<CString>) -> Result<HotkeyData, PlatformError> {
+        install_error_handler();
+
+        let wakeup_display = open_display(&display_name);
+        if wakeup_display.is_null() {
+            return Err(PlatformError::Failure("couldnt open x11 display, is DISPLAY set?".to_owned()));
+        }
+
+        let (tx_window, rx_window) = mpsc::channel();
+        let (tx, rx) = mpsc::channel();
+        let thread_display_name = display_name.clone();
+        ::std::thread::spawn(move || {
+            let display = open_display(&thread_display_name);
+            if display.is_null() {
+                let _ = tx_window.send(Err(PlatformError::Failure("couldnt open second x11 display connection".to_owned())));
+                return;
+            }
+
+            let root = unsafe { xlib::XDefaultRootWindow(display) };
+            let window = unsafe { xlib::XCreateSimpleWindow(display, root, 0, 0, 1, 1, 0, 0, 0) };
+            let atoms = Atoms::new(display);
+
+            let mut xfixes_event_base = 0;
+            let mut xfixes_error_base = 0;
+            let has_xfixes = unsafe { XFixesQueryExtension(display, &mut xfixes_event_base, &mut xfixes_error_base) != 0 };
+            if !has_xfixes {
+                warn!("xfixes extension not available, clipboard history will not be recorded.");
+            }
+
+            unsafe { xlib::XFlush(display) };
+            let _ = tx_window.send(Ok(window));
+
+            let mut handlers : HashMap<i32, BindHandler> = HashMap::new();
+            let mut grabs : HashMap<(c_uint, c_uint), i32> = HashMap::new();
+            let mut clipboard_handlers : Vec<ClipboardHandler> = vec![];
+
+            let mut owned_clipboard : Option<String> = None;
+            let mut pending_conversions : VecDeque<PendingConversion> = VecDeque::new();
+            let mut focus_before : Option<Focus> = None;
+
+            loop {
+                let mut event : xlib::XEvent = unsafe { ::std::mem::zeroed() };
+                unsafe { xlib::XNextEvent(display, &mut event) };
+
+                match event.get_type() {
+                    xlib::KeyPress => {
+                        let key = unsafe { event.key };
+                        if let Some(id) = grabs.get(&(key.keycode, key.state & MODIFIERS_MASK)) {
+                            // the grab doesnt move the focus, it is still on the window the user was in.
+                            focus_before = Some(unsafe { current_focus(display, root, &atoms) });
+                            if let Some(handler) = handlers.get(id) {
+                                handler(*id);
+                            }
+                        }
+                    },
+                    xlib::SelectionRequest => {
+                        let request = unsafe { event.selection_request };
+                        unsafe { serve_selection_request(display, &atoms, &request, owned_clipboard.as_ref()) };
+                    },
+                    xlib::SelectionClear => {
+                        owned_clipboard = None;
+                    },
+                    xlib::SelectionNotify => {
+                        let selection = unsafe { event.selection };
+                        let text = if selection.property == 0 {
+                            None
+                        } else {
+                            unsafe { read_property(display, window, selection.property) }
+                        };
+
+                        match pending_conversions.pop_front() {
+                            Some(PendingConversion::Listeners) => {
+                                if let Some(text) = text {
+                                    for listener in &clipboard_handlers {
+                                        listener(text.clone());
+                                    }
+                                }
+                            },
+                            Some(PendingConversion::Get(reply)) => {
+                                let _ = reply.send(text);
+                            },
+                            None => {},
+                        }
+                    },
+                    event_type if has_xfixes && event_type == xfixes_event_base + XFIXES_SELECTION_NOTIFY => {
+                        let notify : XFixesSelectionNotifyEvent = unsafe { *(&event as *const xlib::XEvent as *const XFixesSelectionNotifyEvent) };
+                        // ownership changes caused by us are what set_clipboard did.
+                        if notify.owner != window && notify.owner != 0 && !clipboard_handlers.is_empty() {
+                            pending_conversions.push_back(PendingConversion::Listeners);
+                            unsafe { xlib::XConvertSelection(display, atoms.clipboard, atoms.utf8_string, atoms.property, window, notify.selection_timestamp) };
+                        }
+                    },
+                    _ => {},
+                }
+
+                while let Ok(request) = rx.try_recv() {
+                    match request {
+                        X11ApiEvent::HotkeyRegister { id, hotkey, handler, reply } => {
+                            let keycode = unsafe { xlib::XKeysymToKeycode(display, keysym(hotkey.key) as c_ulong) } as c_uint;
+                            if keycode == 0 {
+                                let _ = reply.send(Err(PlatformError::UnsupportedKey(hotkey.key)));
+                                continue;
+                            }
+
+                            let modifiers = modifier_mask(hotkey.modifiers);
+                            ACCESS_DENIED.store(false, Ordering::SeqCst);
+                            for lock in LOCK_MASKS.iter() {
+                                unsafe {
+                                    xlib::XGrabKey(display, keycode as c_int, modifiers | lock, root, xlib::False,
+                                                   xlib::GrabModeAsync, xlib::GrabModeAsync);
+                                }
+                            }
+                            unsafe { xlib::XSync(display, xlib::False) };
+
+                            if ACCESS_DENIED.load(Ordering::SeqCst) {
+                                // some of the lock combinations may have succeeded, do not keep half a grab.
+                                for lock in LOCK_MASKS.iter() {
+                                    unsafe { xlib::XUngrabKey(display, keycode as c_int, modifiers | lock, root) };
+                                }
+                                let _ = reply.send(Err(PlatformError::HotkeyTaken(hotkey)));
+                                continue;
+                            }
+
+                            handlers.insert(id, handler);
+                            grabs.insert((keycode, modifiers), id);
+                            let _ = reply.send(Ok(()));
+                        },
+                        X11ApiEvent::HotkeyUnregister { id } => {
+                            handlers.remove(&id);
+                            let released : Vec<_> = grabs.iter().filter(|(_, grab_id)| **grab_id == id).map(|(grab, _)| *grab).collect();
+                            for (keycode, modifiers) in released {
+                                grabs.remove(&(keycode, modifiers));
+                                for lock in LOCK_MASKS.iter() {
+                                    unsafe { xlib::XUngrabKey(display, keycode as c_int, modifiers | lock, root) };
+                                }
+                            }
+                        },
+                        X11ApiEvent::AddClipboardListener { handler } => {
+                            if clipboard_handlers.is_empty() && has_xfixes {
+                                unsafe { XFixesSelectSelectionInput(display, root, atoms.clipboard, XFIXES_SET_SELECTION_OWNER_NOTIFY_MASK) };
+                            }
+
+                            clipboard_handlers.push(handler);
+                        }
+                        X11ApiEvent::SetClipboard { text } => {
+                            owned_clipboard = Some(text);
+                            unsafe { xlib::XSetSelectionOwner(display, atoms.clipboard, window, xlib::CurrentTime) };
+                        }
+                        X11ApiEvent::Paste => {
+                            match focus_before {
+                                Some(focus) if focus.focused > xlib::PointerRoot as xlib::Window => unsafe { paste_into(display, root, &atoms, focus) },
+                                _ => warn!("no window to paste into, pusz wasnt opened by a hotkey"),
+                            }
+                        }
+                        X11ApiEvent::GetClipboard { reply } => {
+                            if let Some(text) = &owned_clipboard {
+                                let _ = reply.send(Some(text.clone()));
+                            } else {
+                                pending_conversions.push_back(PendingConversion::Get(reply));
+                                unsafe { xlib::XConvertSelection(display, atoms.clipboard, atoms.utf8_string, atoms.property, window, xlib::CurrentTime) };
+                            }
+                        }
+                    }
+                }
+
+                unsafe { xlib::XFlush(display) };
+            }
+        });
+
+        let started = rx_window.recv().unwrap_or_else(|_| Err(PlatformError::Failure("x11 thread died while starting".to_owned())));
+        let window = match started {
+            Ok(window) => window,
+            Err(err) => {
+                close_display(wakeup_display);
+                return Err(err);
+            }
+        };
+
+        Ok(HotkeyData {
+            wakeup_display : DisplayPtr(wakeup_display),
+            window,
+            tx : Some(tx),
+        })
+    }
+
+}
+
+pub struct X11Platform;
+
+impl Platform for X11Platform {
+    fn register_hotkey(&self, id : i32, hotkey : Hotkey, handler : BindHandler) -> Result<(), PlatformError> {
+        HotkeyData::register_hotkey(id, hotkey, handler)
+    }
+
+    fn unregister_hotkey(&self, id : i32) {
+        HotkeyData::unregister_hotkey(id)
+    }
+
+    fn add_clipboard_listener(&self, handler : ClipboardHandler) -> Result<(), PlatformError> {
+        HotkeyData::add_clipboard_listener(handler)
+    }
+
+    fn get_clipboard(&self) -> Result<Option<String>, PlatformError> {
+        HotkeyData::get_clipboard()
+    }
+
+    fn set_clipboard(&self, text : &str) -> Result<(), PlatformError> {
+        HotkeyData::set_clipboard(text)
+    }
+
+    fn bring_to_front(&self) {
+        // gtk present() already sends _NET_ACTIVE_WINDOW with the timestamp, nothing more to do.
+    }
+
+    fn paste(&self) -> Result<(), PlatformError> {
+        HotkeyData::paste()
+    }
+}
+
+fn modifier_mask(modifiers : Modifiers) -> u32 {
+    let mut mask = 0;
+    if modifiers.ctrl {
+        mask |= xlib::ControlMask;
+    }
+    if modifiers.alt {
+        mask |= xlib::Mod1Mask;
+    }
+    if modifiers.shift {
+        mask |= xlib::ShiftMask;
+    }
+    if modifiers.win {
+        mask |= xlib::Mod4Mask;
+    }
+    mask
+}
+
+fn keysym(key : Key) -> u32 {
+    match key {
+        Key::A => XK_a,
+        Key::B => XK_b,
+        Key::C => XK_c,
+        Key::D => XK_d,
+        Key::E => XK_e,
+        Key::F => XK_f,
+        Key::G => XK_g,
+        Key::H => XK_h,
+        Key::I => XK_i,
+        Key::J => XK_j,
+        Key::K => XK_k,
+        Key::L => XK_l,
+        Key::M => XK_m,
+        Key::N => XK_n,
+        Key::O => XK_o,
+        Key::P => XK_p,
+        Key::Q => XK_q,
+        Key::R => XK_r,
+        Key::S => XK_s,
+        Key::T => XK_t,
+        Key::U => XK_u,
+        Key::V => XK_v,
+        Key::W => XK_w,
+        Key::X => XK_x,
+        Key::Y => XK_y,
+        Key::Z => XK_z,
+        Key::Num0 => XK_0,
+        Key::Num1 => XK_1,
+        Key::Num2 => XK_2,
+        Key::Num3 => XK_3,
+        Key::Num4 => XK_4,
+        Key::Num5 => XK_5,
+        Key::Num6 => XK_6,
+        Key::Num7 => XK_7,
+        Key::Num8 => XK_8,
+        Key::Num9 => XK_9,
+        Key::F1 => XK_F1,
+        Key::F2 => XK_F2,
+        Key::F3 => XK_F3,
+        Key::F4 => XK_F4,
+        Key::F5 => XK_F5,
+        Key::F6 => XK_F6,
+        Key::F7 => XK_F7,
+        Key::F8 => XK_F8,
+        Key::F9 => XK_F9,
+        Key::F10 => XK_F10,
+        Key::F11 => XK_F11,
+        Key::F12 => XK_F12,
+        Key::F13 => XK_F13,
+        Key::F14 => XK_F14,
+        Key::F15 => XK_F15,
+        Key::F16 => XK_F16,
+        Key::F17 => XK_F17,
+        Key::F18 => XK_F18,
+        Key::F19 => XK_F19,
+        Key::F20 => XK_F20,
+        Key::F21 => XK_F21,
+        Key::F22 => XK_F22,
+        Key::F23 => XK_F23,
+        Key::F24 => XK_F24,
+        Key::Space => XK_space,
+        Key::Tab => XK_Tab,
+        Key::Return => XK_Return,
+        Key::Escape => XK_Escape,
+        Key::Backspace => XK_BackSpace,
+        Key::Delete => XK_Delete,
+        Key::Insert => XK_Insert,
+        Key::Home => XK_Home,
+        Key::End => XK_End,
+        Key::PageUp => XK_Prior,
+        Key::PageDown => XK_Next,
+        Key::Up => XK_Up,
+        Key::Down => XK_Down,
+        Key::Left => XK_Left,
+        Key::Right => XK_Right,
+        Key::Minus => XK_minus,
+        Key::Equal => XK_equal,
+        Key::LeftBracket => XK_bracketleft,
+        Key::RightBracket => XK_bracketright,
+        Key::Backslash => XK_backslash,
+        Key::Semicolon => XK_semicolon,
+        Key::Apostrophe => XK_apostrophe,
+        Key::Grave => XK_grave,
+        Key::Comma => XK_comma,
+        Key::Period => XK_period,
+        Key::Slash => XK_slash,
+    }
+}
+
+lazy_static! {
+    static ref HOTKEY_DAYA: Mutex<Option<Result<HotkeyData, PlatformError>>> = {
+            Mutex::new(None)
+    };
+    // connections opened here, as addresses.
+    static ref OWN_DISPLAYS : Mutex<Vec<usize>> = Mutex::new(vec![]);
+    static ref PREVIOUS_ERROR_HANDLER : Mutex<Option<ErrorHandler>> = Mutex::new(None);
+}
+
+// the ignored ones need an X server, run them under Xvfb: `xvfb-run cargo test -- --ignored`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn unreachable_display_is_an_error() {
+        init_threads();
+        let display = CString::new(":4242").unwrap();
+        assert_eq!(Err(PlatformError::Failure("couldnt open x11 display, is DISPLAY set?".to_owned())), HotkeyData::start(Some(display)).map(|_| ()));
+    }
+
+    #[test]
+    #[ignore]
+    fn set_then_get_clipboard() {
+        init_threads();
+        HotkeyData::set_clipboard("pusz x11 roundtrip").unwrap();
+        assert_eq!(Ok(Some("pusz x11 roundtrip".to_owned())), HotkeyData::get_clipboard());
+    }
+
+    #[test]
+    #[ignore]
+    fn listener_sees_foreign_clipboard_owner() {
+        init_threads();
+        let (tx, rx) = mpsc::channel();
+        HotkeyData::add_clipboard_listener(Arc::new(move |text| {
+            let _ = tx.send(text);
+        })).unwrap();
+
+        // act as another application owning the clipboard.
+        ::std::thread::spawn(|| unsafe {
+            let display = xlib::XOpenDisplay(::std::ptr::null());
+            let root = xlib::XDefaultRootWindow(display);
+            let window = xlib::XCreateSimpleWindow(display, root, 0, 0, 1, 1, 0, 0, 0);
+            let atoms = Atoms::new(display);
+            let text = "from another client".to_owned();
+
+            xlib::XSetSelectionOwner(display, atoms.clipboard, window, xlib::CurrentTime);
+            xlib::XFlush(display);
+
+            loop {
+                let mut event : xlib::XEvent = ::std::mem::zeroed();
+                xlib::XNextEvent(display, &mut event);
+                if event.get_type() == xlib::SelectionRequest {
+                    serve_selection_request(display, &atoms, &event.selection_request, Some(&text));
+                    xlib::XFlush(display);
+                }
+            }
+        });
+
+        assert_eq!("from another client", rx.recv_timeout(Duration::from_secs(5)).expect("no clipboard notification"));
+    }
+}
@@ -91,17 +91,14 @@ fn test() {
     println!("ok done!");
 }
 
-#[no_mangle]
-pub extern "C" fn load(plugin_interface_version : &str) -> Result<Box<dyn plugin_interface::Plugin>, String> {
+fn load(_context : &plugin_interface::PluginContext) -> Result<Box<dyn plugin_interface::Plugin>, plugin_interface::LoadError> {
 
     test();
-    if plugin_interface_version == plugin_interface::COMMON_INTERFACE_VERSION {
-        Ok(Box::new(StorePlugin {}))
-    } else {
-        Err(format!("compatible with: {} but your version is: {}", plugin_interface::COMMON_INTERFACE_VERSION, plugin_interface_version))
-    }
+    Ok(Box::new(StorePlugin {}))
 }
 
+plugin_interface::declare_plugin!(load);
+
 #[cfg(test)]
 mod tests {
     use super::*;